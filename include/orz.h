@@ -0,0 +1,61 @@
+/*
+ * Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+ * https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+ * <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+ * option. This file may not be copied, modified, or distributed
+ * except according to those terms.
+ */
+
+#ifndef ORZ_H
+#define ORZ_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define ORZ_OK            0
+#define ORZ_STREAM_END    1
+#define ORZ_STREAM_ERROR  (-1)
+#define ORZ_DATA_ERROR    (-2)
+
+#define ORZ_NO_FLUSH      0
+#define ORZ_FINISH        1
+#define ORZ_SYNC_FLUSH    2
+
+typedef struct OrzStream orz_stream;
+
+/* creates an encoding stream with level 0..2, returns NULL on invalid level */
+orz_stream *orz_encoder_new(int level);
+
+/* creates a decoding stream */
+orz_stream *orz_decoder_new(void);
+
+/* releases a stream, NULL is ignored */
+void orz_stream_free(orz_stream *stream);
+
+/*
+ * consumes input and produces output without blocking. the number of
+ * consumed/produced bytes are written to *input_consumed/*output_produced,
+ * call again with the remaining input and a drained output buffer to resume.
+ * pass ORZ_FINISH when no more input follows and call until ORZ_STREAM_END.
+ * ORZ_SYNC_FLUSH makes the encoder emit all input received so far without
+ * ending the stream.
+ */
+int orz_compress(orz_stream *stream,
+                 const uint8_t *input, size_t input_len, size_t *input_consumed,
+                 uint8_t *output, size_t output_len, size_t *output_produced,
+                 int flush);
+
+int orz_decompress(orz_stream *stream,
+                   const uint8_t *input, size_t input_len, size_t *input_consumed,
+                   uint8_t *output, size_t output_len, size_t *output_produced,
+                   int flush);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* ORZ_H */
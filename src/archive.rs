@@ -0,0 +1,481 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::{
+    fmt::Display,
+    fs::{self, File, Metadata},
+    io::{Error, ErrorKind, Read, Result, Write},
+    path::{Component, Path, PathBuf},
+    time::{Duration, UNIX_EPOCH},
+};
+
+use crate::{
+    LZCfg, OrzReader, OrzWriter,
+    ioutil::{ReadExt, WriteExt},
+};
+
+// an archive is stored as the decoded data of a single ORZ stream, so all
+// entries share the same window:
+//  magic | version | entries... | end
+// where each entry is:
+//  kind | path_len | path | mode | mtime | file_size | data    (file)
+//  kind | path_len | path | mode | mtime                       (directory)
+//  kind | path_len | path | mode | mtime | target_len | target (symlink)
+const ARCHIVE_MAGIC: [u8; 4] = *b"\x89ORA";
+const ARCHIVE_VERSION: u8 = 1;
+
+const ENTRY_END: u8 = 0;
+const ENTRY_FILE: u8 = 1;
+const ENTRY_DIR: u8 = 2;
+const ENTRY_SYMLINK: u8 = 3;
+
+const MAX_PATH_LEN: usize = 65536;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum EntryKind {
+    File { size: u64 },
+    Dir,
+    Symlink { target: String },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ArchiveEntry {
+    /// Relative path with `/` separators.
+    pub path: String,
+    pub kind: EntryKind,
+    /// Unix permission bits.
+    pub mode: u32,
+    /// Modification time in seconds since the Unix epoch.
+    pub mtime: u64,
+}
+
+impl ArchiveEntry {
+    /// Returns whether the entry is `path` itself or inside directory `path`.
+    pub fn is_under(&self, path: &str) -> bool {
+        let path = path.trim_end_matches('/');
+        self.path == path || self.path.starts_with(path) && self.path[path.len()..].starts_with('/')
+    }
+
+    fn write<W: Write>(&self, w: &mut W) -> Result<()> {
+        let kind = match self.kind {
+            EntryKind::File { .. } => ENTRY_FILE,
+            EntryKind::Dir => ENTRY_DIR,
+            EntryKind::Symlink { .. } => ENTRY_SYMLINK,
+        };
+        w.write_all(&[kind])?;
+        write_string(w, &self.path)?;
+        w.write_len(self.mode as usize)?;
+        w.write_len(self.mtime as usize)?;
+        match &self.kind {
+            EntryKind::File { size } => w.write_len(*size as usize)?,
+            EntryKind::Dir => {}
+            EntryKind::Symlink { target } => write_string(w, target)?,
+        }
+        Ok(())
+    }
+
+    fn read<R: Read>(r: &mut R) -> Result<Option<Self>> {
+        let mut kind = [0u8];
+        r.read_exact(&mut kind)?;
+        if kind[0] == ENTRY_END {
+            return Ok(None);
+        }
+        let path = read_string(r)?;
+        let mode = r.read_len()? as u32;
+        let mtime = r.read_len()? as u64;
+        let kind = match kind[0] {
+            ENTRY_FILE => EntryKind::File {
+                size: r.read_len()? as u64,
+            },
+            ENTRY_DIR => EntryKind::Dir,
+            ENTRY_SYMLINK => EntryKind::Symlink {
+                target: read_string(r)?,
+            },
+            kind => {
+                let msg = format!("unsupported archive entry kind: {}", kind);
+                return Err(Error::new(ErrorKind::InvalidData, msg));
+            }
+        };
+        Ok(Some(Self {
+            path,
+            kind,
+            mode,
+            mtime,
+        }))
+    }
+}
+
+/// Packs files, directories and symlinks into a compressed archive.
+pub struct ArchiveWriter<W: Write> {
+    writer: OrzWriter<W>,
+}
+
+impl<W: Write> ArchiveWriter<W> {
+    pub fn new(inner: W, cfg: &LZCfg) -> Result<Self> {
+        let mut writer = OrzWriter::new(inner, cfg)?;
+        writer.write_all(&ARCHIVE_MAGIC)?;
+        writer.write_all(&[ARCHIVE_VERSION])?;
+        Ok(Self { writer })
+    }
+
+    /// Adds a path, directories are added recursively and symlinks are
+    /// stored without being followed.
+    ///
+    /// Entries are named after `path` without its root and `.` components,
+    /// like tar does. Paths containing `..` are rejected.
+    pub fn add_path(&mut self, path: &Path) -> Result<()> {
+        let mut archive_path = vec![];
+        for component in path.components() {
+            match component {
+                Component::Normal(name) => archive_path.push(utf8_name(path, name)?),
+                Component::ParentDir => {
+                    return Err(path_error(
+                        path,
+                        ErrorKind::InvalidInput,
+                        "path contains ..",
+                    ));
+                }
+                _ => {}
+            }
+        }
+        self.add_tree(path, archive_path.join("/"))
+    }
+
+    /// Adds an entry, `data` must provide exactly `size` bytes for a file.
+    pub fn add_entry(&mut self, entry: &ArchiveEntry, data: &mut dyn Read) -> Result<()> {
+        entry.write(&mut self.writer)?;
+        if let EntryKind::File { size } = entry.kind {
+            let copied = std::io::copy(&mut data.take(size), &mut self.writer)?;
+            if copied < size {
+                let msg = "file shrank while packing";
+                return Err(Error::new(ErrorKind::UnexpectedEof, msg));
+            }
+        }
+        Ok(())
+    }
+
+    /// Ends the archive and returns the inner writer.
+    pub fn finish(mut self) -> Result<W> {
+        self.writer.write_all(&[ENTRY_END])?;
+        self.writer.finish()
+    }
+
+    fn add_tree(&mut self, path: &Path, archive_path: String) -> Result<()> {
+        let metadata = fs::symlink_metadata(path).map_err(|e| path_error(path, e.kind(), e))?;
+        let file_type = metadata.file_type();
+        let (mode, mtime) = (mode_of(&metadata), mtime_of(&metadata));
+        let entry = |kind| ArchiveEntry {
+            path: archive_path.clone(),
+            kind,
+            mode,
+            mtime,
+        };
+
+        if file_type.is_dir() {
+            // the entry of a root directory given as "." or "/" is omitted
+            if !archive_path.is_empty() {
+                self.add_entry(&entry(EntryKind::Dir), &mut std::io::empty())?;
+            }
+            let mut names = fs::read_dir(path)
+                .and_then(|dir| {
+                    dir.map(|entry| Ok(entry?.file_name()))
+                        .collect::<Result<Vec<_>>>()
+                })
+                .map_err(|e| path_error(path, e.kind(), e))?;
+            names.sort();
+            for name in names {
+                let child_path = path.join(&name);
+                let name = utf8_name(&child_path, &name)?;
+                let child_archive_path = match archive_path.as_str() {
+                    "" => name.to_owned(),
+                    _ => format!("{}/{}", archive_path, name),
+                };
+                self.add_tree(&child_path, child_archive_path)?;
+            }
+        } else if file_type.is_file() {
+            let mut file = File::open(path).map_err(|e| path_error(path, e.kind(), e))?;
+            let entry = entry(EntryKind::File {
+                size: metadata.len(),
+            });
+            self.add_entry(&entry, &mut file)
+                .map_err(|e| path_error(path, e.kind(), e))?;
+        } else if file_type.is_symlink() {
+            let target = fs::read_link(path).map_err(|e| path_error(path, e.kind(), e))?;
+            let target = utf8_name(path, target.as_os_str())?.to_owned();
+            self.add_entry(&entry(EntryKind::Symlink { target }), &mut std::io::empty())?;
+        } else {
+            return Err(path_error(
+                path,
+                ErrorKind::Unsupported,
+                "unsupported file type",
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Reads entries of a compressed archive.
+///
+/// After [`ArchiveReader::next_entry`] returns a file, its data can be read
+/// from the reader itself.
+pub struct ArchiveReader<R: Read> {
+    reader: OrzReader<R>,
+    remaining: u64,
+    finished: bool,
+}
+
+impl<R: Read> ArchiveReader<R> {
+    pub fn new(inner: R) -> Result<Self> {
+        let mut reader = OrzReader::new(inner);
+        let mut magic_and_version = [0u8; 5];
+        match reader.read_exact(&mut magic_and_version) {
+            Ok(()) if magic_and_version[..4] == ARCHIVE_MAGIC => {}
+            Ok(()) => return Err(Error::new(ErrorKind::InvalidData, "not an orz archive")),
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+                return Err(Error::new(ErrorKind::InvalidData, "not an orz archive"));
+            }
+            Err(e) => return Err(e),
+        }
+        if magic_and_version[4] != ARCHIVE_VERSION {
+            let msg = format!("unsupported archive version: {}", magic_and_version[4]);
+            return Err(Error::new(ErrorKind::InvalidData, msg));
+        }
+        Ok(Self {
+            reader,
+            remaining: 0,
+            finished: false,
+        })
+    }
+
+    /// Reads the next entry, skipping unread data of the previous file.
+    pub fn next_entry(&mut self) -> Result<Option<ArchiveEntry>> {
+        if self.finished {
+            return Ok(None);
+        }
+        self.reader.skip_exact(self.remaining as usize)?;
+        self.remaining = 0;
+
+        let Some(entry) = ArchiveEntry::read(&mut self.reader)? else {
+            self.finished = true;
+            if self.reader.read(&mut [0u8])? > 0 {
+                return Err(Error::new(ErrorKind::InvalidData, "data after archive end"));
+            }
+            return Ok(None);
+        };
+        if let EntryKind::File { size } = entry.kind {
+            self.remaining = size;
+        }
+        Ok(Some(entry))
+    }
+
+    /// Extracts all selected entries into `dest`.
+    ///
+    /// Entries are never written outside `dest` or through symlinks. Existing
+    /// files are kept unless `overwrite` is set.
+    pub fn unpack(
+        &mut self,
+        dest: &Path,
+        overwrite: bool,
+        mut select: impl FnMut(&ArchiveEntry) -> bool,
+    ) -> Result<()> {
+        // directory attributes are restored last, after their contents are written
+        let mut dirs = vec![];
+        while let Some(entry) = self.next_entry()? {
+            if !select(&entry) {
+                continue;
+            }
+            let path = prepare_output_path(dest, &entry.path, overwrite)?;
+            match &entry.kind {
+                EntryKind::File { .. } => {
+                    let file = match overwrite {
+                        true => File::create(&path),
+                        false => File::create_new(&path),
+                    };
+                    let mut file = file.map_err(|e| path_error(&path, e.kind(), e))?;
+                    std::io::copy(self, &mut file)?;
+                    restore_attributes(&file, &entry)
+                        .map_err(|e| path_error(&path, e.kind(), e))?;
+                }
+                EntryKind::Dir => {
+                    if !path.is_dir() {
+                        fs::create_dir(&path).map_err(|e| path_error(&path, e.kind(), e))?;
+                    }
+                    dirs.push((path, entry));
+                }
+                EntryKind::Symlink { target } => {
+                    #[cfg(unix)]
+                    std::os::unix::fs::symlink(target, &path)
+                        .map_err(|e| path_error(&path, e.kind(), e))?;
+                    #[cfg(not(unix))]
+                    return Err(path_error(&path, ErrorKind::Unsupported, target));
+                }
+            }
+        }
+        for (path, entry) in dirs.iter().rev() {
+            restore_attributes(&File::open(path)?, entry)
+                .map_err(|e| path_error(path, e.kind(), e))?;
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for ArchiveReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let len = buf.len().min(self.remaining as usize);
+        let len = self.reader.read(&mut buf[..len])?;
+        if len == 0 && self.remaining > 0 {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+        self.remaining -= len as u64;
+        Ok(len)
+    }
+}
+
+/// Maps an entry path into `dest`, creating missing parent directories.
+/// Existing entries are removed if `overwrite` is set, so a symlink is
+/// never followed.
+fn prepare_output_path(dest: &Path, entry_path: &str, overwrite: bool) -> Result<PathBuf> {
+    let mut path = dest.to_owned();
+    let names = entry_path.split('/').collect::<Vec<_>>();
+    for (i, name) in names.iter().enumerate() {
+        let mut components = Path::new(name).components();
+        if !matches!(components.next(), Some(Component::Normal(n)) if n == *name)
+            || components.next().is_some()
+        {
+            let msg = "unsafe path in archive";
+            return Err(path_error(entry_path, ErrorKind::InvalidData, msg));
+        }
+        path.push(name);
+
+        let metadata = fs::symlink_metadata(&path);
+        if i + 1 < names.len() {
+            match metadata {
+                Ok(metadata) if metadata.is_dir() => {}
+                Ok(_) => {
+                    let msg = "refusing to extract through a non-directory";
+                    return Err(path_error(&path, ErrorKind::AlreadyExists, msg));
+                }
+                Err(_) => fs::create_dir(&path).map_err(|e| path_error(&path, e.kind(), e))?,
+            }
+        } else if let Ok(metadata) = metadata
+            && !metadata.is_dir()
+            && overwrite
+        {
+            fs::remove_file(&path).map_err(|e| path_error(&path, e.kind(), e))?;
+        }
+    }
+    Ok(path)
+}
+
+fn restore_attributes(file: &File, entry: &ArchiveEntry) -> Result<()> {
+    file.set_modified(UNIX_EPOCH + Duration::from_secs(entry.mtime))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(fs::Permissions::from_mode(entry.mode))?;
+    }
+    Ok(())
+}
+
+fn mode_of(metadata: &Metadata) -> u32 {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        metadata.permissions().mode() & 0o7777
+    }
+    #[cfg(not(unix))]
+    match metadata.is_dir() {
+        true => 0o755,
+        false if metadata.permissions().readonly() => 0o444,
+        false => 0o644,
+    }
+}
+
+fn mtime_of(metadata: &Metadata) -> u64 {
+    let mtime = metadata.modified().ok();
+    mtime
+        .and_then(|mtime| mtime.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |mtime| mtime.as_secs())
+}
+
+fn utf8_name<'a>(path: &Path, name: &'a std::ffi::OsStr) -> Result<&'a str> {
+    name.to_str()
+        .ok_or_else(|| path_error(path, ErrorKind::InvalidInput, "non-UTF-8 path"))
+}
+
+fn path_error(path: impl AsRef<Path>, kind: ErrorKind, e: impl Display) -> Error {
+    Error::new(kind, format!("{}: {}", path.as_ref().display(), e))
+}
+
+fn write_string<W: Write>(w: &mut W, s: &str) -> Result<()> {
+    w.write_len(s.len())?;
+    w.write_all(s.as_bytes())
+}
+
+fn read_string<R: Read>(r: &mut R) -> Result<String> {
+    let len = r.read_len()?;
+    if len > MAX_PATH_LEN {
+        return Err(Error::new(ErrorKind::InvalidData, "path too long"));
+    }
+    let mut buf = vec![0u8; len];
+    r.read_exact(&mut buf)?;
+    String::from_utf8(buf).map_err(|_| Error::new(ErrorKind::InvalidData, "invalid path"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_archive_entries() {
+        let entry = |path: &str, kind| ArchiveEntry {
+            path: path.to_owned(),
+            kind,
+            mode: 0o644,
+            mtime: 1700000000,
+        };
+        let entries = [
+            (entry("dir", EntryKind::Dir), &b""[..]),
+            (entry("dir/a", EntryKind::File { size: 5 }), b"hello"),
+            (entry("dir/b", EntryKind::File { size: 6 }), b"world!"),
+            (
+                entry(
+                    "link",
+                    EntryKind::Symlink {
+                        target: "dir/a".to_owned(),
+                    },
+                ),
+                b"",
+            ),
+        ];
+        let mut writer = ArchiveWriter::new(vec![], &LZCfg::new(5, 3, 2)).unwrap();
+        for (entry, mut data) in entries.clone() {
+            writer.add_entry(&entry, &mut data).unwrap();
+        }
+        let encoded = writer.finish().unwrap();
+
+        // data of dir/a is skipped without being read
+        let mut reader = ArchiveReader::new(&encoded[..]).unwrap();
+        for (entry, data) in &entries {
+            assert_eq!(reader.next_entry().unwrap().as_ref(), Some(entry));
+            if entry.path == "dir/b" {
+                let mut buf = vec![];
+                reader.read_to_end(&mut buf).unwrap();
+                assert_eq!(&buf, data);
+            }
+        }
+        assert_eq!(reader.next_entry().unwrap(), None);
+        assert!(entries[1].0.is_under("dir/"));
+        assert!(!entries[1].0.is_under("di"));
+    }
+
+    #[test]
+    fn test_unsafe_paths() {
+        for path in ["../x", "/x", "./x", ""] {
+            assert!(prepare_output_path(Path::new("/nonexistent"), path, false).is_err());
+        }
+    }
+}
@@ -0,0 +1,333 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::{
+    future::Future,
+    io::{Error, Result},
+    pin::Pin,
+    task::{Context, Poll, ready},
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    task::JoinHandle,
+};
+
+use crate::{Flush, LZ_CHUNK_SIZE, LZCfg, OrzStream, Status};
+
+// max output produced by one offloaded decoding task
+const MAX_TASK_OUTPUT_LEN: usize = LZ_CHUNK_SIZE * 4;
+
+type ProcessFn = fn(&mut OrzStream, &[u8], &mut [u8], Flush) -> Result<(usize, usize, Status)>;
+
+struct TaskOutput {
+    // input not consumed by the task
+    input: Vec<u8>,
+    output: Vec<u8>,
+    status: Status,
+    // whether all input was consumed and all output produced
+    stalled: bool,
+}
+
+enum TaskState {
+    Idle(Box<OrzStream>),
+    Busy(JoinHandle<Result<(Box<OrzStream>, TaskOutput)>>),
+    Poisoned,
+}
+
+impl TaskState {
+    /// Runs the CPU-heavy coding on the blocking thread pool.
+    fn spawn(&mut self, input: Vec<u8>, flush: Flush, f: ProcessFn) {
+        let TaskState::Idle(mut stream) = std::mem::replace(self, TaskState::Poisoned) else {
+            unreachable!("spawning on a busy stream");
+        };
+        *self = TaskState::Busy(tokio::task::spawn_blocking(move || {
+            let mut output = vec![];
+            let mut obuf = vec![0u8; LZ_CHUNK_SIZE];
+            let mut ipos = 0;
+            loop {
+                let (consumed, produced, status) =
+                    f(&mut stream, &input[ipos..], &mut obuf, flush)?;
+                output.extend_from_slice(&obuf[..produced]);
+                ipos += consumed;
+
+                let stalled = ipos == input.len() && produced < obuf.len();
+                if status == Status::StreamEnd || stalled || output.len() >= MAX_TASK_OUTPUT_LEN {
+                    let input = input[ipos..].to_vec();
+                    return Ok((
+                        stream,
+                        TaskOutput {
+                            input,
+                            output,
+                            status,
+                            stalled,
+                        },
+                    ));
+                }
+            }
+        }));
+    }
+
+    /// Waits for the running task, returns immediately if idle.
+    fn poll_idle(&mut self, cx: &mut Context<'_>) -> Poll<Result<Option<TaskOutput>>> {
+        let handle = match self {
+            TaskState::Idle(_) => return Poll::Ready(Ok(None)),
+            TaskState::Busy(handle) => handle,
+            TaskState::Poisoned => return Poll::Ready(Err(Error::other("stream poisoned"))),
+        };
+        let joined = ready!(Pin::new(handle).poll(cx));
+        *self = TaskState::Poisoned;
+        let (stream, task_output) = joined.map_err(Error::other)??;
+        *self = TaskState::Idle(stream);
+        Poll::Ready(Ok(Some(task_output)))
+    }
+}
+
+/// An [`AsyncWrite`] adapter compressing written data into an inner writer.
+///
+/// Chunk encoding runs on tokio's blocking thread pool, so writing never
+/// blocks the reactor. [`AsyncWrite::poll_flush`] emits everything written so
+/// far, [`AsyncWrite::poll_shutdown`] must be called to finish the ORZ stream.
+pub struct OrzAsyncWriter<W> {
+    inner: W,
+    state: TaskState,
+    ibuf: Vec<u8>,
+    obuf: Vec<u8>,
+    opos: usize,
+    // whether the last task left nothing pending in the stream
+    stalled: bool,
+    flushing: bool,
+    finished: bool,
+}
+
+impl<W: AsyncWrite + Unpin> OrzAsyncWriter<W> {
+    /// Fails if the matching options are not valid.
+    pub fn new(inner: W, cfg: &LZCfg) -> Result<Self> {
+        Ok(Self {
+            inner,
+            state: TaskState::Idle(Box::new(OrzStream::encoder(cfg)?)),
+            ibuf: Vec::with_capacity(LZ_CHUNK_SIZE),
+            obuf: vec![],
+            opos: 0,
+            stalled: true,
+            flushing: false,
+            finished: false,
+        })
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+
+    /// Waits for the running task and writes all its output to inner.
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        loop {
+            while self.opos < self.obuf.len() {
+                let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.obuf[self.opos..]))?;
+                if n == 0 {
+                    return Poll::Ready(Err(std::io::ErrorKind::WriteZero.into()));
+                }
+                self.opos += n;
+            }
+            match ready!(self.state.poll_idle(cx))? {
+                Some(mut task_output) => {
+                    // unconsumed input goes before input buffered since
+                    task_output.input.append(&mut self.ibuf);
+                    self.ibuf = task_output.input;
+                    self.obuf = task_output.output;
+                    self.opos = 0;
+                    self.stalled = task_output.stalled;
+                    self.finished = task_output.status == Status::StreamEnd;
+                }
+                None => return Poll::Ready(Ok(())),
+            }
+        }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for OrzAsyncWriter<W> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize>> {
+        let this = self.get_mut();
+        loop {
+            ready!(this.poll_drain(cx))?;
+            if this.ibuf.len() < LZ_CHUNK_SIZE {
+                let n = buf.len().min(LZ_CHUNK_SIZE - this.ibuf.len());
+                this.ibuf.extend_from_slice(&buf[..n]);
+                return Poll::Ready(Ok(n));
+            }
+            let input = std::mem::replace(&mut this.ibuf, Vec::with_capacity(LZ_CHUNK_SIZE));
+            this.state.spawn(input, Flush::None, OrzStream::compress);
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        loop {
+            ready!(this.poll_drain(cx))?;
+            if this.finished || this.flushing && this.stalled && this.ibuf.is_empty() {
+                ready!(Pin::new(&mut this.inner).poll_flush(cx))?;
+                this.flushing = false;
+                return Poll::Ready(Ok(()));
+            }
+            let input = std::mem::take(&mut this.ibuf);
+            this.state.spawn(input, Flush::Sync, OrzStream::compress);
+            this.flushing = true;
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        loop {
+            ready!(this.poll_drain(cx))?;
+            if this.finished {
+                return Pin::new(&mut this.inner).poll_shutdown(cx);
+            }
+            let input = std::mem::take(&mut this.ibuf);
+            this.state.spawn(input, Flush::Finish, OrzStream::compress);
+        }
+    }
+}
+
+/// An [`AsyncRead`] adapter decompressing data read from an inner reader.
+///
+/// Chunk decoding runs on tokio's blocking thread pool, so reading never
+/// blocks the reactor.
+pub struct OrzAsyncReader<R> {
+    inner: R,
+    state: TaskState,
+    ibuf: Vec<u8>,
+    obuf: Vec<u8>,
+    opos: usize,
+    inner_eof: bool,
+    finished: bool,
+}
+
+impl<R: AsyncRead + Unpin> OrzAsyncReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            state: TaskState::Idle(Box::new(OrzStream::decoder())),
+            ibuf: vec![],
+            obuf: vec![],
+            opos: 0,
+            inner_eof: false,
+            finished: false,
+        }
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for OrzAsyncReader<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if this.opos < this.obuf.len() {
+                let n = buf.remaining().min(this.obuf.len() - this.opos);
+                buf.put_slice(&this.obuf[this.opos..][..n]);
+                this.opos += n;
+                return Poll::Ready(Ok(()));
+            }
+            if let Some(task_output) = ready!(this.state.poll_idle(cx))? {
+                this.ibuf = task_output.input;
+                this.obuf = task_output.output;
+                this.opos = 0;
+                this.finished = task_output.status == Status::StreamEnd;
+                continue;
+            }
+            if this.finished {
+                return Poll::Ready(Ok(()));
+            }
+
+            // read more compressed data from inner
+            if this.ibuf.is_empty() && !this.inner_eof {
+                let mut ibuf = vec![0u8; LZ_CHUNK_SIZE];
+                let mut read_buf = ReadBuf::new(&mut ibuf);
+                ready!(Pin::new(&mut this.inner).poll_read(cx, &mut read_buf))?;
+                let n = read_buf.filled().len();
+                ibuf.truncate(n);
+                this.ibuf = ibuf;
+                this.inner_eof = n == 0;
+            }
+            let input = std::mem::take(&mut this.ibuf);
+            let flush = if this.inner_eof {
+                Flush::Finish
+            } else {
+                Flush::None
+            };
+            this.state.spawn(input, flush, OrzStream::decompress);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::testutil::random_bytes;
+
+    #[test]
+    fn test_async_roundtrip() {
+        let input = (0..300000u32)
+            .flat_map(|i| format!("{} {} ", i % 997, i / 13).into_bytes())
+            .collect::<Vec<_>>();
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+
+        runtime.block_on(async {
+            let mut writer = OrzAsyncWriter::new(vec![], &LZCfg::new(5, 3, 2)).unwrap();
+            for piece in input.chunks(12345) {
+                writer.write_all(piece).await.unwrap();
+            }
+            writer.shutdown().await.unwrap();
+            let encoded = writer.into_inner();
+
+            let mut reader = OrzAsyncReader::new(&encoded[..]);
+            let mut decoded = vec![];
+            reader.read_to_end(&mut decoded).await.unwrap();
+            assert!(decoded == input);
+        });
+    }
+
+    #[test]
+    fn test_async_flush() {
+        // after a flush, blocks fill up in the middle of written pieces
+        let random = random_bytes(crate::LZ_BLOCK_SIZE + (8 << 20));
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+
+        runtime.block_on(async {
+            let mut writer = OrzAsyncWriter::new(vec![], &LZCfg::new(5, 3, 2)).unwrap();
+            writer.write_all(b"before flush").await.unwrap();
+            writer.flush().await.unwrap();
+            writer.write_all(&random).await.unwrap();
+            writer.shutdown().await.unwrap();
+            let encoded = writer.into_inner();
+
+            let mut reader = OrzAsyncReader::new(&encoded[..]);
+            let mut decoded = vec![];
+            reader.read_to_end(&mut decoded).await.unwrap();
+            assert!(decoded == [&b"before flush"[..], &random].concat());
+        });
+    }
+}
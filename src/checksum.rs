@@ -0,0 +1,64 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// CRC-32 (IEEE 802.3, as used by gzip and zip), slice-by-4
+const CRC32_POLY: u32 = 0xedb88320;
+const CRC32_TABLES: [[u32; 256]; 4] = {
+    let mut tables = [[0u32; 256]; 4];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = (crc >> 1) ^ (CRC32_POLY & (crc & 1).wrapping_neg());
+            bit += 1;
+        }
+        tables[0][i] = crc;
+        i += 1;
+    }
+    let mut i = 0;
+    while i < 256 {
+        let mut t = 1;
+        while t < 4 {
+            let prev = tables[t - 1][i];
+            tables[t][i] = (prev >> 8) ^ tables[0][(prev & 0xff) as usize];
+            t += 1;
+        }
+        i += 1;
+    }
+    tables
+};
+
+pub fn crc32(buf: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    let mut words = buf.chunks_exact(4);
+    for word in &mut words {
+        crc ^= u32::from_le_bytes([word[0], word[1], word[2], word[3]]);
+        crc = CRC32_TABLES[3][(crc & 0xff) as usize]
+            ^ CRC32_TABLES[2][(crc >> 8 & 0xff) as usize]
+            ^ CRC32_TABLES[1][(crc >> 16 & 0xff) as usize]
+            ^ CRC32_TABLES[0][(crc >> 24) as usize];
+    }
+    for &b in words.remainder() {
+        crc = (crc >> 8) ^ CRC32_TABLES[0][((crc ^ b as u32) & 0xff) as usize];
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xcbf43926);
+        assert_eq!(
+            crc32(b"The quick brown fox jumps over the lazy dog"),
+            0x414fa339
+        );
+    }
+}
@@ -0,0 +1,221 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Classification of the data ahead of the encoder, used to choose how each
+//! chunk is encoded and how each block of the auto filter is filtered.
+
+use crate::text;
+
+/// Bytes classified at once, chunks are split where the encoding chosen for
+/// consecutive segments changes.
+pub const SEGMENT_LEN: usize = 1 << 16;
+
+// order-0 entropy (bits per byte) above which data is considered compressed
+const COMPRESSED_MIN_ENTROPY: f64 = 7.9;
+
+// max bytes sampled to detect the record width of tabular data
+const STRIDE_SAMPLE_LEN: usize = 1 << 16;
+const MAX_DETECTED_STRIDE: u8 = 32;
+
+// max ratio of the entropy of differences to the entropy of the bytes for
+// the delta filter to be applied, and min bytes sampled to decide it
+const DELTA_MAX_ENTROPY_RATIO: f64 = 0.8;
+const DELTA_MIN_SAMPLE_LEN: usize = 1 << 12;
+
+// max ratio of repeated 4-byte strings in a sample for the delta filter to be
+// applied, repeats are matched better without it
+const DELTA_MAX_REPEAT_RATIO: f64 = 0.5;
+
+// slots of the hash table used to find repeated strings
+const REPEAT_TABLE_SIZE: usize = 1 << 14;
+
+/// Kind of data, as guessed from a segment of it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DataClass {
+    Text,
+    /// Machine code, detected by the density of near x86 calls.
+    Executable,
+    /// Other binary data like tables, images or bitmaps.
+    Binary,
+    /// Already compressed or random data without repeats.
+    Compressed,
+    /// A single byte repeated, like zero pages of disk images.
+    Filled(u8),
+}
+
+impl DataClass {
+    fn of(segment: &[u8], table: &mut [u32]) -> Self {
+        if let Some(&b) = segment.first()
+            && segment.iter().all(|&c| c == b)
+        {
+            return DataClass::Filled(b);
+        }
+        if text::looks_like_encoded_text(segment) {
+            return DataClass::Text;
+        }
+        let mut counts = [0usize; 256];
+        segment.iter().for_each(|&b| counts[b as usize] += 1);
+        if entropy(&counts) > COMPRESSED_MIN_ENTROPY && !has_repeats(segment, table) {
+            return DataClass::Compressed;
+        }
+        let near_calls = segment
+            .windows(5)
+            .filter(|w| w[0] == 0xe8 && matches!(w[4], 0x00 | 0xff))
+            .count();
+        match near_calls * 512 >= segment.len() {
+            true => DataClass::Executable,
+            false => DataClass::Binary,
+        }
+    }
+
+    /// Classifies a whole block, like the blocks of `Filter::Auto`.
+    pub fn of_block(block: &[u8]) -> Self {
+        Self::of(block, &mut vec![0; REPEAT_TABLE_SIZE])
+    }
+}
+
+/// Classifier of the segments of a block, remembering their classes while
+/// the encoder looks ahead.
+#[derive(Default)]
+pub struct Classifier {
+    table: Vec<u32>,
+    classes: Vec<(usize, usize, DataClass)>,
+}
+
+impl Classifier {
+    /// Classifies `data[pos..end]`, reusing the class found by an earlier call.
+    /// Classes of segments before `pos` are forgotten.
+    pub fn classify(&mut self, data: &[u8], pos: usize, end: usize) -> DataClass {
+        self.classes.retain(|&(p, ..)| p >= pos);
+        if let Some(&(.., class)) = self.classes.iter().find(|&&(p, e, _)| (p, e) == (pos, end)) {
+            return class;
+        }
+        self.table.resize(REPEAT_TABLE_SIZE, 0);
+        let class = DataClass::of(&data[pos..end], &mut self.table);
+        self.classes.push((pos, end, class));
+        class
+    }
+
+    /// Forgets the classes found, called when the data is moved.
+    pub fn clear(&mut self) {
+        self.classes.clear();
+    }
+}
+
+/// Detects the record width of tabular data from a sample of it, choosing
+/// the stride whose differences have the lowest order-0 entropy.
+pub fn detect_stride(sample: &[u8]) -> u8 {
+    best_stride(&sample[..sample.len().min(STRIDE_SAMPLE_LEN)]).1
+}
+
+/// Chooses the stride for the delta filter of a block, or `None` if the
+/// differences are not much more predictable than the bytes themselves, or
+/// the block is mostly repeats.
+pub fn delta_stride(block: &[u8]) -> Option<u8> {
+    let sample = &block[..block.len().min(STRIDE_SAMPLE_LEN)];
+    if sample.len() < DELTA_MIN_SAMPLE_LEN {
+        return None;
+    }
+    let repeats = num_repeats(sample, &mut vec![0; REPEAT_TABLE_SIZE]);
+    if repeats as f64 > sample.len() as f64 * DELTA_MAX_REPEAT_RATIO {
+        return None;
+    }
+    let mut counts = [0usize; 256];
+    sample.iter().for_each(|&b| counts[b as usize] += 1);
+    let (delta_entropy, stride) = best_stride(sample);
+    (delta_entropy < entropy(&counts) * DELTA_MAX_ENTROPY_RATIO).then_some(stride)
+}
+
+/// Returns the lowest order-0 entropy of differences and its stride.
+fn best_stride(sample: &[u8]) -> (f64, u8) {
+    let mut best = (f64::MAX, 1);
+    for stride in 1..=MAX_DETECTED_STRIDE {
+        let mut counts = [0usize; 256];
+        for (i, &b) in sample.iter().enumerate().skip(stride as usize) {
+            counts[b.wrapping_sub(sample[i - stride as usize]) as usize] += 1;
+        }
+        // multiples of the record width give similar results, prefer the smallest
+        let entropy = entropy(&counts);
+        if entropy < best.0 * 0.98 {
+            best = (entropy, stride);
+        }
+    }
+    best
+}
+
+/// Order-0 entropy in bits per byte of the given byte counts.
+pub fn entropy(counts: &[usize]) -> f64 {
+    let total = counts.iter().sum::<usize>() as f64;
+    let bits = counts.iter().filter(|&&c| c > 0).map(|&c| {
+        let p = c as f64 / total;
+        -p * p.log2()
+    });
+    bits.sum()
+}
+
+/// Checks if more than 1/64 of the 4-byte strings of data occurred before.
+fn has_repeats(data: &[u8], table: &mut [u32]) -> bool {
+    num_repeats(data, table) * 64 > data.len()
+}
+
+/// Counts the 4-byte strings of data found in a hash table of the ones before.
+fn num_repeats(data: &[u8], table: &mut [u32]) -> usize {
+    table.fill(0);
+    let mut repeats = 0;
+    for w in data.windows(4) {
+        let dword = u32::from_le_bytes([w[0], w[1], w[2], w[3]]);
+        let slot = &mut table[(dword.wrapping_mul(0x9e3779b1)
+            >> (32 - REPEAT_TABLE_SIZE.trailing_zeros())) as usize];
+        repeats += (*slot == dword) as usize;
+        *slot = dword;
+    }
+    repeats
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testutil::random_bytes;
+
+    #[test]
+    fn test_classify() {
+        let random = random_bytes(SEGMENT_LEN);
+        let repeated = random[..SEGMENT_LEN / 4].repeat(4);
+        let text = b"The quick brown fox jumps over the lazy dog. ".repeat(1000);
+        let words = (0..SEGMENT_LEN as u32 / 4)
+            .flat_map(u32::to_le_bytes)
+            .collect::<Vec<_>>();
+
+        let classify = |data: &[u8]| Classifier::default().classify(data, 0, data.len());
+
+        assert_eq!(classify(&random), DataClass::Compressed);
+        assert_eq!(classify(&repeated), DataClass::Binary);
+        assert_eq!(classify(&text), DataClass::Text);
+        assert_eq!(classify(&words), DataClass::Binary);
+        assert_eq!(classify(&[0xff; 100]), DataClass::Filled(0xff));
+
+        // classes are remembered until the classifier moves past them
+        let mut classifier = Classifier::default();
+        let data = [&random[..], &text].concat();
+        assert_eq!(
+            classifier.classify(&data, SEGMENT_LEN, data.len()),
+            DataClass::Text
+        );
+        assert_eq!(
+            classifier.classify(&data, 0, SEGMENT_LEN),
+            DataClass::Compressed
+        );
+        assert_eq!(classifier.classes.len(), 2);
+        classifier.classify(&data, SEGMENT_LEN, data.len());
+        assert_eq!(classifier.classes.len(), 1);
+        assert_eq!(DataClass::of_block(&random), DataClass::Compressed);
+        assert_eq!(DataClass::of_block(&text), DataClass::Text);
+        // tables are delta-filtered unless they are mostly repeats
+        assert_eq!(delta_stride(&words), Some(4));
+        assert_eq!(delta_stride(&words[..SEGMENT_LEN / 16].repeat(16)), None);
+        assert_eq!(delta_stride(&random), None);
+    }
+}
@@ -0,0 +1,174 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! C bindings of the push-style streaming API, see `include/orz.h`.
+
+use std::ffi::c_int;
+
+use crate::{Flush, LZCfg, OrzStream, Status};
+
+type ProcessFn =
+    fn(&mut OrzStream, &[u8], &mut [u8], Flush) -> std::io::Result<(usize, usize, Status)>;
+
+pub const ORZ_OK: c_int = 0;
+pub const ORZ_STREAM_END: c_int = 1;
+pub const ORZ_STREAM_ERROR: c_int = -1;
+pub const ORZ_DATA_ERROR: c_int = -2;
+
+pub const ORZ_NO_FLUSH: c_int = 0;
+pub const ORZ_FINISH: c_int = 1;
+pub const ORZ_SYNC_FLUSH: c_int = 2;
+
+/// Creates an encoding stream with a predefined level, returns null if the
+/// level is invalid.
+#[unsafe(no_mangle)]
+pub extern "C" fn orz_encoder_new(level: c_int) -> *mut OrzStream {
+    match u8::try_from(level).ok().and_then(LZCfg::from_level) {
+        Some(cfg) => match OrzStream::encoder(&cfg) {
+            Ok(stream) => Box::into_raw(Box::new(stream)),
+            Err(_) => std::ptr::null_mut(),
+        },
+        None => std::ptr::null_mut(),
+    }
+}
+
+/// Creates a decoding stream.
+#[unsafe(no_mangle)]
+pub extern "C" fn orz_decoder_new() -> *mut OrzStream {
+    Box::into_raw(Box::new(OrzStream::decoder()))
+}
+
+/// Releases a stream created by `orz_encoder_new` or `orz_decoder_new`.
+///
+/// # Safety
+/// `stream` must be null or a pointer returned by one of the constructors.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn orz_stream_free(stream: *mut OrzStream) {
+    if !stream.is_null() {
+        drop(unsafe { Box::from_raw(stream) });
+    }
+}
+
+/// Compresses input into output, see `OrzStream::compress`.
+///
+/// # Safety
+/// `stream` must be a valid encoding stream, `input`/`output` must be valid
+/// for `input_len`/`output_len` bytes and the size pointers must be writable.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn orz_compress(
+    stream: *mut OrzStream,
+    input: *const u8,
+    input_len: usize,
+    input_consumed: *mut usize,
+    output: *mut u8,
+    output_len: usize,
+    output_produced: *mut usize,
+    flush: c_int,
+) -> c_int {
+    unsafe {
+        process(
+            stream,
+            input,
+            input_len,
+            input_consumed,
+            output,
+            output_len,
+            output_produced,
+            flush,
+            OrzStream::compress,
+        )
+    }
+}
+
+/// Decompresses input into output, see `OrzStream::decompress`.
+///
+/// # Safety
+/// `stream` must be a valid decoding stream, `input`/`output` must be valid
+/// for `input_len`/`output_len` bytes and the size pointers must be writable.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn orz_decompress(
+    stream: *mut OrzStream,
+    input: *const u8,
+    input_len: usize,
+    input_consumed: *mut usize,
+    output: *mut u8,
+    output_len: usize,
+    output_produced: *mut usize,
+    flush: c_int,
+) -> c_int {
+    unsafe {
+        process(
+            stream,
+            input,
+            input_len,
+            input_consumed,
+            output,
+            output_len,
+            output_produced,
+            flush,
+            OrzStream::decompress,
+        )
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+unsafe fn process(
+    stream: *mut OrzStream,
+    input: *const u8,
+    input_len: usize,
+    input_consumed: *mut usize,
+    output: *mut u8,
+    output_len: usize,
+    output_produced: *mut usize,
+    flush: c_int,
+    f: ProcessFn,
+) -> c_int {
+    let flush = match flush {
+        ORZ_NO_FLUSH => Flush::None,
+        ORZ_FINISH => Flush::Finish,
+        ORZ_SYNC_FLUSH => Flush::Sync,
+        _ => return ORZ_STREAM_ERROR,
+    };
+    if stream.is_null() || input_consumed.is_null() || output_produced.is_null() {
+        return ORZ_STREAM_ERROR;
+    }
+    let (stream, input, output) = unsafe {
+        (
+            &mut *stream,
+            slice_or_empty(input, input_len),
+            slice_or_empty_mut(output, output_len),
+        )
+    };
+
+    match f(stream, input, output, flush) {
+        Ok((consumed, produced, status)) => {
+            unsafe {
+                *input_consumed = consumed;
+                *output_produced = produced;
+            }
+            match status {
+                Status::Ok => ORZ_OK,
+                Status::StreamEnd => ORZ_STREAM_END,
+            }
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::InvalidInput => ORZ_STREAM_ERROR,
+        Err(_) => ORZ_DATA_ERROR,
+    }
+}
+
+unsafe fn slice_or_empty<'a>(ptr: *const u8, len: usize) -> &'a [u8] {
+    match len {
+        0 => &[],
+        _ => unsafe { std::slice::from_raw_parts(ptr, len) },
+    }
+}
+
+unsafe fn slice_or_empty_mut<'a>(ptr: *mut u8, len: usize) -> &'a mut [u8] {
+    match len {
+        0 => &mut [],
+        _ => unsafe { std::slice::from_raw_parts_mut(ptr, len) },
+    }
+}
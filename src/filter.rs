@@ -0,0 +1,584 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::{
+    fmt::{self, Display},
+    io::{Error, ErrorKind, Read, Result, Write},
+};
+
+use crate::{
+    classify::{self, DataClass},
+    ioutil::{ReadExt, WriteExt},
+    stream::Flush,
+    text,
+};
+
+// max input bytes of a block of the delta filter. delta-filtered data is a
+// sequence of blocks, each of which is:
+//  stride | len | data
+// where a stride of 0 leaves the block unchanged.
+const DELTA_BLOCK_LEN: usize = 1 << 20;
+
+// max input bytes of a block of the auto filter. auto-filtered data is a
+// sequence of blocks, each of which is:
+//  filter | len | data
+// where filter is written like in a frame header, or 0 for an unchanged
+// block, and data is the block filtered as a whole.
+const AUTO_BLOCK_LEN: usize = 1 << 20;
+
+/// A reversible transform applied to the input before encoding, stored in
+/// the frame header so decoding undoes it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Filter {
+    /// x86 and x86-64 CALL/JMP rel32 (E8/E9), converting relative branch
+    /// targets to absolute ones so repeated calls to the same function match.
+    X86,
+    /// ARM64 BL, converted like `X86`.
+    Arm64,
+    /// Byte-wise difference to the byte `stride` (1..=255) bytes before, for
+    /// numeric and tabular data with fixed-width records. The stride is
+    /// detected for every block if `None`, and blocks that do not look
+    /// tabular are stored unchanged.
+    Delta(Option<u8>),
+    /// Capitalization flags and a dictionary of frequent words for UTF-8
+    /// text, applied to blocks that look like text.
+    Text,
+    /// One of the other filters or none for every block, chosen by
+    /// [`Filter::detect`], for mixed content like tar archives.
+    Auto,
+}
+
+impl Filter {
+    /// Chooses the filter for a block of data from its class: x86/arm64 for
+    /// ELF executables and x86 for other machine code, text for UTF-8 text,
+    /// delta with the detected stride for tabular data.
+    pub fn detect(block: &[u8]) -> Option<Self> {
+        const EM_386: u16 = 3;
+        const EM_X86_64: u16 = 62;
+        const EM_AARCH64: u16 = 183;
+
+        // only little-endian ELF files
+        if block.len() >= 20 && block.starts_with(b"\x7fELF") && block[5] == 1 {
+            return match u16::from_le_bytes([block[18], block[19]]) {
+                EM_386 | EM_X86_64 => Some(Filter::X86),
+                EM_AARCH64 => Some(Filter::Arm64),
+                _ => None,
+            };
+        }
+        match DataClass::of_block(block) {
+            DataClass::Text if text::looks_like_text(block) => Some(Filter::Text),
+            DataClass::Executable => Some(Filter::X86),
+            DataClass::Binary => classify::delta_stride(block).map(|s| Filter::Delta(Some(s))),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn write<W: Write>(&self, w: &mut W) -> Result<()> {
+        match self {
+            Filter::X86 => w.write_all(&[1]),
+            Filter::Arm64 => w.write_all(&[2]),
+            Filter::Delta(stride) => w.write_all(&[3, stride.unwrap_or(0)]),
+            Filter::Text => w.write_all(&[4]),
+            Filter::Auto => w.write_all(&[5]),
+        }
+    }
+
+    pub(crate) fn read<R: Read>(r: &mut R) -> Result<Self> {
+        let mut id = [0u8];
+        r.read_exact(&mut id)?;
+        match id[0] {
+            1 => Ok(Filter::X86),
+            2 => Ok(Filter::Arm64),
+            3 => {
+                let mut stride = [0u8];
+                r.read_exact(&mut stride)?;
+                Ok(Filter::Delta((stride[0] > 0).then_some(stride[0])))
+            }
+            4 => Ok(Filter::Text),
+            5 => Ok(Filter::Auto),
+            id => {
+                let msg = format!("unsupported filter: {}", id);
+                Err(Error::new(ErrorKind::InvalidData, msg))
+            }
+        }
+    }
+
+    /// Checks the parameters of the filter.
+    pub(crate) fn check(&self) -> Result<()> {
+        if *self == Filter::Delta(Some(0)) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "invalid delta stride: 0",
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Filter::X86 => write!(f, "x86"),
+            Filter::Arm64 => write!(f, "arm64"),
+            Filter::Delta(Some(stride)) => write!(f, "delta:{}", stride),
+            Filter::Delta(None) => write!(f, "delta"),
+            Filter::Text => write!(f, "text"),
+            Filter::Auto => write!(f, "auto"),
+        }
+    }
+}
+
+/// Applies a filter to consecutive pieces of a stream.
+pub(crate) struct FilterCoder {
+    filter: Filter,
+    /// Stream position of the next piece.
+    pos: u64,
+    /// Original bytes before the next block of a delta filter.
+    history: [u8; u8::MAX as usize],
+    /// Words added by the previous blocks of a text filter.
+    dictionary: text::Dictionary,
+}
+
+impl FilterCoder {
+    pub fn new(filter: Filter) -> Self {
+        FilterCoder {
+            filter,
+            pos: 0,
+            history: [0; u8::MAX as usize],
+            dictionary: text::Dictionary::default(),
+        }
+    }
+
+    pub fn filter(&self) -> Filter {
+        self.filter
+    }
+
+    /// Filters the next piece of input into output, returns the number of
+    /// bytes consumed. Trailing input may be left to be passed again with
+    /// the following input: up to 4 bytes for branch filters unless
+    /// finishing, or an incomplete block of the text, delta and auto filters
+    /// unless flushing.
+    pub fn encode(&mut self, input: &[u8], flush: Flush, output: &mut Vec<u8>) -> usize {
+        match self.filter {
+            Filter::Text => {
+                return text::encode(&mut self.dictionary, input, flush != Flush::None, output);
+            }
+            Filter::Delta(stride) => {
+                return self.encode_delta(stride, input, flush != Flush::None, output);
+            }
+            Filter::Auto => return self.encode_auto(input, flush != Flush::None, output),
+            _ => {}
+        }
+        let start = output.len();
+        output.extend_from_slice(input);
+        let buf = &mut output[start..];
+        let done = match self.filter {
+            Filter::X86 => x86(buf, self.pos, true),
+            Filter::Arm64 => arm64(buf, self.pos, true),
+            Filter::Delta(_) | Filter::Text | Filter::Auto => unreachable!(),
+        };
+        let done = if flush == Flush::Finish {
+            input.len()
+        } else {
+            done
+        };
+        output.truncate(start + done);
+        self.pos += done as u64;
+        done
+    }
+
+    /// Reverses `encode`, all input must be consumed if `last`.
+    pub fn decode(&mut self, input: &[u8], last: bool, output: &mut Vec<u8>) -> Result<usize> {
+        let done = match self.filter {
+            Filter::Text => text::decode(&mut self.dictionary, input, output)?,
+            Filter::Delta(_) => self.decode_delta(input, output)?,
+            Filter::Auto => self.decode_auto(input, output)?,
+            _ => {
+                let start = output.len();
+                output.extend_from_slice(input);
+                let buf = &mut output[start..];
+                let done = match self.filter {
+                    Filter::X86 => x86(buf, self.pos, false),
+                    _ => arm64(buf, self.pos, false),
+                };
+                let done = if last { input.len() } else { done };
+                output.truncate(start + done);
+                self.pos += done as u64;
+                done
+            }
+        };
+        if last && done < input.len() {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "truncated filtered data",
+            ));
+        }
+        Ok(done)
+    }
+
+    /// Filters the complete blocks of input, or all input if `flush`, with a
+    /// fixed or detected stride, returns the number of bytes consumed.
+    fn encode_delta(
+        &mut self,
+        stride: Option<u8>,
+        input: &[u8],
+        flush: bool,
+        output: &mut Vec<u8>,
+    ) -> usize {
+        let mut consumed = 0;
+        while input.len() - consumed >= DELTA_BLOCK_LEN || flush && consumed < input.len() {
+            let block = &input[consumed..][..(input.len() - consumed).min(DELTA_BLOCK_LEN)];
+            let stride = stride.or_else(|| classify::delta_stride(block));
+            output.push(stride.unwrap_or(0));
+            output.write_len(block.len()).unwrap();
+            let start = output.len();
+            output.extend_from_slice(block);
+            if let Some(stride) = stride {
+                delta_encode(&mut output[start..], stride, &self.history);
+            }
+            update_history(&mut self.history, block);
+            consumed += block.len();
+        }
+        self.pos += consumed as u64;
+        consumed
+    }
+
+    /// Reverses `encode_delta` for the complete blocks of input, returns the
+    /// number of bytes consumed.
+    fn decode_delta(&mut self, input: &[u8], output: &mut Vec<u8>) -> Result<usize> {
+        let mut consumed = 0;
+        while let Some((stride, header_len, len)) = parse_delta_block(&input[consumed..])? {
+            let start = output.len();
+            output.extend_from_slice(&input[consumed + header_len..][..len]);
+            if stride > 0 {
+                delta_decode(&mut output[start..], stride, &self.history);
+            }
+            update_history(&mut self.history, &output[start..]);
+            self.pos += len as u64;
+            consumed += header_len + len;
+        }
+        Ok(consumed)
+    }
+
+    /// Filters the complete blocks of input, or all input if `flush`, each
+    /// with the filter detected for it, returns the number of bytes consumed.
+    fn encode_auto(&mut self, input: &[u8], flush: bool, output: &mut Vec<u8>) -> usize {
+        let mut consumed = 0;
+        let mut data = vec![];
+        while input.len() - consumed >= AUTO_BLOCK_LEN || flush && consumed < input.len() {
+            let block = &input[consumed..][..(input.len() - consumed).min(AUTO_BLOCK_LEN)];
+            let filter = Filter::detect(block);
+            let pos = self.pos;
+            data.clear();
+            match filter {
+                Some(Filter::Text) => {
+                    text::encode(&mut self.dictionary, block, true, &mut data);
+                }
+                Some(Filter::Delta(stride)) => {
+                    self.encode_delta(stride, block, true, &mut data);
+                }
+                _ => {
+                    data.extend_from_slice(block);
+                    match filter {
+                        Some(Filter::X86) => x86(&mut data, pos, true),
+                        Some(Filter::Arm64) => arm64(&mut data, pos, true),
+                        _ => 0,
+                    };
+                }
+            }
+            match filter {
+                Some(filter) => filter.write(output).unwrap(),
+                None => output.push(0),
+            }
+            output.write_len(data.len()).unwrap();
+            output.extend_from_slice(&data);
+            self.pos = pos + block.len() as u64;
+            consumed += block.len();
+        }
+        consumed
+    }
+
+    /// Reverses `encode_auto` for the complete blocks of input, returns the
+    /// number of bytes consumed.
+    fn decode_auto(&mut self, input: &[u8], output: &mut Vec<u8>) -> Result<usize> {
+        let mut consumed = 0;
+        while let Some((filter, header_len, len)) = parse_auto_block(&input[consumed..])? {
+            let data = &input[consumed + header_len..][..len];
+            let (pos, start) = (self.pos, output.len());
+            let done = match filter {
+                Some(Filter::Text) => text::decode(&mut self.dictionary, data, output)?,
+                Some(Filter::Delta(_)) => self.decode_delta(data, output)?,
+                Some(Filter::Auto) => return Err(invalid_auto_block()),
+                _ => {
+                    output.extend_from_slice(data);
+                    match filter {
+                        Some(Filter::X86) => x86(&mut output[start..], pos, false),
+                        Some(Filter::Arm64) => arm64(&mut output[start..], pos, false),
+                        _ => 0,
+                    };
+                    len
+                }
+            };
+            if done < len || output.len() - start > AUTO_BLOCK_LEN {
+                return Err(invalid_auto_block());
+            }
+            self.pos = pos + (output.len() - start) as u64;
+            consumed += header_len + len;
+        }
+        Ok(consumed)
+    }
+}
+
+/// Parses the header of an auto block, returns `(filter, header_len, len)`
+/// or none if the block is incomplete.
+fn parse_auto_block(input: &[u8]) -> Result<Option<(Option<Filter>, usize, usize)>> {
+    let mut r = input;
+    let filter = match r.first() {
+        None => return Ok(None),
+        Some(0) => {
+            r = &r[1..];
+            None
+        }
+        Some(_) => match Filter::read(&mut r) {
+            Ok(filter) => Some(filter),
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        },
+    };
+    let len = match r.read_len() {
+        Ok(len) => len,
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    // filtered blocks are at most a few bytes longer than the input block
+    if len > AUTO_BLOCK_LEN * 2 {
+        return Err(invalid_auto_block());
+    }
+    let header_len = input.len() - r.len();
+    Ok((r.len() >= len).then_some((filter, header_len, len)))
+}
+
+fn invalid_auto_block() -> Error {
+    Error::new(ErrorKind::InvalidData, "invalid auto-filtered block")
+}
+
+/// Parses the header of a delta block, returns `(stride, header_len, len)`
+/// or none if the block is incomplete.
+fn parse_delta_block(input: &[u8]) -> Result<Option<(u8, usize, usize)>> {
+    let Some((&stride, mut r)) = input.split_first() else {
+        return Ok(None);
+    };
+    let len = match r.read_len() {
+        Ok(len) => len,
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    if len > DELTA_BLOCK_LEN {
+        return Err(Error::new(ErrorKind::InvalidData, "delta block too large"));
+    }
+    let header_len = input.len() - r.len();
+    Ok((r.len() >= len).then_some((stride, header_len, len)))
+}
+
+/// Appends data to the history, keeping its last bytes.
+fn update_history(history: &mut [u8], data: &[u8]) {
+    let n = data.len().min(history.len());
+    let kept = history.len() - n;
+    history.rotate_left(n);
+    history[kept..].copy_from_slice(&data[data.len() - n..]);
+}
+
+fn delta_encode(buf: &mut [u8], stride: u8, history: &[u8]) {
+    let stride = stride as usize;
+    for i in (0..buf.len()).rev() {
+        let prev = match i.checked_sub(stride) {
+            Some(j) => buf[j],
+            None => history[history.len() - stride + i],
+        };
+        buf[i] = buf[i].wrapping_sub(prev);
+    }
+}
+
+fn delta_decode(buf: &mut [u8], stride: u8, history: &[u8]) {
+    let stride = stride as usize;
+    for i in 0..buf.len() {
+        let prev = match i.checked_sub(stride) {
+            Some(j) => buf[j],
+            None => history[history.len() - stride + i],
+        };
+        buf[i] = buf[i].wrapping_add(prev);
+    }
+}
+
+fn x86(buf: &mut [u8], pos: u64, encode: bool) -> usize {
+    let mut i = 0;
+    while i + 5 <= buf.len() {
+        if buf[i] & 0xfe != 0xe8 {
+            i += 1;
+            continue;
+        }
+        // only targets within +-16MB, whose top byte is 0x00 or 0xff, are converted.
+        // converted targets stay in this range and operands are skipped either way,
+        // so decoding makes the same decisions.
+        if matches!(buf[i + 4], 0x00 | 0xff) {
+            let target = u32::from_le_bytes([buf[i + 1], buf[i + 2], buf[i + 3], buf[i + 4]]);
+            let offset = (pos + i as u64 + 5) as u32;
+            let target = match encode {
+                true => target.wrapping_add(offset),
+                false => target.wrapping_sub(offset),
+            };
+            let target = ((target << 7) as i32 >> 7) as u32; // sign-extend from 25 bits
+            buf[i + 1..i + 5].copy_from_slice(&target.to_le_bytes());
+        }
+        i += 5;
+    }
+    i
+}
+
+fn arm64(buf: &mut [u8], pos: u64, encode: bool) -> usize {
+    // instructions are aligned to stream positions
+    let mut i = (pos.wrapping_neg() % 4) as usize;
+    while i + 4 <= buf.len() {
+        let insn = u32::from_le_bytes([buf[i], buf[i + 1], buf[i + 2], buf[i + 3]]);
+        if insn >> 26 == 0x25 {
+            let offset = ((pos + i as u64) / 4) as u32;
+            let imm26 = match encode {
+                true => insn.wrapping_add(offset),
+                false => insn.wrapping_sub(offset),
+            };
+            let insn = insn & 0xfc00_0000 | imm26 & 0x03ff_ffff;
+            buf[i..i + 4].copy_from_slice(&insn.to_le_bytes());
+        }
+        i += 4;
+    }
+    i.min(buf.len())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testutil::random_bytes;
+
+    #[test]
+    fn test_filters() {
+        let mut data = vec![0x90u8; 1000];
+        data[10..15].copy_from_slice(&[0xe8, 0x10, 0x00, 0x00, 0x00]); // call +0x10
+        data[100..105].copy_from_slice(&[0xe9, 0xf0, 0xff, 0xff, 0xff]); // jmp -0x10
+        data[200..204].copy_from_slice(&0x9400_0010u32.to_le_bytes()); // bl +0x40
+        data[300..306].copy_from_slice(&[0xe9, 0xe8, 0x10, 0x00, 0x01, 0x00]); // overlapping
+        data[996..].copy_from_slice(&[0xe8, 0x00, 0x00, 0x00]);
+
+        for filter in [Filter::X86, Filter::Arm64] {
+            for split in [0, 1, 11, 203, 997] {
+                // filtering in pieces must give the same result
+                let mut encoded = vec![];
+                let mut coder = FilterCoder::new(filter);
+                let done = coder.encode(&data[..split], Flush::None, &mut encoded);
+                coder.encode(&data[done..], Flush::Finish, &mut encoded);
+                assert_ne!(encoded, data);
+
+                let mut decoded = vec![];
+                let mut coder = FilterCoder::new(filter);
+                let done = coder
+                    .decode(&encoded[..split], false, &mut decoded)
+                    .unwrap();
+                assert!(split - done <= 4);
+                coder.decode(&encoded[done..], true, &mut decoded).unwrap();
+                assert_eq!(decoded, data);
+            }
+        }
+
+        let mut elf = b"\x7fELF\x02\x01\x01".to_vec();
+        elf.resize(64, 0);
+        elf[18] = 62;
+        assert_eq!(Filter::detect(&elf), Some(Filter::X86));
+        assert_eq!(Filter::detect(b"\x7fELF"), None);
+        assert_eq!(Filter::detect(b"hello, world"), Some(Filter::Text));
+
+        let table = (0..10000u32)
+            .flat_map(|i| (i * 3).to_le_bytes())
+            .collect::<Vec<_>>();
+        assert_eq!(Filter::detect(&table), Some(Filter::Delta(Some(4))));
+        assert_eq!(Filter::detect(&random_bytes(10000)), None);
+    }
+
+    #[test]
+    fn test_auto_blocks() {
+        // text, a table of 4-byte records and random data in separate blocks
+        let text = b"The quick brown fox jumps over the lazy dog. ".repeat(30000);
+        let mut data = text[..AUTO_BLOCK_LEN].to_vec();
+        data.extend((0..AUTO_BLOCK_LEN as u32 / 4).flat_map(|i| (i * 3).to_le_bytes()));
+        data.extend(random_bytes(AUTO_BLOCK_LEN / 2));
+
+        let mut encoded = vec![];
+        let mut coder = FilterCoder::new(Filter::Auto);
+        let done = coder.encode(&data[..12345], Flush::None, &mut encoded);
+        assert_eq!(done, 0); // the block is incomplete
+        coder.encode(&data, Flush::Finish, &mut encoded);
+
+        // every block stores its filter
+        let mut filters = vec![];
+        let mut rest = &encoded[..];
+        while let Some((filter, header_len, len)) = parse_auto_block(rest).unwrap() {
+            filters.push(filter);
+            rest = &rest[header_len + len..];
+        }
+        assert!(rest.is_empty());
+        assert_eq!(
+            filters,
+            [Some(Filter::Text), Some(Filter::Delta(Some(4))), None]
+        );
+
+        let mut decoded = vec![];
+        let mut coder = FilterCoder::new(Filter::Auto);
+        let done = coder
+            .decode(&encoded[..encoded.len() - 100], false, &mut decoded)
+            .unwrap();
+        assert_eq!(decoded.len(), AUTO_BLOCK_LEN * 2);
+        coder.decode(&encoded[done..], true, &mut decoded).unwrap();
+        assert!(decoded == data);
+
+        // a nested auto block is invalid
+        let mut nested = vec![];
+        Filter::Auto.write(&mut nested).unwrap();
+        nested.write_len(0).unwrap();
+        let mut coder = FilterCoder::new(Filter::Auto);
+        let err = coder.decode(&nested, true, &mut vec![]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_delta_blocks() {
+        // a table of 4-byte records, then data without records
+        let mut data = (0..DELTA_BLOCK_LEN as u32 / 4)
+            .flat_map(|i| (i * 3).to_le_bytes())
+            .collect::<Vec<_>>();
+        data.extend(random_bytes(DELTA_BLOCK_LEN / 2));
+
+        for (filter, strides) in [(None, [4, 0]), (Some(2), [2, 2])] {
+            let mut encoded = vec![];
+            let mut coder = FilterCoder::new(Filter::Delta(filter));
+            let done = coder.encode(&data[..12345], Flush::None, &mut encoded);
+            assert_eq!(done, 0); // the block is incomplete
+            coder.encode(&data, Flush::Finish, &mut encoded);
+
+            // every block stores its stride
+            let (stride, header_len, len) = parse_delta_block(&encoded).unwrap().unwrap();
+            assert_eq!((stride, len), (strides[0], DELTA_BLOCK_LEN));
+            let second = &encoded[header_len + len..];
+            assert_eq!(parse_delta_block(second).unwrap().unwrap().0, strides[1]);
+
+            let mut decoded = vec![];
+            let mut coder = FilterCoder::new(Filter::Delta(filter));
+            let done = coder
+                .decode(&encoded[..DELTA_BLOCK_LEN + 100], false, &mut decoded)
+                .unwrap();
+            assert_eq!(decoded.len(), DELTA_BLOCK_LEN);
+            coder.decode(&encoded[done..], true, &mut decoded).unwrap();
+            assert!(decoded == data);
+        }
+    }
+}
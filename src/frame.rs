@@ -0,0 +1,485 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::io::{Error, ErrorKind, Read, Result, Write};
+
+use crate::{
+    filter::Filter,
+    ioutil::{ReadExt, WriteExt},
+    lz::{ContextHash, LZ_MF_BUCKET_ITEM_SIZE, LZ_MF_BUCKET_ITEM_SIZES, LZCfg},
+    reorder::Reordering,
+};
+
+// an ORZ stream is a sequence of frames, each frame is either a data frame:
+//  magic | version | flags | [file_info] | [lz_cfg] | [reordering] | [filter] |
+// [bucket_size] | [match_options]  chunks... | empty chunk
+// where match_options are the lz_cfg options after the depths, if not the
+// defaults:  good_match_len | lazy_match_max_len | lazy_match_min_gain |
+//  lazy_match_cheap_offset_bits | lazy_match_lookahead
+// where each chunk is:
+//  len | [mode | [context]] | decoded_len | [crc32le of decoded] | data
+// or a skippable metadata frame:
+//  metadata_magic | tag:u32le | len | payload
+pub const FRAME_MAGIC: [u8; 4] = *b"\x89ORZ";
+pub const METADATA_FRAME_MAGIC: [u8; 4] = *b"\x89ORM";
+pub const FORMAT_VERSION: u8 = 4;
+// oldest version decoded
+pub const MIN_FORMAT_VERSION: u8 = 2;
+// first version with the repeated offset symbols
+pub const REP_MATCH_VERSION: u8 = 3;
+// first version with matches extended beyond LZ_MATCH_MAX_LEN
+pub const LONG_MATCH_VERSION: u8 = 4;
+
+const FLAG_FILE_INFO: u8 = 0x01;
+const FLAG_CHUNK_CHECKSUM: u8 = 0x02;
+const FLAG_LZ_CFG: u8 = 0x04;
+const FLAG_REORDERING: u8 = 0x08;
+const FLAG_FILTER: u8 = 0x10;
+const FLAG_CHUNK_MODE: u8 = 0x20;
+const FLAG_BUCKET_SIZE: u8 = 0x40;
+const FLAG_MATCH_OPTIONS: u8 = 0x80;
+
+// chunk modes
+const CHUNK_MODE_LZ: u8 = 0; // with the text context
+const CHUNK_MODE_STORED: u8 = 1;
+const CHUNK_MODE_LZ_CONTEXT: u8 = 2; // followed by the context
+const CHUNK_MODE_FILLED: u8 = 3; // followed by the byte
+
+// file_info fields present
+const FILE_INFO_NAME: u8 = 0x01;
+const FILE_INFO_SIZE: u8 = 0x02;
+const FILE_INFO_MTIME: u8 = 0x04;
+const FILE_INFO_MODE: u8 = 0x08;
+
+const MAX_FILE_NAME_LEN: usize = 4096;
+
+/// Max length of an encoded [`ChunkHeader`].
+pub const MAX_CHUNK_HEADER_LEN: usize = 3 + 10 + 4;
+
+/// Returns whether buf can be the beginning of a frame.
+pub fn is_frame_magic_prefix(buf: &[u8]) -> bool {
+    let len = buf.len().min(FRAME_MAGIC.len());
+    buf[..len] == FRAME_MAGIC[..len] || buf[..len] == METADATA_FRAME_MAGIC[..len]
+}
+
+pub fn write_metadata_frame<W: Write>(w: &mut W, tag: u32, payload: &[u8]) -> Result<()> {
+    w.write_all(&METADATA_FRAME_MAGIC)?;
+    w.write_all(&tag.to_le_bytes())?;
+    w.write_len(payload.len())?;
+    w.write_all(payload)?;
+    Ok(())
+}
+
+pub enum Frame {
+    Data(Box<FrameHeader>),
+    Metadata { tag: u32, len: usize },
+}
+
+impl Frame {
+    /// Parses a frame header from the beginning of buf, returns the frame
+    /// and its header length, or `None` if buf is not long enough.
+    pub fn parse(buf: &[u8]) -> Result<Option<(Self, usize)>> {
+        let mut r = buf;
+        match Self::read(&mut r) {
+            Ok(frame) => Ok(Some((frame, buf.len() - r.len()))),
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn read<R: Read>(r: &mut R) -> Result<Self> {
+        let mut magic = [0u8; FRAME_MAGIC.len()];
+        r.read_exact(&mut magic)?;
+        match magic {
+            FRAME_MAGIC => Ok(Frame::Data(Box::new(FrameHeader::read(r)?))),
+            METADATA_FRAME_MAGIC => {
+                let mut tag = [0u8; 4];
+                r.read_exact(&mut tag)?;
+                let len = r.read_len()?;
+                Ok(Frame::Metadata {
+                    tag: u32::from_le_bytes(tag),
+                    len,
+                })
+            }
+            _ => Err(Error::new(ErrorKind::InvalidData, "not an orz stream")),
+        }
+    }
+}
+
+/// Attributes of the original file, optionally stored in the frame header.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FileInfo {
+    /// File name without directory.
+    pub name: Option<String>,
+    /// Uncompressed size, verified when decoding.
+    pub size: Option<u64>,
+    /// Modification time in seconds since the Unix epoch.
+    pub mtime: Option<u64>,
+    /// Unix permission bits.
+    pub mode: Option<u32>,
+}
+
+impl FileInfo {
+    fn write<W: Write>(&self, w: &mut W) -> Result<()> {
+        let mut fields = 0;
+        if self.name.is_some() {
+            fields |= FILE_INFO_NAME;
+        }
+        if self.size.is_some() {
+            fields |= FILE_INFO_SIZE;
+        }
+        if self.mtime.is_some() {
+            fields |= FILE_INFO_MTIME;
+        }
+        if self.mode.is_some() {
+            fields |= FILE_INFO_MODE;
+        }
+        w.write_all(&[fields])?;
+
+        if let Some(name) = &self.name {
+            w.write_len(name.len())?;
+            w.write_all(name.as_bytes())?;
+        }
+        if let Some(size) = self.size {
+            w.write_len(size as usize)?;
+        }
+        if let Some(mtime) = self.mtime {
+            w.write_len(mtime as usize)?;
+        }
+        if let Some(mode) = self.mode {
+            w.write_len(mode as usize)?;
+        }
+        Ok(())
+    }
+
+    fn read<R: Read>(r: &mut R) -> Result<Self> {
+        let mut fields = [0u8];
+        r.read_exact(&mut fields)?;
+        let [fields] = fields;
+        if fields & !(FILE_INFO_NAME | FILE_INFO_SIZE | FILE_INFO_MTIME | FILE_INFO_MODE) != 0 {
+            let msg = format!("unsupported file info fields: {:#x}", fields);
+            return Err(Error::new(ErrorKind::InvalidData, msg));
+        }
+
+        let mut info = Self::default();
+        if fields & FILE_INFO_NAME != 0 {
+            let len = r.read_len()?;
+            if len > MAX_FILE_NAME_LEN {
+                return Err(Error::new(ErrorKind::InvalidData, "file name too long"));
+            }
+            let mut name = vec![0u8; len];
+            r.read_exact(&mut name)?;
+            let name = String::from_utf8(name)
+                .map_err(|_| Error::new(ErrorKind::InvalidData, "invalid file name"))?;
+            info.name = Some(name);
+        }
+        if fields & FILE_INFO_SIZE != 0 {
+            info.size = Some(r.read_len()? as u64);
+        }
+        if fields & FILE_INFO_MTIME != 0 {
+            info.mtime = Some(r.read_len()? as u64);
+        }
+        if fields & FILE_INFO_MODE != 0 {
+            info.mode = Some(r.read_len()? as u32);
+        }
+        Ok(info)
+    }
+}
+
+pub struct FrameHeader {
+    pub version: u8,
+    pub file_info: Option<FileInfo>,
+    pub lz_cfg: Option<LZCfg>,
+    pub reordering: Option<Reordering>,
+    pub filter: Option<Filter>,
+    pub chunk_checksum: bool,
+    pub chunk_mode: bool,
+    /// Match finder bucket size, if not the default one.
+    pub bucket_size: Option<usize>,
+}
+
+impl FrameHeader {
+    /// Header assumed for streams written before frame headers were added
+    /// (orz 1.6.1 and earlier), a bare sequence of chunks without chunk
+    /// headers, coded like version 2.
+    pub fn legacy() -> Self {
+        Self {
+            version: MIN_FORMAT_VERSION,
+            file_info: None,
+            lz_cfg: None,
+            reordering: None,
+            filter: None,
+            chunk_checksum: false,
+            chunk_mode: false,
+            bucket_size: None,
+        }
+    }
+
+    pub fn write<W: Write>(&self, w: &mut W) -> Result<()> {
+        let mut flags = 0;
+        if self.file_info.is_some() {
+            flags |= FLAG_FILE_INFO;
+        }
+        if self.chunk_checksum {
+            flags |= FLAG_CHUNK_CHECKSUM;
+        }
+        if self.lz_cfg.is_some() {
+            flags |= FLAG_LZ_CFG;
+        }
+        if self.reordering.is_some() {
+            flags |= FLAG_REORDERING;
+        }
+        if self.filter.is_some() {
+            flags |= FLAG_FILTER;
+        }
+        if self.chunk_mode {
+            flags |= FLAG_CHUNK_MODE;
+        }
+        if self.bucket_size.is_some() {
+            flags |= FLAG_BUCKET_SIZE;
+        }
+        let match_options = self.lz_cfg.as_ref().map(match_options);
+        let match_options = match_options.filter(|&options| options != DEFAULT_MATCH_OPTIONS);
+        if match_options.is_some() {
+            flags |= FLAG_MATCH_OPTIONS;
+        }
+        w.write_all(&FRAME_MAGIC)?;
+        w.write_all(&[self.version, flags])?;
+        if let Some(file_info) = &self.file_info {
+            file_info.write(w)?;
+        }
+        if let Some(lz_cfg) = &self.lz_cfg {
+            w.write_len(lz_cfg.match_depth)?;
+            w.write_len(lz_cfg.lazy_match_depth1)?;
+            w.write_len(lz_cfg.lazy_match_depth2)?;
+        }
+        if let Some(reordering) = &self.reordering {
+            reordering.write(w)?;
+        }
+        if let Some(filter) = &self.filter {
+            filter.write(w)?;
+        }
+        if let Some(bucket_size) = self.bucket_size {
+            w.write_len(bucket_size)?;
+        }
+        for option in match_options.into_iter().flatten() {
+            w.write_len(option)?;
+        }
+        Ok(())
+    }
+
+    /// Reads the header following the frame magic.
+    fn read<R: Read>(r: &mut R) -> Result<Self> {
+        let mut version_and_flags = [0u8; 2];
+        r.read_exact(&mut version_and_flags)?;
+        let [version, flags] = version_and_flags;
+        if !(MIN_FORMAT_VERSION..=FORMAT_VERSION).contains(&version) {
+            let msg = format!("unsupported format version: {}", version);
+            return Err(Error::new(ErrorKind::InvalidData, msg));
+        }
+        let known_flags = FLAG_FILE_INFO
+            | FLAG_CHUNK_CHECKSUM
+            | FLAG_LZ_CFG
+            | FLAG_REORDERING
+            | FLAG_FILTER
+            | FLAG_CHUNK_MODE
+            | FLAG_BUCKET_SIZE
+            | FLAG_MATCH_OPTIONS;
+        if flags & !known_flags != 0 {
+            let msg = format!("unsupported frame flags: {:#x}", flags);
+            return Err(Error::new(ErrorKind::InvalidData, msg));
+        }
+        let file_info = match flags & FLAG_FILE_INFO {
+            0 => None,
+            _ => Some(FileInfo::read(r)?),
+        };
+        let lz_cfg = match flags & FLAG_LZ_CFG {
+            0 => None,
+            _ => Some(LZCfg::new(r.read_len()?, r.read_len()?, r.read_len()?)),
+        };
+        let reordering = match flags & FLAG_REORDERING {
+            0 => None,
+            _ => Some(Reordering::read(r)?),
+        };
+        let filter = match flags & FLAG_FILTER {
+            0 => None,
+            _ => Some(Filter::read(r)?),
+        };
+        let bucket_size = match flags & FLAG_BUCKET_SIZE {
+            0 => None,
+            _ => match r.read_len()? {
+                size if LZ_MF_BUCKET_ITEM_SIZES.contains(&size) => Some(size),
+                size => {
+                    let msg = format!("unsupported bucket size: {}", size);
+                    return Err(Error::new(ErrorKind::InvalidData, msg));
+                }
+            },
+        };
+        // the recorded options were used with the bucket size of the stream
+        let mut lz_cfg = lz_cfg.map(|cfg| LZCfg {
+            bucket_size: bucket_size.unwrap_or(LZ_MF_BUCKET_ITEM_SIZE),
+            ..cfg
+        });
+        if flags & FLAG_MATCH_OPTIONS != 0 {
+            let invalid = || Error::new(ErrorKind::InvalidData, "invalid matching options");
+            let cfg = lz_cfg.as_mut().ok_or_else(invalid)?;
+            let mut options = [0; DEFAULT_MATCH_OPTIONS.len()];
+            for option in &mut options {
+                *option = r.read_len()?;
+            }
+            [
+                cfg.good_match_len,
+                cfg.lazy_match_max_len,
+                cfg.lazy_match_min_gain,
+                cfg.lazy_match_cheap_offset_bits,
+                cfg.lazy_match_lookahead,
+            ] = options;
+            cfg.validate().map_err(|_| invalid())?;
+        }
+        Ok(Self {
+            version,
+            file_info,
+            lz_cfg,
+            reordering,
+            filter,
+            chunk_checksum: flags & FLAG_CHUNK_CHECKSUM != 0,
+            chunk_mode: flags & FLAG_CHUNK_MODE != 0,
+            bucket_size,
+        })
+    }
+}
+
+// options of lz_cfg recorded only if they are not the defaults
+const DEFAULT_MATCH_OPTIONS: [usize; 5] = match_options(&LZCfg::new(0, 0, 0));
+
+const fn match_options(cfg: &LZCfg) -> [usize; 5] {
+    [
+        cfg.good_match_len,
+        cfg.lazy_match_max_len,
+        cfg.lazy_match_min_gain,
+        cfg.lazy_match_cheap_offset_bits,
+        cfg.lazy_match_lookahead,
+    ]
+}
+
+/// How the data of a chunk is encoded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChunkMode {
+    Lz(ContextHash),
+    /// The decoded data as is, for incompressible data.
+    Stored,
+    /// No data, the chunk decodes to the byte repeated.
+    Filled(u8),
+}
+
+pub struct ChunkHeader {
+    pub mode: Option<ChunkMode>,
+    pub decoded_len: usize,
+    pub checksum: Option<u32>,
+}
+
+impl ChunkHeader {
+    pub fn write<W: Write>(&self, w: &mut W) -> Result<()> {
+        match self.mode {
+            Some(ChunkMode::Lz(ContextHash::Text)) => w.write_all(&[CHUNK_MODE_LZ])?,
+            Some(ChunkMode::Lz(context)) => {
+                w.write_all(&[CHUNK_MODE_LZ_CONTEXT])?;
+                context.write(w)?;
+            }
+            Some(ChunkMode::Stored) => w.write_all(&[CHUNK_MODE_STORED])?,
+            Some(ChunkMode::Filled(b)) => w.write_all(&[CHUNK_MODE_FILLED, b])?,
+            None => {}
+        }
+        w.write_len(self.decoded_len)?;
+        if let Some(checksum) = self.checksum {
+            w.write_all(&checksum.to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Reads the header in front of the encoded data of a chunk, a mode and
+    /// a checksum are present if the frame header says so.
+    pub fn read<R: Read>(r: &mut R, chunk_mode: bool, chunk_checksum: bool) -> Result<Self> {
+        let mode = match chunk_mode {
+            true => {
+                let mut mode = [0u8];
+                r.read_exact(&mut mode)?;
+                match mode[0] {
+                    CHUNK_MODE_LZ => Some(ChunkMode::Lz(ContextHash::Text)),
+                    CHUNK_MODE_STORED => Some(ChunkMode::Stored),
+                    CHUNK_MODE_LZ_CONTEXT => Some(ChunkMode::Lz(ContextHash::read(r)?)),
+                    CHUNK_MODE_FILLED => {
+                        let mut b = [0u8];
+                        r.read_exact(&mut b)?;
+                        Some(ChunkMode::Filled(b[0]))
+                    }
+                    mode => {
+                        let msg = format!("unsupported chunk mode: {}", mode);
+                        return Err(Error::new(ErrorKind::InvalidData, msg));
+                    }
+                }
+            }
+            false => None,
+        };
+        let decoded_len = r.read_len()?;
+        let checksum = match chunk_checksum {
+            true => {
+                let mut checksum = [0u8; 4];
+                r.read_exact(&mut checksum)?;
+                Some(u32::from_le_bytes(checksum))
+            }
+            false => None,
+        };
+        Ok(Self {
+            mode,
+            decoded_len,
+            checksum,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_match_options() {
+        // returns the flags and the options read back
+        let write_and_parse = |lz_cfg: LZCfg| {
+            let header = FrameHeader {
+                version: FORMAT_VERSION,
+                lz_cfg: Some(lz_cfg),
+                ..FrameHeader::legacy()
+            };
+            let mut buf = vec![];
+            header.write(&mut buf).unwrap();
+            match Frame::parse(&buf).unwrap() {
+                Some((Frame::Data(header), len)) if len == buf.len() => {
+                    (buf[FRAME_MAGIC.len() + 1], header.lz_cfg.unwrap())
+                }
+                _ => panic!("frame header not parsed"),
+            }
+        };
+
+        // only options other than the defaults are recorded
+        let cfg = LZCfg::new(5, 3, 2);
+        let (flags, read_cfg) = write_and_parse(cfg);
+        assert_eq!((flags & FLAG_MATCH_OPTIONS, read_cfg), (0, cfg));
+
+        let tuned_cfg = cfg
+            .with_good_match_len(16)
+            .with_lazy_match_max_len(32)
+            .with_lazy_match_min_gain(2)
+            .with_lazy_match_cheap_offset_bits(6)
+            .with_lazy_match_lookahead(1);
+        let (flags, read_cfg) = write_and_parse(tuned_cfg);
+        assert_eq!(
+            (flags & FLAG_MATCH_OPTIONS, read_cfg),
+            (FLAG_MATCH_OPTIONS, tuned_cfg)
+        );
+        assert_eq!(read_cfg.level(), None);
+    }
+}
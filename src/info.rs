@@ -0,0 +1,180 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::io::{BufRead, BufReader, Error, ErrorKind, Read, Result};
+
+use crate::{
+    LZ_BLOCK_SIZE, SBVEC_PREMATCH_LEN,
+    filter::Filter,
+    frame::{ChunkHeader, ChunkMode, FileInfo, Frame, is_frame_magic_prefix},
+    ioutil::{CountRead, ReadExt},
+    lz::{ContextHash, LZCfg},
+};
+
+/// Layout of an ORZ stream, collected without decoding it.
+#[derive(Debug, Default)]
+pub struct StreamInfo {
+    pub frames: Vec<FrameInfo>,
+    pub metadata: Vec<MetadataInfo>,
+    /// Length of ignored data after the last frame.
+    pub trailing_len: usize,
+}
+
+#[derive(Debug)]
+pub struct FrameInfo {
+    pub version: u8,
+    pub file_info: Option<FileInfo>,
+    pub lz_cfg: Option<LZCfg>,
+    pub chunk_checksum: bool,
+    /// Number of segments if the input was reordered before encoding.
+    pub reordered_segments: Option<usize>,
+    /// Filter applied before encoding, sizes are of the filtered data.
+    pub filter: Option<Filter>,
+    pub num_chunks: usize,
+    /// Total frame length including the header.
+    pub encoded_len: usize,
+    pub blocks: Vec<BlockInfo>,
+}
+
+/// Sizes of a window block, the last block of a frame may be partial.
+#[derive(Debug, Default)]
+pub struct BlockInfo {
+    pub num_chunks: usize,
+    /// Number of chunks stored without encoding.
+    pub num_stored_chunks: usize,
+    /// Number of chunks of a single repeated byte.
+    pub num_filled_chunks: usize,
+    /// Context of the first LZ-encoded chunk.
+    pub context: Option<ContextHash>,
+    pub encoded_len: usize,
+    pub decoded_len: usize,
+}
+
+#[derive(Debug)]
+pub struct MetadataInfo {
+    pub tag: u32,
+    pub len: usize,
+}
+
+impl StreamInfo {
+    /// Walks the frame and chunk framing of an ORZ stream.
+    pub fn read<R: Read>(source: R) -> Result<Self> {
+        let mut source = CountRead::new(BufReader::new(source));
+        let mut info = Self::default();
+        loop {
+            let buf = source.fill_buf()?;
+            if buf.is_empty() {
+                break;
+            }
+            if !is_frame_magic_prefix(buf) {
+                if info.frames.is_empty() && info.metadata.is_empty() {
+                    return Err(Error::new(ErrorKind::InvalidData, "not an orz stream"));
+                }
+                info.trailing_len = std::io::copy(&mut source, &mut std::io::sink())? as usize;
+                break;
+            }
+
+            let frame_start = source.count();
+            match Frame::read(&mut source)? {
+                Frame::Data(header) => {
+                    let mut frame = FrameInfo {
+                        version: header.version,
+                        file_info: header.file_info,
+                        lz_cfg: header.lz_cfg,
+                        chunk_checksum: header.chunk_checksum,
+                        reordered_segments: header.reordering.map(|r| r.num_segments()),
+                        filter: header.filter,
+                        num_chunks: 0,
+                        encoded_len: 0,
+                        blocks: vec![],
+                    };
+                    loop {
+                        let chunk_start = source.count();
+                        let len = source.read_len()?;
+                        if len == 0 {
+                            break;
+                        }
+                        let body_start = source.count();
+                        let chunk_header = ChunkHeader::read(
+                            &mut source,
+                            header.chunk_mode,
+                            header.chunk_checksum,
+                        )?;
+                        let data_len = len
+                            .checked_sub(source.count() - body_start)
+                            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "truncated chunk"))?;
+                        source.skip_exact(data_len)?;
+                        frame.add_chunk(source.count() - chunk_start, &chunk_header);
+                    }
+                    frame.encoded_len = source.count() - frame_start;
+                    info.frames.push(frame);
+                }
+                Frame::Metadata { tag, len } => {
+                    source.skip_exact(len)?;
+                    info.metadata.push(MetadataInfo { tag, len });
+                }
+            }
+        }
+        Ok(info)
+    }
+
+    pub fn encoded_len(&self) -> usize {
+        self.frames.iter().map(|frame| frame.encoded_len).sum()
+    }
+
+    pub fn decoded_len(&self) -> usize {
+        self.frames.iter().map(FrameInfo::decoded_len).sum()
+    }
+}
+
+impl FrameInfo {
+    pub fn decoded_len(&self) -> usize {
+        self.blocks.iter().map(|block| block.decoded_len).sum()
+    }
+
+    fn add_chunk(&mut self, encoded_len: usize, header: &ChunkHeader) {
+        // chunks never cross block boundaries
+        if self
+            .blocks
+            .last()
+            .is_none_or(|block| block.decoded_len >= LZ_BLOCK_SIZE - SBVEC_PREMATCH_LEN)
+        {
+            self.blocks.push(BlockInfo::default());
+        }
+        let block = self.blocks.last_mut().unwrap();
+        block.num_chunks += 1;
+        match header.mode {
+            Some(ChunkMode::Stored) => block.num_stored_chunks += 1,
+            Some(ChunkMode::Filled(_)) => block.num_filled_chunks += 1,
+            Some(ChunkMode::Lz(context)) => _ = block.context.get_or_insert(context),
+            None => _ = block.context.get_or_insert(ContextHash::Text),
+        }
+        block.encoded_len += encoded_len;
+        block.decoded_len += header.decoded_len;
+        self.num_chunks += 1;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testutil::roundtrip;
+
+    #[test]
+    fn test_stream_info() {
+        let encode = |input: &[u8]| roundtrip(&LZCfg::new(5, 3, 2), input);
+        let mut encoded = [encode(b"hello"), encode(b"")].concat();
+        encoded.extend_from_slice(b"xx");
+
+        let info = StreamInfo::read(&encoded[..]).unwrap();
+        assert_eq!(info.frames.len(), 2);
+        assert_eq!(info.frames[0].lz_cfg, Some(LZCfg::new(5, 3, 2)));
+        assert_eq!(info.frames[0].num_chunks, 1);
+        assert_eq!(info.frames[1].num_chunks, 0);
+        assert_eq!(info.encoded_len() + info.trailing_len, encoded.len());
+        assert_eq!((info.decoded_len(), info.trailing_len), (5, 2));
+    }
+}
@@ -4,7 +4,7 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use std::io::{Read, Result, Write};
+use std::io::{BufRead, ErrorKind, Read, Result, Write};
 
 pub struct CountRead<R: Read>(R, usize);
 pub struct CountWrite<W: Write>(W, usize);
@@ -37,6 +37,17 @@ impl<R: Read> Read for CountRead<R> {
     }
 }
 
+impl<R: BufRead> BufRead for CountRead<R> {
+    fn fill_buf(&mut self) -> Result<&[u8]> {
+        self.0.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.0.consume(amt);
+        self.1 += amt;
+    }
+}
+
 impl<W: Write> Write for CountWrite<W> {
     fn write(&mut self, buf: &[u8]) -> Result<usize> {
         let len = self.0.write(buf)?;
@@ -51,12 +62,29 @@ impl<W: Write> Write for CountWrite<W> {
 
 pub trait ReadExt {
     fn read_len(&mut self) -> Result<usize>;
+    fn skip_exact(&mut self, len: usize) -> Result<()>;
 }
 
 pub trait WriteExt {
     fn write_len(&mut self, len: usize) -> Result<()>;
 }
 
+/// Adds the next byte of a length written by `write_len`, returns whether more
+/// bytes follow.
+///
+/// Lengths that do not fit in usize are rejected as invalid data.
+pub fn push_len_byte(len: &mut usize, factor: &mut usize, v: u8) -> Result<bool> {
+    *len = ((v & 127) as usize)
+        .checked_mul(*factor)
+        .and_then(|n| n.checked_add(*len))
+        .ok_or(ErrorKind::InvalidData)?;
+    if v < 128 {
+        return Ok(false);
+    }
+    *factor = factor.checked_mul(128).ok_or(ErrorKind::InvalidData)?;
+    Ok(true)
+}
+
 impl<R: Read> ReadExt for R {
     fn read_len(&mut self) -> Result<usize> {
         let mut buf = [0u8];
@@ -64,16 +92,20 @@ impl<R: Read> ReadExt for R {
         let mut factor = 1;
         loop {
             self.read_exact(&mut buf)?;
-            let v = buf[0];
-            if v < 128 {
-                len += (v as usize) * factor;
+            if !push_len_byte(&mut len, &mut factor, buf[0])? {
                 break;
             }
-            len += (v - 128) as usize * factor;
-            factor *= 128;
         }
         Ok(len)
     }
+
+    fn skip_exact(&mut self, len: usize) -> Result<()> {
+        let skipped = std::io::copy(&mut self.take(len as u64), &mut std::io::sink())?;
+        if skipped < len as u64 {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+        Ok(())
+    }
 }
 
 impl<W: Write> WriteExt for W {
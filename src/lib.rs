@@ -7,25 +7,49 @@
 #![feature(portable_simd)]
 #![feature(likely_unlikely)]
 
-// pub mod ffi;
+mod archive;
+#[cfg(feature = "async")]
+mod async_io;
+mod checksum;
+mod classify;
 mod coder;
+pub mod ffi;
+mod filter;
+mod frame;
 mod huffman;
+mod info;
 mod ioutil;
 mod lz;
 mod matcher;
 mod mem;
 mod progress;
+mod reorder;
+mod stream;
 mod symrank;
+#[cfg(test)]
+mod testutil;
+mod text;
 
-use std::io::{Read, Result, Write};
+use std::io::{Error, Read, Result, Write};
 
+pub use archive::{ArchiveEntry, ArchiveReader, ArchiveWriter, EntryKind};
+#[cfg(feature = "async")]
+pub use async_io::{OrzAsyncReader, OrzAsyncWriter};
+pub use filter::Filter;
+pub use frame::FileInfo;
+pub use info::{BlockInfo, FrameInfo, MetadataInfo, StreamInfo};
 pub use ioutil::{CountRead, CountWrite};
-pub use lz::LZCfg;
-pub use progress::{ProgressLogger, SilentProgressLogger, SimpleProgressLogger};
+pub use lz::{ContextHash, LZCfg};
+pub use progress::{
+    CANCELLED_MSG, ChunkProgress, ProgressLogger, SilentProgressLogger, SimpleProgressLogger,
+};
+pub use reorder::MAX_REORDERED_LEN;
+pub use stream::{Flush, OrzReader, OrzStream, OrzWriter, Status};
 
 use crate::{
-    ioutil::{ReadExt, WriteExt},
-    lz::{LZ_MF_BUCKET_ITEM_SIZE, LZDecoder, LZEncoder, SYMRANK_NUM_SYMBOLS},
+    frame::{Frame, is_frame_magic_prefix},
+    ioutil::ReadExt,
+    lz::SYMRANK_MAX_NUM_SYMBOLS,
 };
 
 const LZ_BLOCK_SIZE: usize = (1 << 25) - 1; //32MB
@@ -52,6 +76,9 @@ fn read_repeatedly<R: Read + ?Sized>(source: &mut R, buf: &mut [u8]) -> Result<u
 }
 
 const SBVEC_SENTINEL_LEN: usize = LZ_MATCH_MAX_LEN * 2;
+
+// decoded matches are copied with mem_fast_copy, which writes past their end
+const _: () = assert!(SBVEC_SENTINEL_LEN >= LZ_MATCH_MAX_LEN + mem::MEM_FAST_COPY_MAX_OVERRUN);
 const SBVEC_PREMATCH_LEN: usize = LZ_BLOCK_SIZE / 2;
 
 /// Encode the source into a target ORZ stream.
@@ -59,71 +86,202 @@ pub fn encode<R: Read, W: Write>(
     source: &mut CountRead<R>,
     target: &mut CountWrite<W>,
     cfg: &LZCfg,
-    progress_logger: &mut Box<dyn ProgressLogger>,
+    progress_logger: &mut impl ProgressLogger,
+) -> Result<()> {
+    encode_stream(
+        &mut OrzStream::encoder(cfg)?,
+        source,
+        target,
+        progress_logger,
+    )
+}
+
+/// Encode the source into a target ORZ stream with a configured encoding
+/// stream.
+pub fn encode_stream<R: Read, W: Write>(
+    stream: &mut OrzStream,
+    source: &mut CountRead<R>,
+    target: &mut CountWrite<W>,
+    progress_logger: &mut impl ProgressLogger,
+) -> Result<()> {
+    stream.set_return_per_chunk(true);
+    let result = encode_stream_with_progress(stream, source, target, progress_logger);
+    stream.set_return_per_chunk(false);
+    result
+}
+
+fn encode_stream_with_progress<R: Read, W: Write>(
+    stream: &mut OrzStream,
+    source: &mut CountRead<R>,
+    target: &mut CountWrite<W>,
+    progress_logger: &mut impl ProgressLogger,
 ) -> Result<()> {
-    let mut lzenc = LZEncoder::new();
     progress_logger.set_is_encode(true);
 
-    let mut sbvec_buf = vec![0u8; LZ_BLOCK_SIZE + SBVEC_SENTINEL_LEN * 2];
-    let mut tbvec_buf = vec![0u8; SBVEC_PREMATCH_LEN * 3];
-    let sbvec = &mut sbvec_buf[SBVEC_SENTINEL_LEN..][..LZ_BLOCK_SIZE];
-    let tbvec = &mut tbvec_buf;
-
-    while let sbvec_read_size = read_repeatedly(source, &mut sbvec[SBVEC_PREMATCH_LEN..])?
-        && sbvec_read_size > 0
-    {
-        let mut spos = SBVEC_PREMATCH_LEN;
-        while spos < SBVEC_PREMATCH_LEN + sbvec_read_size {
-            let sbvec = &sbvec[..SBVEC_PREMATCH_LEN + sbvec_read_size];
-            let (s, t) = lzenc.encode(cfg, &sbvec, tbvec.as_mut(), spos);
-            target.write_len(t)?;
-            target.write_all(&tbvec[..t])?;
-            spos = s;
+    let mut ibuf = vec![0u8; LZ_CHUNK_SIZE];
+    let mut obuf = vec![0u8; LZ_CHUNK_SIZE];
+    let mut num_blocks = 0;
+    let mut num_chunks = 0;
+    loop {
+        let ilen = read_repeatedly(source, &mut ibuf)?;
+        let flush = if ilen == 0 {
+            Flush::Finish
+        } else {
+            Flush::None
+        };
+        let mut ipos = 0;
+        loop {
+            let (consumed, produced, status) =
+                stream.compress(&ibuf[ipos..ilen], &mut obuf, flush)?;
+            target.write_all(&obuf[..produced])?;
+            ipos += consumed;
+
+            if stream.num_chunks() > num_chunks {
+                num_chunks = stream.num_chunks();
+                report_chunk(stream, progress_logger)?;
+            }
+            if stream.num_blocks() > num_blocks {
+                num_blocks = stream.num_blocks();
+                progress_logger.log(stream.total_in(), stream.total_out());
+            }
+            if status == Status::StreamEnd {
+                progress_logger.finish(stream.total_in(), stream.total_out());
+                return Ok(());
+            }
+            if ipos == ilen && produced < obuf.len() {
+                break;
+            }
         }
-        sbvec.copy_within(sbvec.len() - SBVEC_PREMATCH_LEN..sbvec.len(), 0);
-        lzenc.forward(sbvec.len() - SBVEC_PREMATCH_LEN); // reset orz_lz encoder
-        progress_logger.log(source.count(), target.count());
     }
+}
 
-    // write an empty chunk to mark eof
-    target.write_len(0)?;
-    progress_logger.finish(source.count(), target.count());
+/// Encode the source into a target ORZ stream with a configured encoding
+/// stream, grouping similar members together if the source is a tar archive.
+///
+/// The whole source is buffered in memory, and so is the whole decoded frame
+/// when decoding a reordered stream. Sources larger than [`MAX_REORDERED_LEN`]
+/// are not reordered.
+pub fn encode_stream_tar_reordered<R: Read, W: Write>(
+    stream: &mut OrzStream,
+    source: &mut CountRead<R>,
+    target: &mut CountWrite<W>,
+    progress_logger: &mut impl ProgressLogger,
+) -> Result<()> {
+    let mut data = vec![];
+    source.read_to_end(&mut data)?;
+    if let Some(reordering) = reorder::reorder_tar(&data) {
+        data = reordering.apply(&data);
+        stream.set_reordering(reordering)?;
+    }
+    encode_stream(
+        stream,
+        &mut CountRead::new(&data[..]),
+        target,
+        progress_logger,
+    )
+}
+
+/// Reports the last processed chunk, failing if the logger cancels coding.
+///
+/// Streams return after every chunk when reporting, so the consumed input is
+/// the position at the end of the chunk when decoding.
+fn report_chunk(stream: &OrzStream, progress_logger: &mut impl ProgressLogger) -> Result<()> {
+    let progress = ChunkProgress {
+        index: stream.num_chunks() - 1,
+        num_input_bytes: stream.total_in(),
+        num_filtered_bytes: stream.num_filtered_bytes(),
+        num_output_bytes: stream.total_out(),
+    };
+    if progress_logger.chunk(progress).is_break() {
+        return Err(Error::other(CANCELLED_MSG));
+    }
     Ok(())
 }
 
+/// Decode the source ORZ stream into target.
+///
+/// Reordered frames are buffered whole, see [`OrzStream::decoder`].
 pub fn decode<R: Read, W: Write>(
     target: &mut CountRead<R>,
     source: &mut CountWrite<W>,
-    progress_logger: &mut Box<dyn ProgressLogger>,
+    progress_logger: &mut impl ProgressLogger,
+) -> Result<()> {
+    decode_stream(&mut OrzStream::decoder(), target, source, progress_logger)
+}
+
+/// Decode the source ORZ stream into target with a configured decoding stream.
+pub fn decode_stream<R: Read, W: Write>(
+    stream: &mut OrzStream,
+    target: &mut CountRead<R>,
+    source: &mut CountWrite<W>,
+    progress_logger: &mut impl ProgressLogger,
+) -> Result<()> {
+    stream.set_return_per_chunk(true);
+    let result = decode_stream_with_progress(stream, target, source, progress_logger);
+    stream.set_return_per_chunk(false);
+    result
+}
+
+fn decode_stream_with_progress<R: Read, W: Write>(
+    stream: &mut OrzStream,
+    target: &mut CountRead<R>,
+    source: &mut CountWrite<W>,
+    progress_logger: &mut impl ProgressLogger,
 ) -> Result<()> {
-    let mut lzdec = LZDecoder::new();
     progress_logger.set_is_encode(false);
 
-    let mut sbvec_buf = vec![0u8; LZ_BLOCK_SIZE * 2 + SBVEC_SENTINEL_LEN * 2];
-    let mut tbvec_buf = vec![0u8; SBVEC_PREMATCH_LEN * 3];
-    let sbvec = &mut sbvec_buf[SBVEC_SENTINEL_LEN..][..LZ_BLOCK_SIZE];
-    let tbvec = &mut tbvec_buf;
-
-    let mut spos = SBVEC_PREMATCH_LEN;
-    while let t = target.read_len()?
-        && t != 0
-    {
-        if t >= tbvec.len() {
-            return Err(std::io::ErrorKind::InvalidData.into());
-        }
-        target.read_exact(&mut tbvec[..t])?;
+    let mut tbuf = vec![0u8; LZ_CHUNK_SIZE];
+    let mut sbuf = vec![0u8; LZ_CHUNK_SIZE];
+    let mut num_blocks = 0;
+    let mut num_chunks = 0;
+    loop {
+        let tlen = target.read(&mut tbuf)?;
+        let flush = if tlen == 0 {
+            Flush::Finish
+        } else {
+            Flush::None
+        };
+        let mut tpos = 0;
+        loop {
+            let (consumed, produced, status) =
+                stream.decompress(&tbuf[tpos..tlen], &mut sbuf, flush)?;
+            source.write_all(&sbuf[..produced])?;
+            tpos += consumed;
 
-        let spos_end = lzdec.decode(&tbvec[..t], sbvec.as_mut(), spos)?;
-        source.write_all(&sbvec[spos..spos_end])?;
-        spos = spos_end;
+            if stream.num_chunks() > num_chunks {
+                num_chunks = stream.num_chunks();
+                report_chunk(stream, progress_logger)?;
+            }
+            if stream.num_blocks() > num_blocks {
+                num_blocks = stream.num_blocks();
+                progress_logger.log(stream.total_in(), stream.total_out());
+            }
+            if status == Status::StreamEnd {
+                progress_logger.finish(stream.total_in(), stream.total_out());
+                return Ok(());
+            }
+            if tpos == tlen && produced < sbuf.len() {
+                source.flush()?; // output everything before waiting for more input
+                break;
+            }
+        }
+    }
+}
 
-        if spos >= LZ_BLOCK_SIZE {
-            sbvec.copy_within(sbvec.len() - SBVEC_PREMATCH_LEN..sbvec.len(), 0);
-            lzdec.forward(sbvec.len() - SBVEC_PREMATCH_LEN);
-            progress_logger.log(target.count(), source.count());
-            spos = SBVEC_PREMATCH_LEN;
+/// Reads the file info stored in the first data frame header of an ORZ
+/// stream, skipping leading metadata frames. Streams without frame headers
+/// have no file info.
+pub fn read_file_info<R: Read>(source: &mut R) -> Result<Option<FileInfo>> {
+    let mut magic = [0u8; 4];
+    source.read_exact(&mut magic)?;
+    if !is_frame_magic_prefix(&magic) {
+        return Ok(None);
+    }
+    let mut source = magic.chain(source);
+    loop {
+        match Frame::read(&mut source)? {
+            Frame::Data(header) => return Ok(header.file_info),
+            Frame::Metadata { len, .. } => source.skip_exact(len)?,
         }
     }
-    progress_logger.finish(target.count(), source.count());
-    Ok(())
 }
@@ -6,42 +6,269 @@
 
 use std::{
     cmp::{Ordering, Reverse},
-    io::Result,
+    fmt::{self, Display},
+    io::{Error, ErrorKind, Read, Result, Write},
 };
 
 use unchecked_index::UncheckedIndex;
 
 use crate::{
-    LZ_CHUNK_SIZE, LZ_MATCH_MAX_LEN, LZ_MATCH_MIN_LEN,
+    LZ_CHUNK_SIZE, LZ_MATCH_MAX_LEN, LZ_MATCH_MIN_LEN, SBVEC_SENTINEL_LEN, classify,
     coder::{Decoder, Encoder},
+    frame::{FORMAT_VERSION, LONG_MATCH_VERSION, REP_MATCH_VERSION},
     huffman::{HuffmanDecoding, HuffmanEncoding, HuffmanTable},
     matcher::{Bucket, BucketMatcher},
-    mem::{BytesConstPtrExt, BytesMutPtrExt, mem_fast_copy},
+    mem::{BytesConstPtrExt, BytesMutPtrExt, mem_fast_common_prefix, mem_fast_copy},
     symrank::SymRankCoder,
     unchecked,
 };
 
 pub const LZ_MF_BUCKET_ITEM_SIZE: usize = 4094;
-pub const SYMRANK_NUM_SYMBOLS: usize = 256 + LZ_ROID_SIZE * LZ_LENID_SIZE + 1;
+// supported bucket sizes, the reduced offsets of a bucket of 2 * (2^k - 1)
+// items are split into 2k classes
+pub const LZ_MF_BUCKET_ITEM_SIZES: [usize; 7] = [1022, 2046, 4094, 8190, 16382, 32766, 65534];
+pub const SYMRANK_MAX_NUM_SYMBOLS: usize = 256 + LZ_MAX_ROID_SIZE * LZ_LENID_SIZE + LZ_REP_SIZE + 1;
 
-const LZ_ROID_SIZE: usize = 22;
+// max bytes encoded per context when choosing one, smaller samples often
+// choose a worse context
+const CONTEXT_SAMPLE_LEN: usize = 1 << 18;
+
+const LZ_MAX_ROID_SIZE: usize = 30;
 const LZ_LENID_SIZE: usize = 6;
-const WORD_SYMBOL: u16 = SYMRANK_NUM_SYMBOLS as u16 - 1;
+// symbols repeating the offset of the last match, one per length id
+const LZ_REP_SIZE: usize = LZ_LENID_SIZE;
+// max bytes a match of LZ_MATCH_MAX_LEN is extended by
+const LZ_MATCH_MAX_EXT_LEN: usize = 65535;
 
-/// Limpel-Ziv matching options.
+/// Limpel-Ziv matching options, recorded in the frame header.
 #[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct LZCfg {
     pub match_depth: usize,
     pub lazy_match_depth1: usize,
     pub lazy_match_depth2: usize,
+    /// Positions kept in each match finder bucket, fixed for a stream. Must be
+    /// `2 * (2^k - 1)` for k in 9..=15.
+    pub bucket_size: usize,
+    /// Match length ending the search for a longer match (4..=240).
+    pub good_match_len: usize,
+    /// Only matches shorter than this are deferred for a longer match at the
+    /// next positions.
+    pub lazy_match_max_len: usize,
+    /// Bytes a match at the next positions must be longer by (at least 1),
+    /// plus one byte if the current match has a cheap offset.
+    pub lazy_match_min_gain: usize,
+    /// Offsets with fewer extra bits than this are cheap.
+    pub lazy_match_cheap_offset_bits: usize,
+    /// Positions after the current one searched for a longer match (0..=2).
+    pub lazy_match_lookahead: usize,
 }
 
 impl LZCfg {
-    pub fn new(match_depth: usize, lazy_match_depth1: usize, lazy_match_depth2: usize) -> Self {
+    /// Creates options with the default bucket size and lazy matching.
+    pub const fn new(
+        match_depth: usize,
+        lazy_match_depth1: usize,
+        lazy_match_depth2: usize,
+    ) -> Self {
         Self {
             match_depth,
             lazy_match_depth1,
             lazy_match_depth2,
+            bucket_size: LZ_MF_BUCKET_ITEM_SIZE,
+            good_match_len: LZ_MATCH_MAX_LEN,
+            lazy_match_max_len: LZ_MATCH_MAX_LEN / 2,
+            lazy_match_min_gain: 1,
+            lazy_match_cheap_offset_bits: 8,
+            lazy_match_lookahead: 2,
+        }
+    }
+
+    pub fn with_bucket_size(self, bucket_size: usize) -> Self {
+        Self {
+            bucket_size,
+            ..self
+        }
+    }
+
+    pub fn with_good_match_len(self, good_match_len: usize) -> Self {
+        Self {
+            good_match_len,
+            ..self
+        }
+    }
+
+    pub fn with_lazy_match_max_len(self, lazy_match_max_len: usize) -> Self {
+        Self {
+            lazy_match_max_len,
+            ..self
+        }
+    }
+
+    pub fn with_lazy_match_min_gain(self, lazy_match_min_gain: usize) -> Self {
+        Self {
+            lazy_match_min_gain,
+            ..self
+        }
+    }
+
+    pub fn with_lazy_match_cheap_offset_bits(self, lazy_match_cheap_offset_bits: usize) -> Self {
+        Self {
+            lazy_match_cheap_offset_bits,
+            ..self
+        }
+    }
+
+    pub fn with_lazy_match_lookahead(self, lazy_match_lookahead: usize) -> Self {
+        Self {
+            lazy_match_lookahead,
+            ..self
+        }
+    }
+
+    /// Checks that the options are in range.
+    pub fn validate(&self) -> Result<()> {
+        let invalid = |msg: String| Err(Error::new(ErrorKind::InvalidInput, msg));
+        if self.match_depth == 0 {
+            return invalid("match_depth must be positive".to_owned());
+        }
+        if !LZ_MF_BUCKET_ITEM_SIZES.contains(&self.bucket_size) {
+            return invalid(format!("unsupported bucket size: {}", self.bucket_size));
+        }
+        if !(LZ_MATCH_MIN_LEN..=LZ_MATCH_MAX_LEN).contains(&self.good_match_len) {
+            return invalid(format!(
+                "invalid good match length: {}",
+                self.good_match_len
+            ));
+        }
+        if self.lazy_match_min_gain == 0 {
+            return invalid("lazy_match_min_gain must be positive".to_owned());
+        }
+        // longer lazy matches would not be found by the next search
+        if self.lazy_match_max_len + self.lazy_match_min_gain > LZ_MATCH_MAX_LEN {
+            return invalid(format!(
+                "lazy_match_max_len + lazy_match_min_gain exceeds {}",
+                LZ_MATCH_MAX_LEN
+            ));
+        }
+        if self.lazy_match_lookahead > 2 {
+            return invalid(format!(
+                "invalid lazy lookahead: {}",
+                self.lazy_match_lookahead
+            ));
+        }
+        Ok(())
+    }
+
+    /// Matching options of the predefined compression levels (0..2).
+    pub fn from_level(level: u8) -> Option<Self> {
+        let (cfg, bucket_size) = match level {
+            0 => (LZCfg::new(5, 3, 2), 4094),
+            1 => (LZCfg::new(15, 9, 6), 8190),
+            2 => (LZCfg::new(45, 27, 18), 16382),
+            _ => return None,
+        };
+        Some(LZCfg { bucket_size, ..cfg })
+    }
+
+    /// The predefined compression level with these options, if any.
+    pub fn level(&self) -> Option<u8> {
+        (0..=2).find(|&level| LZCfg::from_level(level) == Some(*self))
+    }
+}
+
+/// Function selecting the match finder bucket and symbol ranking context of
+/// a position from the bytes before it, recorded for every chunk.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ContextHash {
+    /// Low 7 bits of the previous byte and whether the byte before it is
+    /// alphanumeric, for text.
+    Text,
+    /// The previous byte.
+    Byte,
+    /// A hash of the previous 2 bytes.
+    Pair,
+    /// The byte `stride` (2..=255) bytes before, which is in the same column
+    /// of the previous record for fixed-width records.
+    Column(u8),
+}
+
+impl ContextHash {
+    /// Chooses the context giving the smallest output for a sample of data by
+    /// encoding it with each. Other contexts must beat the text context by
+    /// more than 1/1024, as small samples favor contexts with fewer distinct
+    /// values.
+    pub fn choose(sample: &[u8]) -> Self {
+        let sample = &sample[..sample.len().min(CONTEXT_SAMPLE_LEN)];
+        let mut contexts = vec![ContextHash::Byte, ContextHash::Pair];
+        if let stride @ 2.. = classify::detect_stride(sample) {
+            contexts.push(ContextHash::Column(stride));
+        }
+
+        // sampled data follows room for the context of its first bytes, and is
+        // followed by the sentinel read past the end of matches
+        let mut sbuf = vec![0u8; 256];
+        sbuf.extend_from_slice(sample);
+        sbuf.resize(sbuf.len() + SBVEC_SENTINEL_LEN, 0);
+        let sbuf_end = sbuf.len() - SBVEC_SENTINEL_LEN;
+        let mut tbuf = vec![0u8; sbuf.len() * 3 + 4096];
+        let cfg = LZCfg::from_level(0).unwrap();
+        let mut encoded_len = |context| {
+            let mut lzenc = LZEncoder::new(cfg.bucket_size);
+            let (_, t) = lzenc.encode(&cfg, context, &sbuf[..sbuf_end], &mut tbuf, 256);
+            t
+        };
+        let text_len = encoded_len(ContextHash::Text);
+        let (len, context) = contexts
+            .into_iter()
+            .map(|context| (encoded_len(context), context))
+            .min_by_key(|&(len, _)| len)
+            .unwrap();
+        match len < text_len - text_len / 1024 {
+            true => context,
+            false => ContextHash::Text,
+        }
+    }
+
+    pub(crate) fn write<W: Write>(&self, w: &mut W) -> Result<()> {
+        match self {
+            ContextHash::Text => w.write_all(&[0]),
+            ContextHash::Byte => w.write_all(&[1]),
+            ContextHash::Pair => w.write_all(&[2]),
+            ContextHash::Column(stride) => w.write_all(&[3, *stride]),
+        }
+    }
+
+    pub(crate) fn read<R: Read>(r: &mut R) -> Result<Self> {
+        let mut id = [0u8];
+        r.read_exact(&mut id)?;
+        match id[0] {
+            0 => Ok(ContextHash::Text),
+            1 => Ok(ContextHash::Byte),
+            2 => Ok(ContextHash::Pair),
+            3 => {
+                let mut stride = [0u8];
+                r.read_exact(&mut stride)?;
+                match stride[0] {
+                    2.. => Ok(ContextHash::Column(stride[0])),
+                    _ => Err(Error::new(ErrorKind::InvalidData, "invalid context stride")),
+                }
+            }
+            id => {
+                let msg = format!("unsupported context hash: {}", id);
+                Err(Error::new(ErrorKind::InvalidData, msg))
+            }
+        }
+    }
+}
+
+impl Display for ContextHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ContextHash::Text => write!(f, "text"),
+            ContextHash::Byte => write!(f, "byte"),
+            ContextHash::Pair => write!(f, "pair"),
+            ContextHash::Column(stride) => write!(f, "column:{}", stride),
         }
     }
 }
@@ -50,32 +277,65 @@ struct LZContext {
     buckets: UncheckedIndex<Vec<Bucket>>,
     symranks: UncheckedIndex<Vec<SymRankCoder>>,
     words: UncheckedIndex<Vec<[u8; 2]>>,
+    bucket_size: usize,
+    version: u8,
+    num_symbols: usize,
+    // first repeated offset symbol, equal to the word symbol without them
+    rep_symbol: u16,
+    // whether matches of LZ_MATCH_MAX_LEN are followed by an extra length
+    long_matches: bool,
+    // distance of the last match, 0 before the first one
+    rep_offset: usize,
     first_block: bool,
     after_literal: bool,
 }
 
 impl LZContext {
-    pub fn new() -> Self {
+    pub fn new(bucket_size: usize, version: u8) -> Self {
+        assert!(
+            LZ_MF_BUCKET_ITEM_SIZES.contains(&bucket_size),
+            "unsupported bucket size: {}",
+            bucket_size,
+        );
+        let rep_symbol = 256 + num_roids(bucket_size) * LZ_LENID_SIZE;
+        let rep_match = version >= REP_MATCH_VERSION;
+        let num_symbols = rep_symbol + rep_match as usize * LZ_REP_SIZE + 1;
         Self {
-            buckets: unchecked!((0..256).map(|_| Bucket::new()).collect()),
-            symranks: unchecked!((0..512).map(|_| SymRankCoder::new()).collect()),
+            buckets: unchecked!((0..256).map(|_| Bucket::new(bucket_size)).collect()),
+            symranks: unchecked!((0..512).map(|_| SymRankCoder::new(num_symbols)).collect()),
             words: unchecked!(vec![[0, 0]; 32768]),
+            bucket_size,
+            version,
+            num_symbols,
+            rep_symbol: rep_symbol as u16,
+            long_matches: version >= LONG_MATCH_VERSION,
+            rep_offset: 0,
             first_block: true,
             after_literal: true,
         }
     }
+
+    fn word_symbol(&self) -> u16 {
+        self.num_symbols as u16 - 1
+    }
 }
 
 pub struct LZEncoder {
     ctx: LZContext,
     bucket_matchers: UncheckedIndex<Vec<BucketMatcher>>,
+    roid_encodings: UncheckedIndex<Vec<(u8, u8, u16)>>,
 }
 
 impl LZEncoder {
-    pub fn new() -> Self {
+    /// Creates an encoder with `bucket_size` positions per match finder
+    /// bucket, panics if the size is not supported.
+    pub fn new(bucket_size: usize) -> Self {
         Self {
-            ctx: LZContext::new(),
-            bucket_matchers: unchecked!((0..256).map(|_| BucketMatcher::new()).collect()),
+            ctx: LZContext::new(bucket_size, FORMAT_VERSION),
+            bucket_matchers: unchecked!(
+                (0..256).map(|_| BucketMatcher::new(bucket_size)).collect()
+            ),
+            roid_encodings: unchecked!(roid_encodings(bucket_size)),
         }
     }
 
@@ -89,11 +349,34 @@ impl LZEncoder {
     pub fn encode(
         &mut self,
         cfg: &LZCfg,
+        context: ContextHash,
         sbuf: &[u8],
         tbuf: &mut [u8],
         spos: usize,
     ) -> (usize, usize) {
-        let roid_encoding_array = &unchecked!(&LZ_ROID_ENCODING_ARRAY);
+        match context {
+            ContextHash::Text => self.encode_with(cfg, hash1, sbuf, tbuf, spos),
+            ContextHash::Byte => self.encode_with(cfg, hash_byte, sbuf, tbuf, spos),
+            ContextHash::Pair => self.encode_with(cfg, hash_pair, sbuf, tbuf, spos),
+            ContextHash::Column(stride) => {
+                let hash = |buf: &[u8], pos| hash_column(buf, pos, stride as usize);
+                self.encode_with(cfg, hash, sbuf, tbuf, spos)
+            }
+        }
+    }
+
+    #[inline(always)]
+    fn encode_with(
+        &mut self,
+        cfg: &LZCfg,
+        context: impl Fn(&[u8], usize) -> usize + Copy,
+        sbuf: &[u8],
+        tbuf: &mut [u8],
+        spos: usize,
+    ) -> (usize, usize) {
+        let word_symbol = self.ctx.word_symbol();
+        let rep_symbol = self.ctx.rep_symbol;
+        let long_matches = self.ctx.long_matches;
         let sbuf = &unchecked!(sbuf);
         let tbuf = &mut unchecked!(tbuf);
 
@@ -105,6 +388,7 @@ impl LZEncoder {
                 robitlen: u8,
                 robits: u16,
                 encoded_match_len: u8,
+                ext_len: Option<u16>,
                 after_literal: bool,
             },
             Symbol {
@@ -132,28 +416,68 @@ impl LZEncoder {
             let last_word_expected = self.ctx.words[hash2(sbuf, spos - 1)];
             let last_word_matched = sbuf.as_ptr().get::<[u8; 2]>(spos) == last_word_expected;
             let symrank_context =
-                hash1(sbuf, spos - 1) as u16 | (self.ctx.after_literal as u16) << 8;
+                context(sbuf, spos - 1) as u16 | (self.ctx.after_literal as u16) << 8;
             let symrank_unlikely = last_word_expected[0];
 
             // encode as match
             let mut lazy_match_id = 0;
-            let m = self.bucket_matchers[hash1(sbuf, spos - 1)].find_match(
-                &self.ctx.buckets[hash1(sbuf, spos - 1)],
+            let m = self.bucket_matchers[context(sbuf, spos - 1)].find_match(
+                &self.ctx.buckets[context(sbuf, spos - 1)],
                 sbuf,
                 spos,
                 cfg.match_depth,
+                cfg.good_match_len,
             );
 
+            // encode as repeated offset if it matches at least as long
+            let rep_offset = self.ctx.rep_offset;
+            if rep_symbol < word_symbol && rep_offset > 0 && rep_offset <= spos {
+                let rep_pos = spos - rep_offset;
+                let rep_len =
+                    mem_fast_common_prefix(sbuf.as_ptr(), rep_pos, spos, LZ_MATCH_MAX_LEN);
+                if rep_len >= m.match_len.max(LZ_MATCH_MIN_LEN) && spos + rep_len < sbuf.len() {
+                    let encoded_match_len = (rep_len - LZ_MATCH_MIN_LEN) as u8;
+                    let lenid = std::cmp::min(LZ_LENID_SIZE as u8 - 1, encoded_match_len);
+                    let ext_len = match long_matches && rep_len == LZ_MATCH_MAX_LEN {
+                        true => Some(match_ext_len(sbuf, rep_pos, spos)),
+                        false => None,
+                    };
+                    match_items.push(MatchItem::Match {
+                        symbol: rep_symbol + lenid as u16,
+                        symrank_context,
+                        symrank_unlikely,
+                        robitlen: 0,
+                        robits: 0,
+                        encoded_match_len,
+                        ext_len,
+                        after_literal: self.ctx.after_literal,
+                    });
+
+                    self.ctx.buckets[context(sbuf, spos - 1)].update(spos, 0, 0);
+                    self.bucket_matchers[context(sbuf, spos - 1)].update(
+                        &self.ctx.buckets[context(sbuf, spos - 1)],
+                        sbuf,
+                        spos,
+                    );
+                    spos += rep_len + ext_len.unwrap_or(0) as usize;
+                    self.ctx.after_literal = false;
+                    self.ctx.words[hash2(sbuf, spos - 3)] = sbuf.as_ptr().get(spos - 2);
+                    continue;
+                }
+            }
+
             if m.match_len > 0 {
-                let (roid, robitlen, robits) = roid_encoding_array[m.reduced_offset as usize];
+                let (roid, robitlen, robits) = self.roid_encodings[m.reduced_offset];
 
                 // find lazy match
-                if m.match_len < LZ_MATCH_MAX_LEN / 2 {
-                    let lazy_len1 = m.match_len + 1 + (robitlen < 8) as usize;
+                if m.match_len < cfg.lazy_match_max_len {
+                    let lazy_len1 = m.match_len
+                        + cfg.lazy_match_min_gain
+                        + ((robitlen as usize) < cfg.lazy_match_cheap_offset_bits) as usize;
                     let lazy_len2 = lazy_len1 - last_word_matched as usize;
                     let has_lazy_match = |pos, lazy_len, match_depth| {
-                        let lazy_bucket_matcher = &self.bucket_matchers[hash1(sbuf, pos)];
-                        let lazy_bucket = &self.ctx.buckets[hash1(sbuf, pos)];
+                        let lazy_bucket_matcher = &self.bucket_matchers[context(sbuf, pos)];
+                        let lazy_bucket = &self.ctx.buckets[context(sbuf, pos)];
                         lazy_bucket_matcher.has_lazy_match(
                             lazy_bucket,
                             sbuf,
@@ -162,9 +486,18 @@ impl LZEncoder {
                             match_depth,
                         )
                     };
+                    let lookahead = cfg.lazy_match_lookahead;
                     lazy_match_id = match () {
-                        _ if has_lazy_match(spos, lazy_len1, cfg.lazy_match_depth1) => 1,
-                        _ if has_lazy_match(spos + 1, lazy_len2, cfg.lazy_match_depth2) => 2,
+                        _ if lookahead >= 1
+                            && has_lazy_match(spos, lazy_len1, cfg.lazy_match_depth1) =>
+                        {
+                            1
+                        }
+                        _ if lookahead >= 2
+                            && has_lazy_match(spos + 1, lazy_len2, cfg.lazy_match_depth2) =>
+                        {
+                            2
+                        }
                         _ => 0,
                     };
                 }
@@ -178,6 +511,10 @@ impl LZEncoder {
                     let lenid = std::cmp::min(LZ_LENID_SIZE as u8 - 1, encoded_match_len);
                     let encoded_roid_lenid =
                         256 + roid as u16 * LZ_LENID_SIZE as u16 + lenid as u16;
+                    let ext_len = match long_matches && m.match_len == LZ_MATCH_MAX_LEN {
+                        true => Some(match_ext_len(sbuf, m.pos, spos)),
+                        false => None,
+                    };
                     match_items.push(MatchItem::Match {
                         symbol: encoded_roid_lenid,
                         symrank_context,
@@ -185,28 +522,30 @@ impl LZEncoder {
                         robitlen,
                         robits,
                         encoded_match_len,
+                        ext_len,
                         after_literal: self.ctx.after_literal,
                     });
 
-                    self.ctx.buckets[hash1(sbuf, spos - 1)].update(
+                    self.ctx.buckets[context(sbuf, spos - 1)].update(
                         spos,
                         m.reduced_offset,
                         m.match_len,
                     );
-                    self.bucket_matchers[hash1(sbuf, spos - 1)].update(
-                        &self.ctx.buckets[hash1(sbuf, spos - 1)],
+                    self.bucket_matchers[context(sbuf, spos - 1)].update(
+                        &self.ctx.buckets[context(sbuf, spos - 1)],
                         sbuf,
                         spos,
                     );
-                    spos += m.match_len;
+                    self.ctx.rep_offset = spos - m.pos;
+                    spos += m.match_len + ext_len.unwrap_or(0) as usize;
                     self.ctx.after_literal = false;
                     self.ctx.words[hash2(sbuf, spos - 3)] = sbuf.as_ptr().get(spos - 2);
                     continue;
                 }
             }
-            self.ctx.buckets[hash1(sbuf, spos - 1)].update(spos, 0, 0);
-            self.bucket_matchers[hash1(sbuf, spos - 1)].update(
-                &self.ctx.buckets[hash1(sbuf, spos - 1)],
+            self.ctx.buckets[context(sbuf, spos - 1)].update(spos, 0, 0);
+            self.bucket_matchers[context(sbuf, spos - 1)].update(
+                &self.ctx.buckets[context(sbuf, spos - 1)],
                 sbuf,
                 spos,
             );
@@ -214,7 +553,7 @@ impl LZEncoder {
             // encode as symbol
             if spos + 1 < sbuf.len() && lazy_match_id != 1 && last_word_matched {
                 match_items.push(MatchItem::Symbol {
-                    symbol: WORD_SYMBOL,
+                    symbol: word_symbol,
                     symrank_context,
                     symrank_unlikely,
                     after_literal: self.ctx.after_literal,
@@ -237,14 +576,14 @@ impl LZEncoder {
         // init symrank array
         if self.ctx.first_block {
             // count symbols
-            let symbol_counts = &mut [0; SYMRANK_NUM_SYMBOLS];
+            let symbol_counts = &mut [0; SYMRANK_MAX_NUM_SYMBOLS][..self.ctx.num_symbols];
             for m in &match_items {
                 symbol_counts[m.symbol() as usize] += 1;
             }
             let num_counted_symbols = symbol_counts.iter().filter(|&&c| c > 1).count();
 
             // sort symbols by count
-            let mut vs = (0..SYMRANK_NUM_SYMBOLS as u16)
+            let mut vs = (0..self.ctx.num_symbols as u16)
                 .into_iter()
                 .collect::<Vec<_>>();
             vs.sort_by_key(|&i| Reverse(symbol_counts[i as usize].max(1)));
@@ -256,7 +595,7 @@ impl LZEncoder {
             }
 
             // init all symranks with sorted symbols
-            let mut initial_symrank = SymRankCoder::new();
+            let mut initial_symrank = SymRankCoder::new(self.ctx.num_symbols);
             initial_symrank.init(&vs);
             for symranks in &mut self.ctx.symranks[..] {
                 *symranks = initial_symrank.clone();
@@ -269,7 +608,7 @@ impl LZEncoder {
         encoder.encode_varint(match_items.len() as u32);
 
         // start Huffman encoding
-        let mut huff_weights1 = unchecked!([[0u32; SYMRANK_NUM_SYMBOLS]; 2]);
+        let mut huff_weights1 = unchecked!([[0u32; SYMRANK_MAX_NUM_SYMBOLS]; 2]);
         let mut huff_weights2 = unchecked!([0u32; LZ_MATCH_MAX_LEN]);
         for match_item in &mut match_items {
             match match_item {
@@ -304,8 +643,8 @@ impl LZEncoder {
             }
         }
         let huff_table1 = [
-            HuffmanTable::new_from_sym_weights(&huff_weights1[0][..], 15),
-            HuffmanTable::new_from_sym_weights(&huff_weights1[1][..], 15),
+            HuffmanTable::new_from_sym_weights(&huff_weights1[0][..self.ctx.num_symbols], 15),
+            HuffmanTable::new_from_sym_weights(&huff_weights1[1][..self.ctx.num_symbols], 15),
         ];
         let huff_table2 = HuffmanTable::new_from_sym_weights(&huff_weights2[..], 15);
         encoder.encode_huffman_table(&huff_table1[0]);
@@ -330,6 +669,7 @@ impl LZEncoder {
                 robitlen,
                 robits,
                 encoded_match_len,
+                ext_len,
                 after_literal,
                 ..
             } => {
@@ -338,6 +678,11 @@ impl LZEncoder {
                 if encoded_match_len as usize >= LZ_LENID_SIZE - 1 {
                     encoder.encode_huffman_sym(&huff2, encoded_match_len as u16);
                 }
+                if let Some(ext_len) = ext_len {
+                    let ext_bitlen = u16::BITS - ext_len.leading_zeros();
+                    encoder.encode_raw_bits(ext_bitlen, 5);
+                    encoder.encode_raw_bits(ext_len as u32, ext_bitlen as u8);
+                }
             }
         });
 
@@ -347,15 +692,28 @@ impl LZEncoder {
 
 pub struct LZDecoder {
     ctx: LZContext,
+    roid_decodings: UncheckedIndex<Vec<(u16, u8)>>,
 }
 
 impl LZDecoder {
-    pub fn new() -> Self {
+    /// Creates a decoder with `bucket_size` positions per match finder
+    /// bucket for streams of the given format version, panics if the size is
+    /// not supported.
+    pub fn new(bucket_size: usize, version: u8) -> Self {
         Self {
-            ctx: LZContext::new(),
+            ctx: LZContext::new(bucket_size, version),
+            roid_decodings: unchecked!(roid_decodings(bucket_size)),
         }
     }
 
+    pub fn bucket_size(&self) -> usize {
+        self.ctx.bucket_size
+    }
+
+    pub fn version(&self) -> u8 {
+        self.ctx.version
+    }
+
     pub fn forward(&mut self, forward_len: usize) {
         self.ctx
             .buckets
@@ -363,28 +721,64 @@ impl LZDecoder {
             .for_each(|bucket| bucket.forward(forward_len));
     }
 
-    pub fn decode(&mut self, tbuf: &[u8], sbuf: &mut [u8], spos: usize) -> Result<usize> {
-        let roid_decoding_array = unchecked!(&LZ_ROID_DECODING_ARRAY);
+    // safety: sbuf must be followed by SBVEC_SENTINEL_LEN writable bytes, matches
+    // are copied with mem_fast_copy which writes past their end
+    pub fn decode(
+        &mut self,
+        context: ContextHash,
+        tbuf: &[u8],
+        sbuf: &mut [u8],
+        spos: usize,
+    ) -> Result<usize> {
+        match context {
+            ContextHash::Text => self.decode_with(hash1, tbuf, sbuf, spos),
+            ContextHash::Byte => self.decode_with(hash_byte, tbuf, sbuf, spos),
+            ContextHash::Pair => self.decode_with(hash_pair, tbuf, sbuf, spos),
+            ContextHash::Column(stride) => {
+                let hash = |buf: &[u8], pos| hash_column(buf, pos, stride as usize);
+                self.decode_with(hash, tbuf, sbuf, spos)
+            }
+        }
+    }
+
+    #[inline(always)]
+    fn decode_with(
+        &mut self,
+        context: impl Fn(&[u8], usize) -> usize,
+        tbuf: &[u8],
+        sbuf: &mut [u8],
+        spos: usize,
+    ) -> Result<usize> {
+        let num_symbols = self.ctx.num_symbols;
+        let word_symbol = self.ctx.word_symbol();
+        let rep_symbol = self.ctx.rep_symbol;
+        let long_matches = self.ctx.long_matches;
         let mut decoder: Decoder = Decoder::new(tbuf, 0);
         let mut spos = spos;
 
         // init symrank array
         if self.ctx.first_block {
             let mut num_counted_symbols = decoder.decode_varint() as usize;
-            let mut vs = [0; SYMRANK_NUM_SYMBOLS];
-            let mut set = [false; SYMRANK_NUM_SYMBOLS];
+            let mut vs = [0; SYMRANK_MAX_NUM_SYMBOLS];
+            let mut set = [false; SYMRANK_MAX_NUM_SYMBOLS];
+            if num_counted_symbols > num_symbols {
+                return Err(Error::new(ErrorKind::InvalidData, "invalid symbol ranks"));
+            }
             for i in 0..num_counted_symbols {
                 vs[i] = decoder.decode_raw_bits(9) as u16;
+                if vs[i] as usize >= num_symbols || set[vs[i] as usize] {
+                    return Err(Error::new(ErrorKind::InvalidData, "invalid symbol ranks"));
+                }
                 set[vs[i] as usize] = true;
             }
-            for i in 0..SYMRANK_NUM_SYMBOLS {
+            for i in 0..num_symbols {
                 if !set[i] {
                     vs[num_counted_symbols] = i as u16;
                     num_counted_symbols += 1;
                 }
             }
-            let mut initial_symrank = SymRankCoder::new();
-            initial_symrank.init(&vs);
+            let mut initial_symrank = SymRankCoder::new(self.ctx.num_symbols);
+            initial_symrank.init(&vs[..num_symbols]);
             for symranks in &mut self.ctx.symranks[..] {
                 *symranks = initial_symrank.clone();
             }
@@ -410,19 +804,19 @@ impl LZDecoder {
 
         for _ in 0..match_items_len {
             let symbol = decoder.decode_huffman_sym(&huff1[self.ctx.after_literal as usize]);
-            if !(0..=SYMRANK_NUM_SYMBOLS as u16).contains(&symbol) {
+            if symbol as usize >= num_symbols {
                 return Err(std::io::Error::from(std::io::ErrorKind::InvalidData).into());
             }
 
-            let cur_bucket = &mut self.ctx.buckets[hash1(sbuf, spos - 1)];
+            let cur_bucket = &mut self.ctx.buckets[context(sbuf, spos - 1)];
             let last_word_expected = self.ctx.words[hash2(sbuf, spos - 1)];
             let symrank_context =
-                hash1(sbuf, spos - 1) as u16 | (self.ctx.after_literal as u16) << 8;
+                context(sbuf, spos - 1) as u16 | (self.ctx.after_literal as u16) << 8;
             let symrank = &mut self.ctx.symranks[symrank_context as usize];
             let symrank_unlikely = last_word_expected[0];
 
             match symrank.decode(symbol, symrank_unlikely as u16) {
-                WORD_SYMBOL => {
+                symbol if symbol == word_symbol => {
                     cur_bucket.update(spos, 0, 0);
                     self.ctx.after_literal = false;
                     sbuf.as_mut_ptr().put(spos, last_word_expected);
@@ -435,6 +829,32 @@ impl LZDecoder {
                     spos += 1;
                     self.ctx.words[hash2(sbuf, spos - 3)] = sbuf.as_ptr().get(spos - 2);
                 }
+                symbol if symbol >= rep_symbol => {
+                    let lenid = (symbol - rep_symbol) as u8;
+                    let encoded_match_len = if lenid == LZ_LENID_SIZE as u8 - 1 {
+                        decoder.decode_huffman_sym(&huff2) as usize
+                    } else {
+                        lenid as usize
+                    };
+                    let mut match_len = encoded_match_len + LZ_MATCH_MIN_LEN;
+                    let rep_offset = self.ctx.rep_offset;
+                    if rep_offset == 0 || rep_offset > spos {
+                        return Err(Error::new(
+                            ErrorKind::InvalidData,
+                            "invalid repeated offset",
+                        ));
+                    }
+                    cur_bucket.update(spos, 0, 0);
+                    self.ctx.after_literal = false;
+
+                    if long_matches && match_len == LZ_MATCH_MAX_LEN {
+                        match_len += decode_ext_len(&mut decoder, spos + match_len, sbuf.len())?;
+                    }
+
+                    mem_fast_copy(sbuf.as_mut_ptr(), spos - rep_offset, spos, match_len);
+                    spos += match_len;
+                    self.ctx.words[hash2(sbuf, spos - 3)] = sbuf.as_ptr().get(spos - 2);
+                }
                 encoded_roid_lenid => {
                     let (roid, lenid) = (
                         ((encoded_roid_lenid - 256) / LZ_LENID_SIZE as u16) as u8,
@@ -442,7 +862,7 @@ impl LZDecoder {
                     );
 
                     // get match position and lengths
-                    let (robase, robitlen) = roid_decoding_array[roid as usize];
+                    let (robase, robitlen) = self.roid_decodings[roid as usize];
                     let reduced_offset =
                         robase as usize + decoder.decode_raw_bits(robitlen) as usize;
                     let node = cur_bucket.get_match_node_index(reduced_offset);
@@ -460,7 +880,7 @@ impl LZDecoder {
                     let match_len_expected = match_len_expected
                         .match_len_expected()
                         .max(LZ_MATCH_MIN_LEN);
-                    let match_len = match encoded_match_len {
+                    let mut match_len = match encoded_match_len {
                         l if l + match_len_min > match_len_expected => l + match_len_min,
                         l if l > 0 => l + match_len_min - 1,
                         _ => match_len_expected,
@@ -468,6 +888,11 @@ impl LZDecoder {
                     cur_bucket.update(spos, reduced_offset, match_len);
                     self.ctx.after_literal = false;
 
+                    if long_matches && match_len == LZ_MATCH_MAX_LEN {
+                        match_len += decode_ext_len(&mut decoder, spos + match_len, sbuf.len())?;
+                    }
+
+                    self.ctx.rep_offset = spos - match_pos;
                     mem_fast_copy(sbuf.as_mut_ptr(), match_pos, spos, match_len);
                     spos += match_len;
                     self.ctx.words[hash2(sbuf, spos - 3)] = sbuf.as_ptr().get(spos - 2);
@@ -478,6 +903,36 @@ impl LZDecoder {
     }
 }
 
+// bytes following a match of LZ_MATCH_MAX_LEN which still match, the match
+// must still end before the end of buf
+fn match_ext_len(buf: &[u8], match_pos: usize, pos: usize) -> u16 {
+    let max_len = (buf.len() - pos - 1).min(LZ_MATCH_MAX_LEN + LZ_MATCH_MAX_EXT_LEN);
+    let mut len = LZ_MATCH_MAX_LEN;
+    while len < max_len {
+        let lcp =
+            mem_fast_common_prefix(buf.as_ptr(), match_pos + len, pos + len, LZ_MATCH_MAX_LEN);
+        len += lcp;
+        if lcp < LZ_MATCH_MAX_LEN {
+            break;
+        }
+    }
+    (len.min(max_len) - LZ_MATCH_MAX_LEN) as u16
+}
+
+// reads the extra length of a match of LZ_MATCH_MAX_LEN ending at match_end
+fn decode_ext_len(decoder: &mut Decoder, match_end: usize, buf_len: usize) -> Result<usize> {
+    let invalid = || Error::new(ErrorKind::InvalidData, "invalid match length");
+    let ext_bitlen = decoder.decode_raw_bits(5) as u8;
+    if ext_bitlen > u16::BITS as u8 {
+        return Err(invalid());
+    }
+    let ext_len = decoder.decode_raw_bits(ext_bitlen) as usize;
+    if match_end + ext_len > buf_len {
+        return Err(invalid());
+    }
+    Ok(ext_len)
+}
+
 #[inline]
 fn hash1(buf: &[u8], pos: usize) -> usize {
     // safety: assume buf[pos - 1] is valid
@@ -485,50 +940,164 @@ fn hash1(buf: &[u8], pos: usize) -> usize {
         | (buf.as_ptr().get::<u8>(pos - 1).is_ascii_alphanumeric() as usize) << 7
 }
 
+#[inline]
+fn hash_byte(buf: &[u8], pos: usize) -> usize {
+    buf.as_ptr().get::<u8>(pos) as usize
+}
+
+#[inline]
+fn hash_pair(buf: &[u8], pos: usize) -> usize {
+    // safety: assume buf[pos - 1] is valid
+    let pair = buf.as_ptr().get::<u16>(pos - 1) as u32;
+    (pair.wrapping_mul(0x9e3779b1) >> 24) as usize
+}
+
+#[inline]
+fn hash_column(buf: &[u8], pos: usize, stride: usize) -> usize {
+    // safety: assume buf[pos + 1 - stride] is valid
+    buf.as_ptr().get::<u8>(pos + 1 - stride) as usize
+}
+
 #[inline]
 fn hash2(buf: &[u8], pos: usize) -> usize {
     // safety: assume buf[pos - 1] is valid
     buf.as_ptr().get::<u8>(pos) as usize & 0x7f | hash1(buf, pos - 1) << 7
 }
 
-const LZ_ROID_ENCODING_ARRAY: [(u8, u8, u16); LZ_MF_BUCKET_ITEM_SIZE] = {
-    let mut encs = [(0, 0, 0); LZ_MF_BUCKET_ITEM_SIZE];
+// number of reduced offset classes of a bucket
+fn num_roids(bucket_size: usize) -> usize {
+    (bucket_size / 2 + 1).trailing_zeros() as usize * 2
+}
+
+// class, extra bit length and extra bits of each reduced offset
+fn roid_encodings(bucket_size: usize) -> Vec<(u8, u8, u16)> {
+    (0..num_roids(bucket_size))
+        .flat_map(|roid| {
+            let bit_len = get_extra_bitlen(roid);
+            (0..1 << bit_len).map(move |rest_bits| (roid as u8, bit_len as u8, rest_bits))
+        })
+        .collect()
+}
+
+// base reduced offset and extra bit length of each class
+fn roid_decodings(bucket_size: usize) -> Vec<(u16, u8)> {
     let mut base = 0;
-    let mut current_id = 0;
-    let mut enc_idx = 0;
-
-    while base < LZ_MF_BUCKET_ITEM_SIZE {
-        let bit_len = get_extra_bitlen(current_id);
-        let mut rest_bits = 0;
-        while rest_bits != (1 << bit_len) {
-            if base < LZ_MF_BUCKET_ITEM_SIZE {
-                encs[enc_idx] = (current_id as u8, bit_len as u8, rest_bits as u16);
-                enc_idx += 1;
-                base += 1;
-            }
-            rest_bits += 1;
+    (0..num_roids(bucket_size))
+        .map(|roid| {
+            let bit_len = get_extra_bitlen(roid);
+            let dec = (base as u16, bit_len as u8);
+            base += 1 << bit_len;
+            dec
+        })
+        .collect()
+}
+
+fn get_extra_bitlen(i: usize) -> usize {
+    i / 2
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testutil::random_bytes;
+
+    struct FirstChunk {
+        lzenc: LZEncoder,
+        encoded: Vec<u8>,
+        /// Symbols counted more than once.
+        symbols: Vec<u16>,
+        num_symbols: usize,
+    }
+
+    /// Encodes data as the first chunk of a block.
+    fn encode_first_chunk(cfg: &LZCfg, data: &[u8]) -> FirstChunk {
+        let mut sbuf = vec![0u8; 256];
+        sbuf.extend_from_slice(data);
+        sbuf.resize(sbuf.len() + SBVEC_SENTINEL_LEN, 0);
+        let sbuf_end = sbuf.len() - SBVEC_SENTINEL_LEN;
+        let mut tbuf = vec![0u8; sbuf.len() * 3 + 4096];
+        let mut lzenc = LZEncoder::new(cfg.bucket_size);
+        let (s, t) = lzenc.encode(cfg, ContextHash::Text, &sbuf[..sbuf_end], &mut tbuf, 256);
+        assert_eq!(s, sbuf_end);
+
+        // the first chunk starts with the counted symbols, followed by its end
+        // position and the number of symbols
+        let mut decoder = Decoder::new(&tbuf, 0);
+        let num_counted_symbols = decoder.decode_varint();
+        let symbols = (0..num_counted_symbols)
+            .map(|_| decoder.decode_raw_bits(9) as u16)
+            .collect();
+        decoder.decode_varint();
+        let num_symbols = decoder.decode_varint() as usize;
+        FirstChunk {
+            lzenc,
+            encoded: tbuf[..t].to_vec(),
+            symbols,
+            num_symbols,
         }
-        current_id += 1;
     }
-    encs
-};
 
-const LZ_ROID_DECODING_ARRAY: [(u16, u8); LZ_ROID_SIZE] = {
-    let mut decs = [(0, 0); LZ_ROID_SIZE];
-    let mut base = 0;
-    let mut current_id = 0;
-    let mut dec_idx = 0;
+    #[test]
+    fn test_rep_match_symbols() {
+        // a copy with every 16th byte changed, so every match after the first
+        // one repeats its offset
+        let data = random_bytes(4096);
+        let mut changed = data.clone();
+        changed.iter_mut().step_by(16).for_each(|b| *b ^= 0x55);
+        let chunk = encode_first_chunk(&LZCfg::new(5, 3, 2), &[data, changed].concat());
+        let rep_symbols = chunk.lzenc.ctx.rep_symbol..chunk.lzenc.ctx.word_symbol();
+        assert!(
+            chunk
+                .symbols
+                .iter()
+                .any(|symbol| rep_symbols.contains(symbol))
+        );
+    }
 
-    while base < LZ_MF_BUCKET_ITEM_SIZE {
-        let bit_len = get_extra_bitlen(current_id);
-        decs[dec_idx] = (base as u16, bit_len as u8);
-        dec_idx += 1;
-        current_id += 1;
-        base += 1 << bit_len;
+    #[test]
+    fn test_extended_match_len() {
+        // without extended lengths, the copies take a match every 240 bytes
+        let record = random_bytes(3000);
+        let chunk = encode_first_chunk(&LZCfg::new(5, 3, 2), &record.repeat(300));
+        assert!(chunk.num_symbols < record.len() + 100);
     }
-    decs
-};
 
-const fn get_extra_bitlen(i: usize) -> usize {
-    i / 2
+    #[test]
+    fn test_lazy_match_cfg() {
+        // the defaults are the fixed heuristics used before they were options
+        let cfg = LZCfg::new(5, 3, 2);
+        let default_cfg = cfg
+            .with_good_match_len(240)
+            .with_lazy_match_max_len(120)
+            .with_lazy_match_min_gain(1)
+            .with_lazy_match_cheap_offset_bits(8)
+            .with_lazy_match_lookahead(2);
+        assert_eq!(cfg, default_cfg);
+
+        // the options change the matches chosen
+        let words = [
+            "a ", "an ", "and ", "band ", "bandit ", "it ", "its ", "sit ",
+        ];
+        let input = random_bytes(100000)
+            .iter()
+            .flat_map(|&b| words[b as usize % words.len()].bytes())
+            .collect::<Vec<_>>();
+        let fast_cfg = cfg
+            .with_good_match_len(16)
+            .with_lazy_match_max_len(32)
+            .with_lazy_match_lookahead(1);
+        let no_lazy_cfg = cfg.with_lazy_match_lookahead(0);
+        let encoded = [cfg, fast_cfg, no_lazy_cfg].map(|cfg| {
+            assert!(cfg.validate().is_ok());
+            encode_first_chunk(&cfg, &input).encoded
+        });
+        assert!(encoded[1] != encoded[0]);
+        assert!(encoded[2] != encoded[0]);
+
+        assert!(cfg.with_good_match_len(241).validate().is_err());
+        assert!(cfg.with_lazy_match_min_gain(0).validate().is_err());
+        assert!(cfg.with_lazy_match_max_len(240).validate().is_err());
+        assert!(cfg.with_lazy_match_lookahead(3).validate().is_err());
+        assert!(cfg.with_bucket_size(4096).validate().is_err());
+    }
 }
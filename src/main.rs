@@ -5,69 +5,288 @@
 // except according to those terms.
 
 use std::{
+    collections::HashSet,
     error::Error,
+    ffi::OsStr,
+    fmt::Write as _,
     fs::File,
-    io::{Read, Write, stdin, stdout},
-    path::PathBuf,
+    io::{BufReader, BufWriter, IsTerminal, Read, Write, stdin, stdout},
+    ops::ControlFlow,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{RecvTimeoutError, sync_channel},
+    },
+    time::{Duration, Instant, UNIX_EPOCH},
 };
 
 use clap::Parser;
 use orz::{
-    CountRead, CountWrite, LZCfg, ProgressLogger, SilentProgressLogger, SimpleProgressLogger,
-    decode, encode,
+    ArchiveReader, ArchiveWriter, ChunkProgress, CountRead, CountWrite, EntryKind, FileInfo,
+    Filter, LZCfg, OrzStream, OrzWriter, ProgressLogger, SilentProgressLogger,
+    SimpleProgressLogger, StreamInfo, decode, decode_stream, encode, encode_stream,
+    encode_stream_tar_reordered, read_file_info,
 };
 
-fn main() -> Result<(), Box<dyn Error>> {
-    #[derive(Parser, Debug)]
-    #[command(name = "orz", about = "an optimized ROLZ data compressor")]
-    enum Opt {
-        #[command(name = "encode", about = "Encode")]
-        Encode {
-            #[arg(long = "silent", short = 's')]
-            /// Run silently
-            silent: bool,
-            #[arg(long = "level", short = 'l', default_value = "2")]
-            /// Set compression level (0..2)
-            level: u8,
-            #[arg()]
-            /// Source file name, default to stdin
-            ipath: Option<PathBuf>,
-            #[arg()]
-            /// Target file name, default to stdout
-            opath: Option<PathBuf>,
-        },
+#[derive(Parser, Debug)]
+#[command(
+    name = "orz",
+    about = "an optimized ROLZ data compressor",
+    args_conflicts_with_subcommands = true
+)]
+struct Opt {
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[arg(long = "decode", short = 'd')]
+    /// Decode FILE.orz into FILE instead of encoding
+    decode: bool,
+    #[arg(long = "keep", short = 'k')]
+    /// Keep input files instead of deleting them
+    keep: bool,
+    #[arg(long = "force", short = 'f')]
+    /// Overwrite existing output files and allow writing compressed data to
+    /// a terminal
+    force: bool,
+    #[arg(long = "stdout", short = 'c')]
+    /// Write to stdout and keep input files
+    stdout: bool,
+    #[arg(long = "silent", short = 's')]
+    /// Run silently
+    silent: bool,
+    #[arg(long = "level", short = 'l', default_value = "2")]
+    /// Set compression level (0..2)
+    level: u8,
+    #[arg()]
+    /// Files to encode into FILE.orz (or decode with -d), default to stdin
+    files: Vec<PathBuf>,
+}
+
+// default limit of concurrent jobs of encode -r, as every job takes a window
+// and a match finder
+const MAX_DEFAULT_JOBS: usize = 4;
 
-        #[command(name = "decode", about = "Decode")]
-        Decode {
-            #[arg(long = "silent", short = 's')]
-            /// Run silently
-            silent: bool,
-            #[arg()]
-            /// Source file name, default to stdin
-            ipath: Option<PathBuf>,
-            #[arg()]
-            /// Target file name, default to stdout
-            opath: Option<PathBuf>,
+#[derive(Clone, Copy, Debug)]
+enum FilterMode {
+    None,
+    Fixed(Filter),
+}
+
+impl FilterMode {
+    fn filter(self) -> Option<Filter> {
+        match self {
+            FilterMode::None => None,
+            FilterMode::Fixed(filter) => Some(filter),
+        }
+    }
+}
+
+fn parse_flush_interval(s: &str) -> Result<Duration, String> {
+    let secs = s.parse::<f64>().map_err(|e| e.to_string())?;
+    match Duration::try_from_secs_f64(secs) {
+        Ok(interval) if !interval.is_zero() => Ok(interval),
+        _ => Err(format!("not a positive interval: {}", s)),
+    }
+}
+
+fn parse_filter_mode(s: &str) -> Result<FilterMode, String> {
+    Ok(match s {
+        "auto" => FilterMode::Fixed(Filter::Auto),
+        "none" => FilterMode::None,
+        "x86" => FilterMode::Fixed(Filter::X86),
+        "arm64" => FilterMode::Fixed(Filter::Arm64),
+        "text" => FilterMode::Fixed(Filter::Text),
+        "delta" => FilterMode::Fixed(Filter::Delta(None)),
+        _ => match s.strip_prefix("delta:").and_then(|s| s.parse::<u8>().ok()) {
+            Some(stride @ 1..) => FilterMode::Fixed(Filter::Delta(Some(stride))),
+            _ => return Err(format!("invalid filter: {}", s)),
         },
+    })
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Command {
+    #[command(name = "encode", about = "Encode")]
+    Encode {
+        #[arg(long = "silent", short = 's')]
+        /// Run silently
+        silent: bool,
+        #[arg(long = "level", short = 'l', default_value = "2")]
+        /// Set compression level (0..2)
+        level: u8,
+        #[arg(
+            long = "flush-interval",
+            value_name = "SECONDS",
+            value_parser = parse_flush_interval
+        )]
+        /// Flush encoded data periodically when reading from stdin
+        flush_interval: Option<Duration>,
+        #[arg(long = "reorder-tar", conflicts_with = "flush_interval")]
+        /// Group similar members together if the source is a tar archive,
+        /// buffering the whole source in memory
+        reorder_tar: bool,
+        #[arg(
+            long = "filter",
+            value_name = "FILTER",
+            default_value = "auto",
+            value_parser = parse_filter_mode,
+            conflicts_with = "flush_interval"
+        )]
+        /// Preprocess the source with a filter: auto (x86, text, delta or none
+        /// chosen for every 1MB block from its contents), none, x86, arm64,
+        /// text, delta (stride detected per block) or delta:N
+        filter: FilterMode,
+        #[arg(
+            long = "recursive",
+            short = 'r',
+            requires = "ipath",
+            conflicts_with_all = ["opath", "flush_interval", "reorder_tar"]
+        )]
+        /// Encode every file under the source directory into a sibling
+        /// FILE.orz, keeping the input files
+        recursive: bool,
+        #[arg(long = "jobs", short = 'j', requires = "recursive")]
+        /// Number of files encoded concurrently with -r, default to the
+        /// number of CPUs up to 4. Every job takes about 110MB of memory at
+        /// level 2
+        jobs: Option<usize>,
+        #[arg(long = "force", short = 'f', requires = "recursive")]
+        /// Overwrite existing output files with -r
+        force: bool,
+        #[arg(
+            long = "stats-json",
+            value_name = "FD",
+            conflicts_with_all = ["flush_interval", "recursive"]
+        )]
+        /// Write statistics as JSON to the given file descriptor when done
+        stats_json: Option<i32>,
+        #[arg()]
+        /// Source file name, default to stdin
+        ipath: Option<PathBuf>,
+        #[arg()]
+        /// Target file name, default to stdout
+        opath: Option<PathBuf>,
+    },
+
+    #[command(name = "decode", about = "Decode")]
+    Decode {
+        #[arg(long = "silent", short = 's')]
+        /// Run silently
+        silent: bool,
+        #[arg(long = "strict")]
+        /// Fail on trailing garbage after the last frame instead of
+        /// ignoring it
+        strict: bool,
+        #[arg(long = "stats-json", value_name = "FD")]
+        /// Write statistics as JSON to the given file descriptor when done
+        stats_json: Option<i32>,
+        #[arg()]
+        /// Source file name, default to stdin
+        ipath: Option<PathBuf>,
+        #[arg()]
+        /// Target file name, default to the original file name stored
+        /// in the source file, or stdout
+        opath: Option<PathBuf>,
+    },
+
+    #[command(name = "test", about = "Test integrity of compressed files")]
+    Test {
+        #[arg(long = "verbose", short = 'v')]
+        /// Print OK for every intact file
+        verbose: bool,
+        #[arg()]
+        /// Compressed files to test, default to stdin
+        files: Vec<PathBuf>,
+    },
+
+    #[command(name = "info", about = "Show the layout of compressed files")]
+    Info {
+        #[arg(long = "json")]
+        /// Print a JSON array with an object for every file
+        json: bool,
+        #[arg()]
+        /// Compressed files to inspect, default to stdin
+        files: Vec<PathBuf>,
+    },
+
+    #[command(name = "pack", about = "Pack files and directories into an archive")]
+    Pack {
+        #[arg(long = "level", short = 'l', default_value = "2")]
+        /// Set compression level (0..2)
+        level: u8,
+        #[arg(long = "force", short = 'f')]
+        /// Overwrite an existing archive
+        force: bool,
+        #[arg()]
+        /// Archive file name
+        archive: PathBuf,
+        #[arg(required = true)]
+        /// Files and directories to pack
+        paths: Vec<PathBuf>,
+    },
+
+    #[command(name = "unpack", about = "Extract files from an archive")]
+    Unpack {
+        #[arg(long = "directory", short = 'C', default_value = ".")]
+        /// Extract into this directory
+        dir: PathBuf,
+        #[arg(long = "force", short = 'f')]
+        /// Overwrite existing files
+        force: bool,
+        #[arg()]
+        /// Archive file name
+        archive: PathBuf,
+        #[arg()]
+        /// Entries to extract (with their contents for directories), default
+        /// to all
+        entries: Vec<String>,
+    },
+
+    #[command(name = "list", about = "List the entries of an archive")]
+    List {
+        #[arg()]
+        /// Archive file name
+        archive: PathBuf,
+    },
+}
+
+impl Command {
+    fn is_silent(&self) -> bool {
+        match self {
+            Command::Encode { silent, .. } => *silent,
+            Command::Decode { silent, .. } => *silent,
+            _ => true,
+        }
     }
 
-    impl Opt {
-        fn is_silent(&self) -> bool {
-            match self {
-                Opt::Encode { silent, .. } => *silent,
-                Opt::Decode { silent, .. } => *silent,
-            }
+    fn ipath(&self) -> Option<&Path> {
+        match self {
+            Command::Encode { ipath, .. } => ipath.as_deref(),
+            Command::Decode { ipath, .. } => ipath.as_deref(),
+            _ => None,
         }
     }
 
+    fn stats_fd(&self) -> Option<i32> {
+        match self {
+            Command::Encode { stats_json, .. } => *stats_json,
+            Command::Decode { stats_json, .. } => *stats_json,
+            _ => None,
+        }
+    }
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
     let args = Opt::parse();
+    let Some(command) = &args.command else {
+        return process_files(&args);
+    };
 
     // init progress logger
-    let mut progress_logger: Box<dyn ProgressLogger> = if args.is_silent() {
-        Box::new(SilentProgressLogger)
-    } else {
-        Box::new(SimpleProgressLogger::new())
-    };
+    let mut progress_logger = new_progress_logger(command.is_silent(), command.ipath());
+    if let Some(fd) = command.stats_fd() {
+        check_fd_writable(fd)?;
+        progress_logger = Box::new(StatsLogger::new(progress_logger, fd));
+    }
 
     // init input/output
     let get_ifile = |ipath| {
@@ -84,34 +303,918 @@ fn main() -> Result<(), Box<dyn Error>> {
     };
 
     // encode/decode
-    match &args {
-        Opt::Encode {
+    match command {
+        Command::Encode {
             level,
+            flush_interval: Some(flush_interval),
+            ipath: None,
+            opath,
+            ..
+        } => {
+            let cfg = LZCfg::from_level(*level).ok_or(format!("invalid level: {}", level))?;
+            encode_with_flush_interval(
+                CountWrite::new(get_ofile(opath.as_deref())?),
+                &cfg,
+                *flush_interval,
+                &mut progress_logger,
+            )
+            .map_err(|e| format!("encoding failed: {}", e))?;
+        }
+        Command::Encode {
+            flush_interval: Some(_),
+            ..
+        } => return Err("--flush-interval requires reading from stdin".into()),
+        Command::Encode {
+            silent,
+            level,
+            recursive: true,
+            jobs,
+            force,
+            filter,
+            ipath: Some(dir),
+            ..
+        } => {
+            let cfg = LZCfg::from_level(*level).ok_or(format!("invalid level: {}", level))?;
+            let jobs = match jobs {
+                Some(0) => return Err("invalid number of jobs: 0".into()),
+                Some(jobs) => *jobs,
+                None => std::thread::available_parallelism()
+                    .map_or(1, |n| n.get().min(MAX_DEFAULT_JOBS)),
+            };
+            encode_tree(dir, &cfg, *filter, jobs, *force, *silent)?;
+        }
+        Command::Encode {
+            level,
+            reorder_tar,
+            filter,
             ipath,
             opath,
             ..
         } => {
-            encode(
-                &mut CountRead::new(get_ifile(ipath.as_deref())?),
+            let cfg = LZCfg::from_level(*level).ok_or(format!("invalid level: {}", level))?;
+            let mut stream = OrzStream::encoder(&cfg)?;
+            if let Some(ipath) = ipath {
+                stream.set_file_info(get_file_info(ipath)?)?;
+            }
+            if let Some(filter) = filter.filter() {
+                stream.set_filter(filter)?;
+            }
+            let source = get_ifile(ipath.as_deref())?;
+            let encode_stream = match reorder_tar {
+                true => encode_stream_tar_reordered,
+                false => encode_stream,
+            };
+            encode_stream(
+                &mut stream,
+                &mut CountRead::new(source),
                 &mut CountWrite::new(get_ofile(opath.as_deref())?),
-                &match level {
-                    0 => LZCfg::new(5, 3, 2),
-                    1 => LZCfg::new(15, 9, 6),
-                    2 => LZCfg::new(45, 27, 18),
-                    _ => return Err(format!("invalid level: {}", level).into()),
-                },
                 &mut progress_logger,
             )
             .map_err(|e| format!("encoding failed: {}", e))?;
         }
-        Opt::Decode { ipath, opath, .. } => {
-            decode(
+        Command::Decode {
+            strict,
+            ipath,
+            opath,
+            ..
+        } => {
+            let mut stream = OrzStream::decoder();
+            stream.set_reject_trailing_garbage(*strict)?;
+
+            // restore the original file like gzip -N if no target is given
+            let restore = match (ipath, opath) {
+                (Some(ipath), None) => read_file_info(&mut File::open(ipath)?)
+                    .map_err(|e| format!("decoding failed: {}", e))?
+                    .and_then(|info| {
+                        let name = original_file_name(&info)?;
+                        Some((ipath.with_file_name(name), info))
+                    }),
+                _ => None,
+            };
+            if let Some((opath, info)) = restore {
+                let mut ofile = File::create_new(&opath)
+                    .map_err(|e| format!("cannot create {}: {}", opath.display(), e))?;
+                decode_stream(
+                    &mut stream,
+                    &mut CountRead::new(get_ifile(ipath.as_deref())?),
+                    &mut CountWrite::new(&mut ofile),
+                    &mut progress_logger,
+                )
+                .map_err(|e| format!("decoding failed: {}", e))?;
+                restore_file_attributes(&ofile, &info)?;
+                return Ok(());
+            }
+            decode_stream(
+                &mut stream,
                 &mut CountRead::new(get_ifile(ipath.as_deref())?),
                 &mut CountWrite::new(get_ofile(opath.as_deref())?),
                 &mut progress_logger,
             )
             .map_err(|e| format!("decoding failed: {}", e))?;
         }
+        Command::Test { verbose, files } => test_files(files, *verbose)?,
+        Command::Info { json, files } => info_files(files, *json)?,
+        Command::Pack {
+            level,
+            force,
+            archive,
+            paths,
+        } => {
+            let cfg = LZCfg::from_level(*level).ok_or(format!("invalid level: {}", level))?;
+            let file = if *force {
+                File::create(archive)
+            } else {
+                File::create_new(archive)
+            };
+            let file = file.map_err(|e| format!("cannot create {}: {}", archive.display(), e))?;
+            let result = ArchiveWriter::new(BufWriter::new(file), &cfg).and_then(|mut writer| {
+                paths.iter().try_for_each(|path| writer.add_path(path))?;
+                writer.finish()?.flush()
+            });
+            if let Err(e) = result {
+                let _ = std::fs::remove_file(archive);
+                return Err(format!("packing failed: {}", e).into());
+            }
+        }
+        Command::Unpack {
+            dir,
+            force,
+            archive,
+            entries,
+        } => {
+            let mut reader = ArchiveReader::new(BufReader::new(File::open(archive)?))?;
+            let mut unmatched = entries.iter().collect::<HashSet<_>>();
+            reader
+                .unpack(dir, *force, |entry| {
+                    if entries.is_empty() {
+                        return true;
+                    }
+                    let mut selected = false;
+                    for path in entries.iter().filter(|path| entry.is_under(path)) {
+                        unmatched.remove(path);
+                        selected = true;
+                    }
+                    selected
+                })
+                .map_err(|e| format!("unpacking failed: {}", e))?;
+            if let Some(path) = unmatched.into_iter().next() {
+                return Err(format!("{}: not found in archive", path).into());
+            }
+        }
+        Command::List { archive } => {
+            let mut reader = ArchiveReader::new(BufReader::new(File::open(archive)?))?;
+            while let Some(entry) = reader.next_entry()? {
+                let (kind, size) = match &entry.kind {
+                    EntryKind::File { size } => ('-', *size),
+                    EntryKind::Dir => ('d', 0),
+                    EntryKind::Symlink { .. } => ('l', 0),
+                };
+                let mode = (0..9)
+                    .map(|i| match entry.mode >> (8 - i) & 1 {
+                        0 => '-',
+                        _ => ['r', 'w', 'x'][i % 3],
+                    })
+                    .collect::<String>();
+                match &entry.kind {
+                    EntryKind::Symlink { target } => {
+                        println!("{}{} {:>12} {} -> {}", kind, mode, size, entry.path, target)
+                    }
+                    _ => println!("{}{} {:>12} {}", kind, mode, size, entry.path),
+                }
+            }
+        }
+    };
+    Ok(())
+}
+
+fn new_progress_logger(silent: bool, ipath: Option<&Path>) -> Box<dyn ProgressLogger> {
+    if silent {
+        return Box::new(SilentProgressLogger);
+    }
+    let mut progress_logger = SimpleProgressLogger::new();
+    let metadata = ipath.and_then(|ipath| std::fs::metadata(ipath).ok());
+    if let Some(metadata) = metadata.filter(|metadata| metadata.is_file()) {
+        progress_logger.set_total_input_bytes(metadata.len() as usize);
+    }
+    Box::new(progress_logger)
+}
+
+/// Wraps a progress logger, collecting the numbers of every block and writing
+/// them as JSON to a file descriptor when finished.
+struct StatsLogger {
+    inner: Box<dyn ProgressLogger>,
+    fd: i32,
+    is_encode: bool,
+    start_time: Instant,
+    num_chunks: usize,
+    // bytes of the chunks after filtering, or before unfiltering when decoding
+    num_filtered_bytes: usize,
+    // cumulative [input bytes, output bytes, chunks] and time at the last block boundary
+    last: ([usize; 3], Instant),
+    blocks: Vec<([usize; 3], Duration)>,
+}
+
+impl StatsLogger {
+    fn new(inner: Box<dyn ProgressLogger>, fd: i32) -> Self {
+        Self {
+            inner,
+            fd,
+            is_encode: true,
+            start_time: Instant::now(),
+            num_chunks: 0,
+            num_filtered_bytes: 0,
+            last: ([0; 3], Instant::now()),
+            blocks: vec![],
+        }
+    }
+
+    fn add_block(&mut self, num_input_bytes: usize, num_output_bytes: usize) {
+        let (last, last_time) = self.last;
+        let totals = [num_input_bytes, num_output_bytes, self.num_chunks];
+        let now = Instant::now();
+        self.blocks.push((
+            std::array::from_fn(|i| totals[i] - last[i]),
+            now - last_time,
+        ));
+        self.last = (totals, now);
+    }
+
+    fn stats_json(&self, num_input_bytes: usize, num_output_bytes: usize) -> String {
+        let (encoded_len, decoded_len) = match self.is_encode {
+            true => (num_output_bytes, num_input_bytes),
+            false => (num_input_bytes, num_output_bytes),
+        };
+        let ratio = match decoded_len {
+            0 => "null".to_owned(),
+            _ => format!("{:.4}", encoded_len as f64 / decoded_len as f64),
+        };
+        let (cpu_time, peak_memory) = resource_usage().unzip();
+        let blocks = self.blocks.iter().map(|([input, output, chunks], time)| {
+            format!(
+                r#"{{"input_bytes":{},"output_bytes":{},"num_chunks":{},"wall_time":{:.6}}}"#,
+                input,
+                output,
+                chunks,
+                time.as_secs_f64(),
+            )
+        });
+        format!(
+            r#"{{"mode":{},"input_bytes":{},"output_bytes":{},"filtered_bytes":{},"ratio":{},"wall_time":{:.6},"cpu_time":{},"peak_memory":{},"num_chunks":{},"blocks":[{}]}}"#,
+            json_string(if self.is_encode { "encode" } else { "decode" }),
+            num_input_bytes,
+            num_output_bytes,
+            self.num_filtered_bytes,
+            ratio,
+            self.start_time.elapsed().as_secs_f64(),
+            json_option(cpu_time.map(|t: Duration| format!("{:.6}", t.as_secs_f64()))),
+            json_option(peak_memory),
+            self.num_chunks,
+            blocks.collect::<Vec<_>>().join(","),
+        )
+    }
+}
+
+impl ProgressLogger for StatsLogger {
+    fn set_is_encode(&mut self, is_encode: bool) {
+        self.is_encode = is_encode;
+        self.inner.set_is_encode(is_encode);
+    }
+
+    fn log(&mut self, num_input_bytes: usize, num_output_bytes: usize) {
+        self.add_block(num_input_bytes, num_output_bytes);
+        self.inner.log(num_input_bytes, num_output_bytes);
+    }
+
+    fn chunk(&mut self, progress: ChunkProgress) -> ControlFlow<()> {
+        self.num_chunks = progress.index + 1;
+        self.num_filtered_bytes = progress.num_filtered_bytes;
+        self.inner.chunk(progress)
+    }
+
+    fn finish(&mut self, num_input_bytes: usize, num_output_bytes: usize) {
+        if self.num_chunks > self.last.0[2] {
+            self.add_block(num_input_bytes, num_output_bytes); // the last partial block
+        }
+        self.inner.finish(num_input_bytes, num_output_bytes);
+        let json = self.stats_json(num_input_bytes, num_output_bytes) + "\n";
+        if let Err(e) = write_to_fd(self.fd, json.as_bytes()) {
+            eprintln!("orz: cannot write statistics: {}", e);
+        }
+    }
+}
+
+/// CPU time and peak resident memory of the process.
+#[cfg(unix)]
+fn resource_usage() -> Option<(Duration, u64)> {
+    let mut usage = std::mem::MaybeUninit::<libc::rusage>::uninit();
+    if unsafe { libc::getrusage(libc::RUSAGE_SELF, usage.as_mut_ptr()) } != 0 {
+        return None;
+    }
+    let usage = unsafe { usage.assume_init() };
+    let timeval = |t: libc::timeval| Duration::new(t.tv_sec as u64, t.tv_usec as u32 * 1000);
+    let cpu_time = timeval(usage.ru_utime) + timeval(usage.ru_stime);
+
+    // ru_maxrss is in bytes on macOS and in kilobytes elsewhere
+    let peak_memory = match cfg!(target_os = "macos") {
+        true => usage.ru_maxrss as u64,
+        false => usage.ru_maxrss as u64 * 1024,
     };
+    Some((cpu_time, peak_memory))
+}
+
+#[cfg(not(unix))]
+fn resource_usage() -> Option<(Duration, u64)> {
+    None
+}
+
+#[cfg(unix)]
+fn check_fd_writable(fd: i32) -> Result<(), Box<dyn Error>> {
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags == -1 || flags & libc::O_ACCMODE == libc::O_RDONLY {
+        return Err(format!("file descriptor {} is not open for writing", fd).into());
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn check_fd_writable(fd: i32) -> Result<(), Box<dyn Error>> {
+    match fd {
+        1 | 2 => Ok(()),
+        _ => Err(format!("unsupported file descriptor: {}", fd).into()),
+    }
+}
+
+#[cfg(unix)]
+fn write_to_fd(fd: i32, buf: &[u8]) -> std::io::Result<()> {
+    use std::os::fd::FromRawFd;
+
+    // the descriptor is owned by the parent process, so it is never closed
+    let mut file = std::mem::ManuallyDrop::new(unsafe { File::from_raw_fd(fd) });
+    file.write_all(buf)
+}
+
+#[cfg(not(unix))]
+fn write_to_fd(fd: i32, buf: &[u8]) -> std::io::Result<()> {
+    match fd {
+        1 => stdout().write_all(buf),
+        _ => std::io::stderr().write_all(buf),
+    }
+}
+
+/// Encodes or decodes files in place like gzip, or stdin to stdout if no
+/// files are given.
+fn process_files(opt: &Opt) -> Result<(), Box<dyn Error>> {
+    let cfg = LZCfg::from_level(opt.level).ok_or(format!("invalid level: {}", opt.level))?;
+    if opt.files.is_empty() {
+        let mut progress_logger = new_progress_logger(opt.silent, None);
+        let mut source = CountRead::new(stdin());
+        let mut target = CountWrite::new(stdout());
+        if opt.decode {
+            if stdin().is_terminal() && !opt.force {
+                return Err("refusing to read compressed data from a terminal".into());
+            }
+            decode(&mut source, &mut target, &mut progress_logger)
+                .map_err(|e| format!("decoding failed: {}", e))?;
+        } else {
+            check_stdout_not_terminal(opt.force)?;
+            encode(&mut source, &mut target, &cfg, &mut progress_logger)
+                .map_err(|e| format!("encoding failed: {}", e))?;
+        }
+        return Ok(());
+    }
+
+    // like gzip, a failed file is reported and the remaining files are processed
+    let mut num_failed = 0;
+    for path in &opt.files {
+        let result = if opt.decode {
+            decode_file(opt, path)
+        } else {
+            encode_file(opt, &cfg, path)
+        };
+        if let Err(e) = result {
+            eprintln!("orz: {}: {}", path.display(), e);
+            num_failed += 1;
+        }
+    }
+    if num_failed > 0 {
+        return Err(format!("{} of {} files failed", num_failed, opt.files.len()).into());
+    }
+    Ok(())
+}
+
+/// Fully decodes files into a discarding sink, checking framing and chunk
+/// checksums without writing any output.
+fn test_files(paths: &[PathBuf], verbose: bool) -> Result<(), Box<dyn Error>> {
+    let test = |source: &mut dyn Read| {
+        let mut stream = OrzStream::decoder();
+        stream.set_reject_trailing_garbage(true)?;
+        decode_stream(
+            &mut stream,
+            &mut CountRead::new(source),
+            &mut CountWrite::new(std::io::sink()),
+            &mut new_progress_logger(true, None),
+        )
+    };
+    if paths.is_empty() {
+        test(&mut stdin()).map_err(|e| format!("stdin: {}", e))?;
+        return Ok(());
+    }
+
+    let mut num_failed = 0;
+    for path in paths {
+        match File::open(path).and_then(|mut file| test(&mut file)) {
+            Ok(()) if verbose => println!("{}: OK", path.display()),
+            Ok(()) => {}
+            Err(e) => {
+                eprintln!("orz: {}: {}", path.display(), e);
+                num_failed += 1;
+            }
+        }
+    }
+    if num_failed > 0 {
+        return Err(format!("{} of {} files failed", num_failed, paths.len()).into());
+    }
+    Ok(())
+}
+
+/// Encodes FILE into FILE.orz, or to stdout with -c.
+fn encode_file(opt: &Opt, cfg: &LZCfg, path: &Path) -> Result<(), Box<dyn Error>> {
+    if path.extension() == Some(OsStr::new("orz")) {
+        return Err("already has .orz suffix, ignored".into());
+    }
+    if !std::fs::metadata(path)?.is_file() {
+        return Err("not a regular file, ignored".into());
+    }
+    let mut progress_logger = new_progress_logger(opt.silent, Some(path));
+    let mut stream = OrzStream::encoder(cfg)?;
+    stream.set_file_info(get_file_info(path)?)?;
+    stream.set_filter(Filter::Auto)?;
+    let mut source = CountRead::new(File::open(path)?);
+    let mut encode = |target: &mut CountWrite<&mut dyn Write>| {
+        encode_stream(&mut stream, &mut source, target, &mut progress_logger)
+            .map_err(|e| format!("encoding failed: {}", e))
+    };
+
+    if opt.stdout {
+        check_stdout_not_terminal(opt.force)?;
+        encode(&mut CountWrite::new(&mut stdout()))?;
+        return Ok(());
+    }
+    let mut opath = path.as_os_str().to_owned();
+    opath.push(".orz");
+    create_output(opt.force, Path::new(&opath), encode)?;
+    if !opt.keep {
+        std::fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+/// Encodes every regular file under a directory into a sibling FILE.orz on a
+/// pool of workers, each reusing one encoding stream across files.
+fn encode_tree(
+    dir: &Path,
+    cfg: &LZCfg,
+    filter: FilterMode,
+    jobs: usize,
+    force: bool,
+    silent: bool,
+) -> Result<(), Box<dyn Error>> {
+    let mut paths = vec![];
+    collect_files(dir, &mut paths).map_err(|e| format!("{}: {}", dir.display(), e))?;
+
+    let next = AtomicUsize::new(0);
+    let encode_files = || {
+        let mut stream = OrzStream::encoder(cfg)?;
+        let mut totals = [0u64; 3]; // [num_failed, total_in, total_out]
+        while let Some(path) = paths.get(next.fetch_add(1, Ordering::Relaxed)) {
+            stream.reset();
+            match encode_tree_file(&mut stream, path, filter, force) {
+                Ok(()) => {
+                    let (total_in, total_out) = (stream.total_in(), stream.total_out());
+                    if !silent {
+                        println!(
+                            "{}: {} bytes => {} bytes",
+                            path.display(),
+                            total_in,
+                            total_out
+                        );
+                    }
+                    totals[1] += total_in as u64;
+                    totals[2] += total_out as u64;
+                }
+                Err(e) => {
+                    eprintln!("orz: {}: {}", path.display(), e);
+                    totals[0] += 1;
+                }
+            }
+        }
+        std::io::Result::Ok(totals)
+    };
+    let [num_failed, total_in, total_out] = std::thread::scope(|s| {
+        let workers = (0..jobs.min(paths.len()))
+            .map(|_| s.spawn(encode_files))
+            .collect::<Vec<_>>();
+        workers.into_iter().try_fold([0u64; 3], |totals, worker| {
+            let worker_totals = worker.join().unwrap()?;
+            Ok::<_, std::io::Error>(std::array::from_fn(|i| totals[i] + worker_totals[i]))
+        })
+    })?;
+
+    if !silent {
+        let num_encoded = paths.len() - num_failed as usize;
+        print!(
+            "{} files: {} bytes => {} bytes",
+            num_encoded, total_in, total_out
+        );
+        if total_in > 0 {
+            print!(", {:.2}%", total_out as f64 * 100.0 / total_in as f64);
+        }
+        println!();
+    }
+    if num_failed > 0 {
+        return Err(format!("{} of {} files failed", num_failed, paths.len()).into());
+    }
+    Ok(())
+}
+
+/// Collects regular files under a directory in sorted order, skipping
+/// symlinks and files with .orz suffix.
+fn collect_files(dir: &Path, paths: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    let mut entries = std::fs::read_dir(dir)?.collect::<std::io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let file_type = entry.file_type()?;
+        let path = entry.path();
+        if file_type.is_dir() {
+            collect_files(&path, paths)?;
+        } else if file_type.is_file() && path.extension() != Some(OsStr::new("orz")) {
+            paths.push(path);
+        }
+    }
+    Ok(())
+}
+
+fn encode_tree_file(
+    stream: &mut OrzStream,
+    path: &Path,
+    filter: FilterMode,
+    force: bool,
+) -> Result<(), Box<dyn Error>> {
+    stream.set_file_info(get_file_info(path)?)?;
+    if let Some(filter) = filter.filter() {
+        stream.set_filter(filter)?;
+    }
+    let mut source = CountRead::new(File::open(path)?);
+    let mut opath = path.as_os_str().to_owned();
+    opath.push(".orz");
+    create_output(force, Path::new(&opath), |target| {
+        encode_stream(
+            stream,
+            &mut source,
+            target,
+            &mut new_progress_logger(true, None),
+        )
+        .map_err(|e| format!("encoding failed: {}", e))
+    })?;
+    Ok(())
+}
+
+/// Decodes FILE.orz into FILE, or to stdout with -c.
+fn decode_file(opt: &Opt, path: &Path) -> Result<(), Box<dyn Error>> {
+    if path.extension() != Some(OsStr::new("orz")) {
+        return Err("unknown suffix, ignored".into());
+    }
+    let mut progress_logger = new_progress_logger(opt.silent, Some(path));
+    let mut stream = OrzStream::decoder();
+    let mut source = CountRead::new(File::open(path)?);
+    let mut decode = |target: &mut CountWrite<&mut dyn Write>| {
+        decode_stream(&mut stream, &mut source, target, &mut progress_logger)
+            .map_err(|e| format!("decoding failed: {}", e))
+    };
+
+    if opt.stdout {
+        decode(&mut CountWrite::new(&mut stdout()))?;
+        return Ok(());
+    }
+    let ofile = create_output(opt.force, &path.with_extension(""), decode)?;
+    if let Some(info) = stream.file_info() {
+        restore_file_attributes(&ofile, info)?;
+    }
+    if !opt.keep {
+        std::fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+/// Creates an output file and writes it, the incomplete file is removed if
+/// writing fails.
+fn create_output(
+    force: bool,
+    path: &Path,
+    write: impl FnOnce(&mut CountWrite<&mut dyn Write>) -> Result<(), String>,
+) -> Result<File, Box<dyn Error>> {
+    let file = if force {
+        File::create(path)
+    } else {
+        File::create_new(path)
+    };
+    let mut file = file.map_err(|e| format!("cannot create {}: {}", path.display(), e))?;
+    if let Err(e) = write(&mut CountWrite::new(&mut file)) {
+        drop(file);
+        let _ = std::fs::remove_file(path);
+        return Err(e.into());
+    }
+    Ok(file)
+}
+
+fn check_stdout_not_terminal(force: bool) -> Result<(), Box<dyn Error>> {
+    if stdout().is_terminal() && !force {
+        return Err("refusing to write compressed data to a terminal, use -f to force".into());
+    }
+    Ok(())
+}
+
+/// Prints the frame/chunk layout of files without decoding them.
+fn info_files(paths: &[PathBuf], json: bool) -> Result<(), Box<dyn Error>> {
+    let mut infos = vec![];
+    let mut num_failed = 0;
+    if paths.is_empty() {
+        infos.push(("-".to_owned(), StreamInfo::read(stdin().lock())?));
+    }
+    for path in paths {
+        match File::open(path).and_then(StreamInfo::read) {
+            Ok(info) => infos.push((path.display().to_string(), info)),
+            Err(e) => {
+                eprintln!("orz: {}: {}", path.display(), e);
+                num_failed += 1;
+            }
+        }
+    }
+
+    if json {
+        let infos = infos
+            .iter()
+            .map(|(name, info)| stream_info_json(name, info))
+            .collect::<Vec<_>>();
+        println!("[{}]", infos.join(","));
+    } else {
+        infos
+            .iter()
+            .for_each(|(name, info)| print_stream_info(name, info));
+    }
+    if num_failed > 0 {
+        return Err(format!("{} of {} files failed", num_failed, paths.len()).into());
+    }
+    Ok(())
+}
+
+fn print_stream_info(name: &str, info: &StreamInfo) {
+    let (encoded_len, decoded_len) = (info.encoded_len(), info.decoded_len());
+    println!("{}:", name);
+    println!("  frames:            {}", info.frames.len());
+    println!("  compressed size:   {} bytes", encoded_len);
+    println!("  uncompressed size: {} bytes", decoded_len);
+    if decoded_len > 0 {
+        let ratio = encoded_len as f64 * 100.0 / decoded_len as f64;
+        println!("  ratio:             {:.2}%", ratio);
+    }
+
+    for (i, frame) in info.frames.iter().enumerate() {
+        println!("  frame {}:", i);
+        println!("    format version:  {}", frame.version);
+        if let Some(lz_cfg) = &frame.lz_cfg {
+            let level = lz_cfg.level().map(|level| level.to_string());
+            println!(
+                "    level:           {} (match_depth={}, lazy_match_depth1={}, lazy_match_depth2={}, bucket_size={})",
+                level.as_deref().unwrap_or("custom"),
+                lz_cfg.match_depth,
+                lz_cfg.lazy_match_depth1,
+                lz_cfg.lazy_match_depth2,
+                lz_cfg.bucket_size,
+            );
+            println!(
+                "    matching:        good_match_len={}, lazy_match_max_len={}, lazy_match_min_gain={}, lazy_match_cheap_offset_bits={}, lazy_match_lookahead={}",
+                lz_cfg.good_match_len,
+                lz_cfg.lazy_match_max_len,
+                lz_cfg.lazy_match_min_gain,
+                lz_cfg.lazy_match_cheap_offset_bits,
+                lz_cfg.lazy_match_lookahead,
+            );
+        }
+        let checksum = if frame.chunk_checksum {
+            "crc32"
+        } else {
+            "none"
+        };
+        println!("    checksum:        {}", checksum);
+        if let Some(num_segments) = frame.reordered_segments {
+            println!("    reordered:       {} segments", num_segments);
+        }
+        if let Some(filter) = frame.filter {
+            println!("    filter:          {}", filter);
+        }
+        if let Some(name) = frame.file_info.as_ref().and_then(|i| i.name.as_ref()) {
+            println!("    original name:   {}", name);
+        }
+        println!("    chunks:          {}", frame.num_chunks);
+        println!(
+            "    size:            {} bytes => {} bytes",
+            frame.decoded_len(),
+            frame.encoded_len
+        );
+        for (j, block) in frame.blocks.iter().enumerate() {
+            let context = block.context.map_or("-".to_owned(), |c| c.to_string());
+            println!(
+                "    block {}:         {} bytes => {} bytes, {} chunks ({} stored, {} filled), context {}",
+                j,
+                block.decoded_len,
+                block.encoded_len,
+                block.num_chunks,
+                block.num_stored_chunks,
+                block.num_filled_chunks,
+                context,
+            );
+        }
+    }
+    for metadata in &info.metadata {
+        println!(
+            "  metadata:          tag {}, {} bytes",
+            metadata.tag, metadata.len
+        );
+    }
+    if info.trailing_len > 0 {
+        println!("  trailing garbage:  {} bytes", info.trailing_len);
+    }
+}
+
+fn stream_info_json(name: &str, info: &StreamInfo) -> String {
+    let (encoded_len, decoded_len) = (info.encoded_len(), info.decoded_len());
+    let ratio = match decoded_len {
+        0 => "null".to_owned(),
+        _ => format!("{:.4}", encoded_len as f64 / decoded_len as f64),
+    };
+    let frames = info.frames.iter().map(|frame| {
+        let lz_cfg = frame.lz_cfg.map_or("null".to_owned(), |lz_cfg| {
+            format!(
+                r#"{{"match_depth":{},"lazy_match_depth1":{},"lazy_match_depth2":{},"bucket_size":{},"good_match_len":{},"lazy_match_max_len":{},"lazy_match_min_gain":{},"lazy_match_cheap_offset_bits":{},"lazy_match_lookahead":{}}}"#,
+                lz_cfg.match_depth,
+                lz_cfg.lazy_match_depth1,
+                lz_cfg.lazy_match_depth2,
+                lz_cfg.bucket_size,
+                lz_cfg.good_match_len,
+                lz_cfg.lazy_match_max_len,
+                lz_cfg.lazy_match_min_gain,
+                lz_cfg.lazy_match_cheap_offset_bits,
+                lz_cfg.lazy_match_lookahead,
+            )
+        });
+        let level = frame.lz_cfg.and_then(|lz_cfg| lz_cfg.level());
+        let file_info = frame.file_info.as_ref().map_or("null".to_owned(), |file_info| {
+            format!(
+                r#"{{"name":{},"size":{},"mtime":{},"mode":{}}}"#,
+                json_option(file_info.name.as_deref().map(json_string)),
+                json_option(file_info.size),
+                json_option(file_info.mtime),
+                json_option(file_info.mode),
+            )
+        });
+        let blocks = frame.blocks.iter().map(|block| {
+            format!(
+                r#"{{"num_chunks":{},"num_stored_chunks":{},"num_filled_chunks":{},"context":{},"compressed_size":{},"uncompressed_size":{}}}"#,
+                block.num_chunks,
+                block.num_stored_chunks,
+                block.num_filled_chunks,
+                json_option(block.context.map(|context| json_string(&context.to_string()))),
+                block.encoded_len,
+                block.decoded_len,
+            )
+        });
+        format!(
+            r#"{{"format_version":{},"level":{},"lz_cfg":{},"checksum":{},"reordered_segments":{},"filter":{},"file_info":{},"num_chunks":{},"compressed_size":{},"uncompressed_size":{},"blocks":[{}]}}"#,
+            frame.version,
+            json_option(level),
+            lz_cfg,
+            json_string(if frame.chunk_checksum { "crc32" } else { "none" }),
+            json_option(frame.reordered_segments),
+            json_option(frame.filter.map(|filter| json_string(&filter.to_string()))),
+            file_info,
+            frame.num_chunks,
+            frame.encoded_len,
+            frame.decoded_len(),
+            blocks.collect::<Vec<_>>().join(","),
+        )
+    });
+    let metadata = info
+        .metadata
+        .iter()
+        .map(|metadata| format!(r#"{{"tag":{},"size":{}}}"#, metadata.tag, metadata.len));
+    format!(
+        r#"{{"file":{},"compressed_size":{},"uncompressed_size":{},"ratio":{},"frames":[{}],"metadata":[{}],"trailing_size":{}}}"#,
+        json_string(name),
+        encoded_len,
+        decoded_len,
+        ratio,
+        frames.collect::<Vec<_>>().join(","),
+        metadata.collect::<Vec<_>>().join(","),
+        info.trailing_len,
+    )
+}
+
+fn json_string(s: &str) -> String {
+    let mut json = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(json, "\\u{:04x}", c as u32);
+            }
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+fn json_option<T: ToString>(v: Option<T>) -> String {
+    v.map_or("null".to_owned(), |v| v.to_string())
+}
+
+/// Collects the attributes of a source file to store in the header.
+fn get_file_info(path: &Path) -> std::io::Result<FileInfo> {
+    let metadata = std::fs::metadata(path)?;
+    let mtime = metadata.modified().ok();
+    Ok(FileInfo {
+        name: path.file_name().and_then(OsStr::to_str).map(str::to_owned),
+        size: metadata.is_file().then_some(metadata.len()),
+        mtime: mtime.and_then(|t| t.duration_since(UNIX_EPOCH).ok().map(|d| d.as_secs())),
+        #[cfg(unix)]
+        mode: Some(std::os::unix::fs::PermissionsExt::mode(&metadata.permissions()) & 0o7777),
+        #[cfg(not(unix))]
+        mode: None,
+    })
+}
+
+/// Returns the stored file name if it is a plain name without directories.
+fn original_file_name(info: &FileInfo) -> Option<&str> {
+    let name = info.name.as_deref()?;
+    (Path::new(name).file_name() == Some(OsStr::new(name))).then_some(name)
+}
+
+/// Restores the modification time and permissions of a decoded file.
+fn restore_file_attributes(file: &File, info: &FileInfo) -> std::io::Result<()> {
+    if let Some(mtime) = info.mtime {
+        file.set_modified(UNIX_EPOCH + Duration::from_secs(mtime))?;
+    }
+    #[cfg(unix)]
+    if let Some(mode) = info.mode {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(std::fs::Permissions::from_mode(mode))?;
+    }
+    Ok(())
+}
+
+/// Encodes stdin, flushing everything read so far at every interval.
+fn encode_with_flush_interval<W: Write>(
+    target: CountWrite<W>,
+    cfg: &LZCfg,
+    flush_interval: Duration,
+    progress_logger: &mut impl ProgressLogger,
+) -> std::io::Result<()> {
+    // read stdin in a separate thread, so flushing is not blocked by reading
+    let (sender, receiver) = sync_channel(16);
+    std::thread::spawn(move || {
+        let mut stdin = stdin().lock();
+        loop {
+            let mut buf = vec![0u8; 65536];
+            let result = stdin.read(&mut buf).map(|len| {
+                buf.truncate(len);
+                buf
+            });
+            let eof = !matches!(&result, Ok(buf) if !buf.is_empty());
+            if sender.send(result).is_err() || eof {
+                break;
+            }
+        }
+    });
+
+    progress_logger.set_is_encode(true);
+    let mut writer = OrzWriter::new(target, cfg)?;
+    let mut flush_time = Instant::now() + flush_interval;
+    loop {
+        match receiver.recv_timeout(flush_time.saturating_duration_since(Instant::now())) {
+            Ok(buf) if buf.as_ref().is_ok_and(|buf| buf.is_empty()) => break,
+            Ok(buf) => writer.write_all(&buf?)?,
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+        if Instant::now() >= flush_time {
+            writer.flush()?;
+            flush_time = Instant::now() + flush_interval;
+        }
+    }
+    let num_input_bytes = writer.stream().total_in();
+    let target = writer.finish()?;
+    progress_logger.finish(num_input_bytes, target.count());
     Ok(())
 }
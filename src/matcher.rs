@@ -10,15 +10,17 @@ use bitfield_struct::*;
 use unchecked_index::UncheckedIndex;
 
 use crate::{
-    LZ_MATCH_MAX_LEN, LZ_MATCH_MIN_LEN, LZ_MF_BUCKET_ITEM_SIZE,
+    LZ_MATCH_MAX_LEN, LZ_MATCH_MIN_LEN,
     mem::{BytesConstPtrExt, mem_fast_common_prefix, mem_fast_equal},
     unchecked,
 };
 
-const LZ_MF_BUCKET_ITEM_HASH_SIZE: usize = (LZ_MF_BUCKET_ITEM_SIZE as f64 * 1.13) as usize | 1;
+// index of no node in the matcher chains
+const NO_NODE: u16 = u16::MAX;
 
 #[derive(Default)] // Match::default = unmatched
 pub struct Match {
+    pub pos: usize,
     pub reduced_offset: usize,
     pub match_len: usize,
     pub match_len_expected: usize,
@@ -26,9 +28,10 @@ pub struct Match {
 }
 
 pub struct Bucket {
-    nodes1: UncheckedIndex<[MatchPosAndLenMin; LZ_MF_BUCKET_ITEM_SIZE]>, /* pos:25 | match_len_min:7 */
-    nodes2: UncheckedIndex<[MatchLenExpected; LZ_MF_BUCKET_ITEM_SIZE]>,  // match_len_expected:8
+    nodes1: UncheckedIndex<Vec<MatchPosAndLenMin>>, // pos:25 | match_len_min:7
+    nodes2: UncheckedIndex<Vec<MatchLenExpected>>,  // match_len_expected:8
     head: usize,
+    size: usize,
     // match_len_expected:
     //  the match length we got when searching match for this position
     //  if no match is found, this value is set to 0.
@@ -51,20 +54,22 @@ pub struct Bucket {
 }
 
 impl Bucket {
-    pub fn new() -> Self {
+    /// Creates a bucket keeping the last `size` (at most 65535) positions.
+    pub fn new(size: usize) -> Self {
         Self {
-            nodes1: unchecked!([MatchPosAndLenMin::default(); LZ_MF_BUCKET_ITEM_SIZE]),
-            nodes2: unchecked!([MatchLenExpected::default(); LZ_MF_BUCKET_ITEM_SIZE]),
+            nodes1: unchecked!(vec![MatchPosAndLenMin::default(); size]),
+            nodes2: unchecked!(vec![MatchLenExpected::default(); size]),
             head: 0,
+            size,
         }
     }
 
     pub fn update(&mut self, pos: usize, reduced_offset: usize, match_len: usize) {
-        let new_head = node_size_bounded_add(self.head, 1);
+        let new_head = node_size_bounded_add(self.head, 1, self.size);
 
         // update match_len_min of matched position
         if match_len >= LZ_MATCH_MIN_LEN {
-            let node_index = node_size_bounded_sub(self.head, reduced_offset);
+            let node_index = node_size_bounded_sub(self.head, reduced_offset, self.size);
             if self.nodes1[node_index].match_len_min() <= match_len {
                 self.nodes1[node_index].set_match_len_min((match_len + 1).min(127));
             }
@@ -87,7 +92,7 @@ impl Bucket {
     }
 
     pub fn get_match_node_index(&self, reduced_offset: usize) -> usize {
-        node_size_bounded_sub(self.head, reduced_offset)
+        node_size_bounded_sub(self.head, reduced_offset, self.size)
     }
 
     pub fn get_match_pos_and_len_min(&self, node_index: usize) -> MatchPosAndLenMin {
@@ -100,45 +105,57 @@ impl Bucket {
 }
 
 pub struct BucketMatcher {
-    heads: UncheckedIndex<[i16; LZ_MF_BUCKET_ITEM_HASH_SIZE]>,
-    nexts: UncheckedIndex<[i16; LZ_MF_BUCKET_ITEM_SIZE]>,
+    heads: UncheckedIndex<Vec<u16>>,
+    nexts: UncheckedIndex<Vec<u16>>,
 }
 
 impl BucketMatcher {
-    pub fn new() -> Self {
+    pub fn new(bucket_size: usize) -> Self {
+        let hash_size = (bucket_size as f64 * 1.13) as usize | 1;
         Self {
-            heads: unchecked!([-1; LZ_MF_BUCKET_ITEM_HASH_SIZE]),
-            nexts: unchecked!([-1; LZ_MF_BUCKET_ITEM_SIZE]),
+            heads: unchecked!(vec![NO_NODE; hash_size]),
+            nexts: unchecked!(vec![NO_NODE; bucket_size]),
         }
     }
 
+    #[inline]
+    fn entry(&self, buf: &[u8], pos: usize) -> usize {
+        hash_dword(buf, pos) % self.heads.len()
+    }
+
     pub fn update(&mut self, bucket: &Bucket, buf: &[u8], pos: usize) {
         let head = bucket.head;
-        let entry = hash_dword(buf, pos) % LZ_MF_BUCKET_ITEM_HASH_SIZE;
+        let entry = self.entry(buf, pos);
 
         self.nexts[head] = self.heads[entry];
-        self.heads[entry] = bucket.head as i16;
+        self.heads[entry] = bucket.head as u16;
     }
 
     pub fn forward(&mut self, bucket: &Bucket) {
         // clear all entries/positions that points to out-of-date node
         self.heads
             .iter_mut()
-            .filter(|head| **head != -1 && bucket.nodes1[**head as usize].pos() == 0)
-            .for_each(|head| *head = -1);
+            .filter(|head| **head != NO_NODE && bucket.nodes1[**head as usize].pos() == 0)
+            .for_each(|head| *head = NO_NODE);
         self.nexts
             .iter_mut()
-            .filter(|next| **next != -1 && bucket.nodes1[**next as usize].pos() == 0)
-            .for_each(|next| *next = -1);
+            .filter(|next| **next != NO_NODE && bucket.nodes1[**next as usize].pos() == 0)
+            .for_each(|next| *next = NO_NODE);
     }
 
-    pub fn find_match(&self, bucket: &Bucket, buf: &[u8], pos: usize, match_depth: usize) -> Match {
-        let entry = hash_dword(buf, pos) % LZ_MF_BUCKET_ITEM_HASH_SIZE;
-        let mut node_index = self.heads[entry] as usize;
-
-        if node_index == usize::MAX {
+    pub fn find_match(
+        &self,
+        bucket: &Bucket,
+        buf: &[u8],
+        pos: usize,
+        match_depth: usize,
+        good_match_len: usize,
+    ) -> Match {
+        let entry = self.entry(buf, pos);
+        if self.heads[entry] == NO_NODE {
             return Match::default();
         }
+        let mut node_index = self.heads[entry] as usize;
         let mut max_len = LZ_MATCH_MIN_LEN - 1;
         let mut max_match_len_min = LZ_MATCH_MIN_LEN;
         let mut max_match_len_expected = LZ_MATCH_MIN_LEN;
@@ -160,7 +177,7 @@ impl BucketMatcher {
                     max_node_index = node_index;
                     max_len_dword = buf.as_ptr().get(pos + max_len - 3);
                 }
-                if lcp == LZ_MATCH_MAX_LEN {
+                if lcp >= good_match_len {
                     break;
                 }
                 if max_match_len_expected > 0 && lcp > max_match_len_expected {
@@ -168,10 +185,10 @@ impl BucketMatcher {
                 }
             }
 
-            node_index = self.nexts[node_index] as usize;
-            if node_index == usize::MAX {
+            if self.nexts[node_index] == NO_NODE {
                 break;
             }
+            node_index = self.nexts[node_index] as usize;
 
             let node_pos_next = bucket.nodes1[node_index].pos() as usize;
             if node_pos <= node_pos_next {
@@ -182,7 +199,8 @@ impl BucketMatcher {
 
         if max_len >= LZ_MATCH_MIN_LEN && pos + max_len < buf.len() {
             return Match {
-                reduced_offset: node_size_bounded_sub(bucket.head, max_node_index),
+                pos: bucket.nodes1[max_node_index].pos(),
+                reduced_offset: node_size_bounded_sub(bucket.head, max_node_index, bucket.size),
                 match_len: max_len,
                 match_len_expected: std::cmp::max(max_match_len_expected, LZ_MATCH_MIN_LEN),
                 match_len_min: std::cmp::max(max_match_len_min, LZ_MATCH_MIN_LEN),
@@ -200,12 +218,11 @@ impl BucketMatcher {
         depth: usize,
     ) -> bool {
         let max_len_dword = buf.as_ptr().get::<u32>(pos + min_match_len - 4);
-        let entry = hash_dword(buf, pos) % LZ_MF_BUCKET_ITEM_HASH_SIZE;
-        let mut node_index = self.heads[entry] as usize;
-
-        if node_index == usize::MAX {
+        let entry = self.entry(buf, pos);
+        if self.heads[entry] == NO_NODE {
             return false;
         }
+        let mut node_index = self.heads[entry] as usize;
         let mut node_pos = bucket.nodes1[node_index].pos();
 
         for _ in 0..depth {
@@ -213,10 +230,10 @@ impl BucketMatcher {
                 return true;
             };
 
-            node_index = self.nexts[node_index] as usize;
-            if node_index == usize::MAX {
+            if self.nexts[node_index] == NO_NODE {
                 break;
             }
+            node_index = self.nexts[node_index] as usize;
 
             let node_pos_next = bucket.nodes1[node_index].pos();
             if node_pos <= node_pos_next {
@@ -243,17 +260,21 @@ pub struct MatchLenExpected {
 }
 
 #[inline]
-fn node_size_bounded_add(v1: usize, v2: usize) -> usize {
-    (v1 + v2) % LZ_MF_BUCKET_ITEM_SIZE
+fn node_size_bounded_add(v1: usize, v2: usize, size: usize) -> usize {
+    // v1, v2 < size
+    let v = v1 + v2;
+    if v >= size { v - size } else { v }
 }
 
 #[inline]
-fn node_size_bounded_sub(v1: usize, v2: usize) -> usize {
-    (v1 + LZ_MF_BUCKET_ITEM_SIZE - v2) % LZ_MF_BUCKET_ITEM_SIZE
+fn node_size_bounded_sub(v1: usize, v2: usize, size: usize) -> usize {
+    // v1, v2 < size
+    let v = v1 + size - v2;
+    if v >= size { v - size } else { v }
 }
 
 #[inline]
-fn hash_dword(buf: &[u8], pos: usize) -> usize {
+pub fn hash_dword(buf: &[u8], pos: usize) -> usize {
     // safety: buf[pos..][..4] must be valid
     const MULS: u32x4 = u32x4::from_array([131313131, 1313131, 13131, 131]);
     const ADDS: u32x4 = u32x4::from_array([797, 79797, 7979797, 797979797]);
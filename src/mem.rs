@@ -69,7 +69,14 @@ pub fn mem_fast_equal(
         })
 }
 
-// safety: with max_match_len sentinel bytes at the end
+/// Max bytes `mem_fast_copy` writes after `pdst + len`.
+///
+/// Repeating a pattern closer than 16 bytes moves the destination by up to 21
+/// bytes (offsets 3 and 7), then the 16-byte stores round the length up by up
+/// to 15 bytes.
+pub const MEM_FAST_COPY_MAX_OVERRUN: usize = 36;
+
+// safety: with MEM_FAST_COPY_MAX_OVERRUN sentinel bytes after pdst + len
 #[inline(always)]
 pub fn mem_fast_copy(buf: *mut u8, psrc: usize, pdst: usize, len: usize) {
     let mut pdst = pdst;
@@ -80,13 +87,50 @@ pub fn mem_fast_copy(buf: *mut u8, psrc: usize, pdst: usize, len: usize) {
         return;
     }
 
-    // handle overlapping
-    while pdst - psrc < 4 {
-        buf.put(pdst, buf.cast_const().get::<u32>(psrc));
-        pdst += pdst - psrc;
+    // handle overlapping, repeating the pattern until 16 bytes can be copied at
+    // once
+    while pdst - psrc < 16 {
+        let distance = pdst - psrc;
+        for l in (0..distance).step_by(4) {
+            buf.put(pdst + l, buf.cast_const().get::<u32>(psrc + l));
+        }
+        pdst += distance;
     }
 
-    for l in (0..len).step_by(4) {
-        buf.put(pdst + l, buf.cast_const().get::<u32>(psrc + l));
+    for l in (0..len).step_by(16) {
+        buf.put(pdst + l, buf.cast_const().get::<std::simd::u8x16>(psrc + l));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_mem_fast_copy_overrun() {
+        // short offsets with the match ending right before the sentinel bytes
+        let canary = 0xaa;
+        let mut max_overrun = 0;
+        for offset in 1..=32 {
+            for len in 4..=80 {
+                let end = 64 + offset + len;
+                let mut buf = vec![canary; end + MEM_FAST_COPY_MAX_OVERRUN + 64];
+                buf[..end - len]
+                    .iter_mut()
+                    .enumerate()
+                    .for_each(|(i, b)| *b = i as u8 & 0x7f);
+                let mut expected = buf[..end].to_vec();
+                (end - len..end).for_each(|i| expected[i] = expected[i - offset]);
+
+                mem_fast_copy(buf.as_mut_ptr(), end - len - offset, end - len, len);
+                assert_eq!(buf[..end], expected);
+                let overrun = buf[end..]
+                    .iter()
+                    .rposition(|&b| b != canary)
+                    .map_or(0, |i| i + 1);
+                max_overrun = max_overrun.max(overrun);
+            }
+        }
+        assert_eq!(max_overrun, MEM_FAST_COPY_MAX_OVERRUN);
     }
 }
@@ -4,12 +4,63 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use std::time::Instant;
+use std::{
+    io::{IsTerminal, Write, stderr},
+    ops::ControlFlow,
+    time::{Duration, Instant},
+};
+
+// min interval between two redraws of the progress bar
+const BAR_REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+const BAR_WIDTH: usize = 30;
+
+/// Message of the error returned when a progress logger cancels coding.
+pub const CANCELLED_MSG: &str = "cancelled by progress logger";
 
 pub trait ProgressLogger {
     fn set_is_encode(&mut self, is_encode: bool);
     fn log(&mut self, num_input_bytes: usize, num_output_bytes: usize);
     fn finish(&mut self, _num_input_bytes: usize, _num_output_bytes: usize);
+
+    /// Called after chunks are processed, before the `log` of their block.
+    /// Returning `ControlFlow::Break` cancels coding with an `Other` error
+    /// whose message is [`CANCELLED_MSG`].
+    fn chunk(&mut self, _progress: ChunkProgress) -> ControlFlow<()> {
+        ControlFlow::Continue(())
+    }
+}
+
+/// Position of the coder after processing a chunk.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChunkProgress {
+    /// Index of the chunk in the stream.
+    pub index: usize,
+    /// Input bytes consumed when the chunk was processed, the raw input when
+    /// encoding and the compressed stream when decoding.
+    pub num_input_bytes: usize,
+    /// Bytes of the processed chunks as coded, counted after filtering when
+    /// encoding and before unfiltering when decoding.
+    pub num_filtered_bytes: usize,
+    /// Output bytes produced so far.
+    pub num_output_bytes: usize,
+}
+
+impl<P: ProgressLogger + ?Sized> ProgressLogger for Box<P> {
+    fn set_is_encode(&mut self, is_encode: bool) {
+        (**self).set_is_encode(is_encode);
+    }
+
+    fn log(&mut self, num_input_bytes: usize, num_output_bytes: usize) {
+        (**self).log(num_input_bytes, num_output_bytes);
+    }
+
+    fn finish(&mut self, num_input_bytes: usize, num_output_bytes: usize) {
+        (**self).finish(num_input_bytes, num_output_bytes);
+    }
+
+    fn chunk(&mut self, progress: ChunkProgress) -> ControlFlow<()> {
+        (**self).chunk(progress)
+    }
 }
 
 pub struct SilentProgressLogger;
@@ -20,22 +71,86 @@ impl ProgressLogger for SilentProgressLogger {
     fn finish(&mut self, _num_input_bytes: usize, _num_output_bytes: usize) {}
 }
 
+/// Logs progress to stderr, as an in-place progress bar if stderr is a
+/// terminal, or one line per block otherwise.
 pub struct SimpleProgressLogger {
     is_encode: bool,
     start_time: Instant,
     update_time: Instant,
     cur_num_input_bytes: usize,
     cur_num_output_bytes: usize,
+    total_input_bytes: Option<usize>,
+    bar_time: Option<Instant>,
 }
 
 impl SimpleProgressLogger {
     pub fn new() -> Self {
+        let is_terminal = stderr().is_terminal();
         SimpleProgressLogger {
             is_encode: true,
             start_time: Instant::now(),
             update_time: Instant::now(),
             cur_num_input_bytes: 0,
             cur_num_output_bytes: 0,
+            total_input_bytes: None,
+            bar_time: is_terminal.then(|| {
+                let now = Instant::now();
+                now.checked_sub(BAR_REDRAW_INTERVAL).unwrap_or(now)
+            }),
+        }
+    }
+
+    /// Sets the total input size, enabling percentage and ETA.
+    pub fn set_total_input_bytes(&mut self, total_input_bytes: usize) {
+        self.total_input_bytes = Some(total_input_bytes);
+    }
+
+    fn draw_bar(&mut self, num_input_bytes: usize, num_output_bytes: usize) {
+        let time = Instant::now();
+        match self.bar_time {
+            Some(bar_time) if time - bar_time >= BAR_REDRAW_INTERVAL => self.bar_time = Some(time),
+            _ => return,
+        }
+        let line = self.bar_line(num_input_bytes, num_output_bytes, time - self.start_time);
+        eprint!("\r{line}\x1b[K");
+        let _ = stderr().flush();
+    }
+
+    /// The progress bar line, num_input_bytes is the raw input consumed (the
+    /// compressed stream when decoding), comparable to the total input size.
+    fn bar_line(
+        &self,
+        num_input_bytes: usize,
+        num_output_bytes: usize,
+        elapsed: Duration,
+    ) -> String {
+        let uncompressed_bytes = match self.is_encode {
+            true => num_input_bytes,
+            false => num_output_bytes,
+        };
+        let mbps = uncompressed_bytes as f64 / elapsed.as_micros().max(1) as f64;
+        let mode = if self.is_encode { "encode" } else { "decode" };
+
+        match self.total_input_bytes {
+            Some(total) if total > 0 => {
+                let fraction = (num_input_bytes as f64 / total as f64).min(1.0);
+                let filled = (fraction * BAR_WIDTH as f64) as usize;
+                let eta = match num_input_bytes {
+                    0 => "--:--".to_owned(),
+                    n => format_duration(elapsed.mul_f64((total - n.min(total)) as f64 / n as f64)),
+                };
+                format!(
+                    "{mode}: [{}{}] {:5.1}% {mbps:.3} MB/s, elapsed {}, ETA {eta}",
+                    "#".repeat(filled),
+                    " ".repeat(BAR_WIDTH - filled),
+                    fraction * 100.0,
+                    format_duration(elapsed),
+                )
+            }
+            _ => format!(
+                "{mode}: {num_input_bytes} bytes => {num_output_bytes} bytes, {mbps:.3} MB/s, elapsed {}",
+                format_duration(elapsed),
+            ),
         }
     }
 }
@@ -46,6 +161,10 @@ impl ProgressLogger for SimpleProgressLogger {
     }
 
     fn log(&mut self, num_input_bytes: usize, num_output_bytes: usize) {
+        if self.bar_time.is_some() {
+            self.draw_bar(num_input_bytes, num_output_bytes);
+            return;
+        }
         let time = Instant::now();
         let batch_duration_micros = time.duration_since(self.update_time).as_micros();
         let ibs = num_input_bytes - self.cur_num_input_bytes;
@@ -56,14 +175,24 @@ impl ProgressLogger for SimpleProgressLogger {
             eprintln!("encode: {ibs} bytes => {obs} bytes, {mbps:.3} MB/s");
         } else {
             let mbps = obs as f64 / batch_duration_micros as f64;
-            eprintln!("encode: {obs} bytes <= {ibs} bytes, {mbps:.3} MB/s");
+            eprintln!("decode: {obs} bytes <= {ibs} bytes, {mbps:.3} MB/s");
         }
         self.cur_num_input_bytes = num_input_bytes;
         self.cur_num_output_bytes = num_output_bytes;
         self.update_time = time;
     }
 
+    fn chunk(&mut self, progress: ChunkProgress) -> ControlFlow<()> {
+        if self.bar_time.is_some() {
+            self.draw_bar(progress.num_input_bytes, progress.num_output_bytes);
+        }
+        ControlFlow::Continue(())
+    }
+
     fn finish(&mut self, num_input_bytes: usize, num_output_bytes: usize) {
+        if self.bar_time.is_some() {
+            eprint!("\r\x1b[K"); // clear the progress bar
+        }
         self.cur_num_input_bytes = num_input_bytes;
         self.cur_num_output_bytes = num_output_bytes;
         self.update_time = Instant::now();
@@ -97,3 +226,140 @@ impl ProgressLogger for SimpleProgressLogger {
         eprintln!("  time:  {:.3} sec", duration_micros as f64 * 1e-6);
     }
 }
+
+/// Formats a duration as m:ss or h:mm:ss.
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match secs / 3600 {
+        0 => format!("{}:{:02}", secs / 60, secs % 60),
+        hours => format!("{}:{:02}:{:02}", hours, secs / 60 % 60, secs % 60),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{CountRead, CountWrite, LZCfg, testutil::random_bytes};
+
+    /// Records the chunks reported, cancelling at the second one if `cancel`.
+    struct ChunkLogger {
+        chunks: Vec<ChunkProgress>,
+        cancel: bool,
+    }
+
+    impl ChunkLogger {
+        fn new(cancel: bool) -> Self {
+            Self {
+                chunks: vec![],
+                cancel,
+            }
+        }
+    }
+
+    impl ProgressLogger for ChunkLogger {
+        fn set_is_encode(&mut self, _is_encode: bool) {}
+        fn log(&mut self, _num_input_bytes: usize, _num_output_bytes: usize) {}
+        fn finish(&mut self, _num_input_bytes: usize, _num_output_bytes: usize) {}
+
+        fn chunk(&mut self, progress: ChunkProgress) -> ControlFlow<()> {
+            self.chunks.push(progress);
+            match self.chunks.len() {
+                2.. if self.cancel => ControlFlow::Break(()),
+                _ => ControlFlow::Continue(()),
+            }
+        }
+    }
+
+    #[test]
+    fn test_cancel_by_chunk() {
+        // incompressible data, so every chunk covers about LZ_CHUNK_SIZE bytes
+        let input = random_bytes(crate::LZ_CHUNK_SIZE * 3);
+        let mut logger = ChunkLogger::new(true);
+        let e = crate::encode(
+            &mut CountRead::new(&input[..]),
+            &mut CountWrite::new(vec![]),
+            &LZCfg::new(5, 3, 2),
+            &mut logger,
+        )
+        .unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::Other);
+        assert_eq!(e.to_string(), CANCELLED_MSG);
+        assert_eq!(logger.chunks.len(), 2);
+        assert_eq!(logger.chunks[0].index, 0);
+        let filtered = logger.chunks.iter().map(|chunk| chunk.num_filtered_bytes);
+        assert!(filtered.is_sorted() && logger.chunks[0].num_filtered_bytes > 0);
+    }
+
+    #[test]
+    fn test_chunk_progress_filtered() {
+        // the text filter shrinks the input, chunks are counted after filtering
+        let input = (0..400000u32)
+            .flat_map(|i| format!("word{} ", i % 5000).into_bytes())
+            .collect::<Vec<_>>();
+        let mut stream = crate::OrzStream::encoder(&LZCfg::new(5, 3, 2)).unwrap();
+        stream.set_filter(crate::Filter::Text).unwrap();
+        let mut logger = ChunkLogger::new(true);
+        let e = crate::encode_stream(
+            &mut stream,
+            &mut CountRead::new(&input[..]),
+            &mut CountWrite::new(vec![]),
+            &mut logger,
+        )
+        .unwrap_err();
+        assert_eq!(e.to_string(), CANCELLED_MSG);
+        let [first, second] = logger.chunks[..] else {
+            panic!("two chunks reported");
+        };
+        assert!(
+            0 < first.num_filtered_bytes && first.num_filtered_bytes < second.num_filtered_bytes
+        );
+        assert!(second.num_filtered_bytes < second.num_input_bytes);
+        assert_eq!(second.num_input_bytes, stream.total_in());
+    }
+
+    #[test]
+    fn test_decode_chunk_progress() {
+        // the compressed bytes consumed up to every decoded chunk
+        let input = random_bytes(crate::LZ_CHUNK_SIZE * 3);
+        let encoded = crate::testutil::roundtrip(&LZCfg::new(5, 3, 2), &input);
+        let mut logger = ChunkLogger::new(false);
+        crate::decode(
+            &mut CountRead::new(&encoded[..]),
+            &mut CountWrite::new(vec![]),
+            &mut logger,
+        )
+        .unwrap();
+
+        let chunks = &logger.chunks;
+        let info = crate::StreamInfo::read(&encoded[..]).unwrap();
+        assert_eq!(chunks.len(), info.frames[0].num_chunks);
+        assert!(chunks[0].num_input_bytes > 0);
+        assert!(
+            chunks
+                .windows(2)
+                .all(|w| w[0].num_input_bytes < w[1].num_input_bytes)
+        );
+        assert!(chunks.last().unwrap().num_input_bytes < encoded.len());
+        assert_eq!(chunks.last().unwrap().num_filtered_bytes, input.len());
+        assert!(
+            chunks
+                .iter()
+                .all(|chunk| chunk.num_output_bytes <= chunk.num_filtered_bytes)
+        );
+    }
+
+    #[test]
+    fn test_bar_line() {
+        let mut logger = SimpleProgressLogger::new();
+        logger.set_total_input_bytes(1000);
+        let elapsed = Duration::from_secs(10);
+
+        // encoding all of a filtered input fills the bar, whatever it filters to
+        assert!(logger.bar_line(1000, 300, elapsed).contains("] 100.0%"));
+
+        // decoding is measured on the compressed input
+        logger.set_is_encode(false);
+        let line = logger.bar_line(500, 4000, elapsed);
+        assert!(line.contains("]  50.0%") && line.ends_with("ETA 0:10"));
+    }
+}
@@ -0,0 +1,343 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::{
+    io::{Error, ErrorKind, Read, Result, Write},
+    ops::Range,
+};
+
+use crate::{
+    ioutil::{ReadExt, WriteExt},
+    matcher::hash_dword,
+};
+
+const TAR_BLOCK_SIZE: usize = 512;
+
+// max bytes of a member sampled for its content signature
+const SIGNATURE_SAMPLE_LEN: usize = 1 << 20;
+
+/// Max bytes of a reordered frame, which decoders buffer whole to restore
+/// the original order.
+pub const MAX_REORDERED_LEN: usize = 1 << 30;
+
+/// A permutation of the input segments applied before encoding, stored in
+/// the frame header so decoding restores the original order.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Reordering {
+    /// Segment lengths in original order.
+    segment_lens: Vec<usize>,
+    /// Original indices of the segments in encoded order.
+    order: Vec<usize>,
+}
+
+impl Reordering {
+    fn new(segment_lens: Vec<usize>, order: Vec<usize>) -> Result<Self> {
+        let mut seen = vec![false; segment_lens.len()];
+        for &i in &order {
+            if i >= seen.len() || std::mem::replace(&mut seen[i], true) {
+                return Err(Error::new(ErrorKind::InvalidData, "invalid reordering"));
+            }
+        }
+        let total_len = segment_lens
+            .iter()
+            .try_fold(0usize, |sum, &len| sum.checked_add(len));
+        if order.len() != segment_lens.len() || total_len.is_none() {
+            return Err(Error::new(ErrorKind::InvalidData, "invalid reordering"));
+        }
+        if total_len.is_some_and(|len| len > MAX_REORDERED_LEN) {
+            let msg = "reordered frame too large";
+            return Err(Error::new(ErrorKind::InvalidData, msg));
+        }
+        Ok(Self {
+            segment_lens,
+            order,
+        })
+    }
+
+    pub fn num_segments(&self) -> usize {
+        self.segment_lens.len()
+    }
+
+    pub fn total_len(&self) -> usize {
+        self.segment_lens.iter().sum()
+    }
+
+    /// Reorders the original data for encoding.
+    pub fn apply(&self, data: &[u8]) -> Vec<u8> {
+        let ranges = self.segment_ranges();
+        self.order
+            .iter()
+            .flat_map(|&i| &data[ranges[i].clone()])
+            .copied()
+            .collect()
+    }
+
+    /// Restores the original data from decoded data.
+    pub fn restore(&self, data: &[u8]) -> Vec<u8> {
+        let mut restored = vec![0u8; data.len()];
+        let ranges = self.segment_ranges();
+        let mut pos = 0;
+        for &i in &self.order {
+            restored[ranges[i].clone()].copy_from_slice(&data[pos..][..ranges[i].len()]);
+            pos += ranges[i].len();
+        }
+        restored
+    }
+
+    pub fn write<W: Write>(&self, w: &mut W) -> Result<()> {
+        w.write_len(self.segment_lens.len())?;
+        for &len in &self.segment_lens {
+            w.write_len(len)?;
+        }
+        for &i in &self.order {
+            w.write_len(i)?;
+        }
+        Ok(())
+    }
+
+    pub fn read<R: Read>(r: &mut R) -> Result<Self> {
+        let num_segments = r.read_len()?;
+        let segment_lens = (0..num_segments)
+            .map(|_| r.read_len())
+            .collect::<Result<Vec<_>>>()?;
+        let order = (0..num_segments)
+            .map(|_| r.read_len())
+            .collect::<Result<Vec<_>>>()?;
+        Self::new(segment_lens, order)
+    }
+
+    fn segment_ranges(&self) -> Vec<Range<usize>> {
+        let mut pos = 0;
+        let ranges = self.segment_lens.iter().map(|&len| {
+            pos += len;
+            pos - len..pos
+        });
+        ranges.collect()
+    }
+}
+
+struct TarMember {
+    range: Range<usize>,
+    content: Range<usize>,
+    name: Vec<u8>,
+}
+
+/// Reorders the members of a tar archive by extension and content
+/// similarity, returns `None` if data is not a tar archive or is larger than
+/// [`MAX_REORDERED_LEN`].
+pub fn reorder_tar(data: &[u8]) -> Option<Reordering> {
+    let members = parse_tar(data)?;
+    let head_len = members[0].range.start;
+    let trailer_start = members.last().unwrap().range.end;
+
+    // group by extension, then by min-hash so members sharing content are adjacent
+    let mut keys = members
+        .iter()
+        .enumerate()
+        .map(|(i, member)| {
+            let extension = extension(&member.name).to_ascii_lowercase();
+            let signature = content_signature(&data[member.content.clone()]);
+            (extension, signature, i + 1)
+        })
+        .collect::<Vec<_>>();
+    keys.sort();
+
+    // the head (a leading global header) and the trailer are kept in place
+    let segment_lens = std::iter::once(head_len)
+        .chain(members.iter().map(|member| member.range.len()))
+        .chain(std::iter::once(data.len() - trailer_start))
+        .collect();
+    let order = std::iter::once(0)
+        .chain(keys.into_iter().map(|(_, _, i)| i))
+        .chain(std::iter::once(members.len() + 1))
+        .collect();
+    Reordering::new(segment_lens, order).ok()
+}
+
+/// Splits a tar archive into members, each with its preceding extension
+/// headers, returns `None` if it is not a tar archive with multiple members.
+fn parse_tar(data: &[u8]) -> Option<Vec<TarMember>> {
+    let mut members = vec![];
+    let mut member_start = 0;
+    let mut long_name = None;
+    let mut pos = 0;
+    while pos + TAR_BLOCK_SIZE <= data.len() {
+        let header = &data[pos..][..TAR_BLOCK_SIZE];
+        if header.iter().all(|&b| b == 0) {
+            break; // end of archive
+        }
+        if !tar_checksum_ok(header) {
+            return None;
+        }
+        let size = parse_tar_number(&header[124..136]).filter(|&size| size <= data.len())?;
+        let content = pos + TAR_BLOCK_SIZE..pos + TAR_BLOCK_SIZE + size;
+        let end = pos + TAR_BLOCK_SIZE + size.div_ceil(TAR_BLOCK_SIZE) * TAR_BLOCK_SIZE;
+        if end > data.len() {
+            return None;
+        }
+
+        match header[156] {
+            b'L' => long_name = Some(c_str(&data[content]).to_vec()),
+            b'x' => long_name = pax_path(&data[content]).or(long_name),
+            b'K' => {}
+            // global headers apply to all following members, so only a leading one is allowed
+            b'g' if members.is_empty() && pos == member_start => member_start = end,
+            b'g' => return None,
+            _ => {
+                members.push(TarMember {
+                    range: member_start..end,
+                    content,
+                    name: long_name
+                        .take()
+                        .unwrap_or_else(|| c_str(&header[..100]).to_vec()),
+                });
+                member_start = end;
+            }
+        }
+        pos = end;
+    }
+    (members.len() >= 2).then_some(members)
+}
+
+fn tar_checksum_ok(header: &[u8]) -> bool {
+    let sum = header
+        .iter()
+        .enumerate()
+        .map(|(i, &b)| if (148..156).contains(&i) { b' ' } else { b } as usize)
+        .sum::<usize>();
+    parse_tar_number(&header[148..156]) == Some(sum)
+}
+
+/// Parses an octal or base-256 number field.
+fn parse_tar_number(field: &[u8]) -> Option<usize> {
+    if field[0] & 0x80 != 0 {
+        let bytes = &field[1..];
+        let len = bytes.len().min(8);
+        if bytes[..bytes.len() - len].iter().any(|&b| b != 0) {
+            return None;
+        }
+        let mut be = [0u8; 8];
+        be[8 - len..].copy_from_slice(&bytes[bytes.len() - len..]);
+        return usize::try_from(u64::from_be_bytes(be)).ok();
+    }
+    let digits = c_str(field).trim_ascii();
+    let digits = std::str::from_utf8(digits).ok()?;
+    usize::from_str_radix(digits, 8).ok()
+}
+
+/// Finds the path record in pax extended header data.
+fn pax_path(mut records: &[u8]) -> Option<Vec<u8>> {
+    // each record is "<len> <key>=<value>\n", where len includes itself
+    let mut path = None;
+    while let Some(space) = records.iter().position(|&b| b == b' ') {
+        let len = std::str::from_utf8(&records[..space]).ok()?.parse().ok()?;
+        let record = records.get(space + 1..len)?.strip_suffix(b"\n")?;
+        if let Some(value) = record.strip_prefix(b"path=") {
+            path = Some(value.to_vec());
+        }
+        records = &records[len..];
+    }
+    path
+}
+
+fn c_str(field: &[u8]) -> &[u8] {
+    let len = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    &field[..len]
+}
+
+fn extension(name: &[u8]) -> &[u8] {
+    let name = name.rsplit(|&b| b == b'/').next().unwrap_or(name);
+    match name.iter().rposition(|&b| b == b'.') {
+        Some(dot) if dot > 0 => &name[dot + 1..],
+        _ => b"",
+    }
+}
+
+/// Min-hash of the dwords of the sampled content, equal signatures indicate
+/// similar contents.
+fn content_signature(content: &[u8]) -> u64 {
+    let content = &content[..content.len().min(SIGNATURE_SAMPLE_LEN)];
+    (0..content.len().saturating_sub(3))
+        .map(|pos| (hash_dword(content, pos) as u64).wrapping_mul(0x9e3779b97f4a7c15))
+        .min()
+        .unwrap_or(u64::MAX)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{CountRead, CountWrite, LZCfg, OrzReader, OrzStream, SilentProgressLogger};
+
+    fn tar_member(name: &str, content: &[u8]) -> Vec<u8> {
+        let mut header = [0u8; TAR_BLOCK_SIZE];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        header[124..135].copy_from_slice(format!("{:011o}", content.len()).as_bytes());
+        header[156] = b'0';
+        header[148..156].fill(b' ');
+        let sum = header.iter().map(|&b| b as usize).sum::<usize>();
+        header[148..155].copy_from_slice(format!("{:06o}\0", sum).as_bytes());
+
+        let mut member = header.to_vec();
+        member.extend_from_slice(content);
+        member.resize(member.len().next_multiple_of(TAR_BLOCK_SIZE), 0);
+        member
+    }
+
+    #[test]
+    fn test_reorder_tar() {
+        let tar = [
+            tar_member("a.txt", b"some text"),
+            tar_member("b.bin", &[1, 2, 3, 4, 5]),
+            tar_member("c.txt", b"more text"),
+            vec![0u8; TAR_BLOCK_SIZE * 2],
+        ]
+        .concat();
+        let reordering = reorder_tar(&tar).unwrap();
+        assert_eq!(reordering.num_segments(), 5);
+        assert_eq!(reordering.order[1], 2); // grouped by extension
+
+        let reordered = reordering.apply(&tar);
+        assert_eq!(reordering.restore(&reordered), tar);
+        assert!(reorder_tar(b"not a tar").is_none());
+
+        // frames too large to buffer while decoding are rejected
+        let mut header = vec![];
+        for len in [1, MAX_REORDERED_LEN + 1, 0] {
+            header.write_len(len).unwrap();
+        }
+        assert!(Reordering::read(&mut &header[..]).is_err());
+    }
+
+    #[test]
+    fn test_tar_reordered_stream() {
+        let tar = (0..20)
+            .map(|i| {
+                tar_member(
+                    &format!("{}.{}", i, ["txt", "bin"][i % 2]),
+                    &[i as u8; 1000],
+                )
+            })
+            .chain(std::iter::once(vec![0u8; TAR_BLOCK_SIZE * 2]))
+            .collect::<Vec<_>>()
+            .concat();
+        let mut encoded = vec![];
+        crate::encode_stream_tar_reordered(
+            &mut OrzStream::encoder(&LZCfg::new(5, 3, 2)).unwrap(),
+            &mut CountRead::new(&tar[..]),
+            &mut CountWrite::new(&mut encoded),
+            &mut SilentProgressLogger,
+        )
+        .unwrap();
+        let info = crate::StreamInfo::read(&encoded[..]).unwrap();
+        assert_eq!(info.frames[0].reordered_segments, Some(22));
+
+        let mut decoded = vec![];
+        OrzReader::new(&encoded[..])
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert!(decoded == tar);
+    }
+}
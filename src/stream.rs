@@ -0,0 +1,1639 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::{
+    fmt::Display,
+    io::{Error, ErrorKind, Read, Result, Write},
+};
+
+use crate::{
+    LZ_BLOCK_SIZE, LZ_CHUNK_SIZE, SBVEC_PREMATCH_LEN, SBVEC_SENTINEL_LEN,
+    checksum::crc32,
+    classify::{Classifier, DataClass, SEGMENT_LEN},
+    filter::{Filter, FilterCoder},
+    frame::{
+        ChunkHeader, ChunkMode, FORMAT_VERSION, FRAME_MAGIC, FileInfo, Frame, FrameHeader,
+        MAX_CHUNK_HEADER_LEN, is_frame_magic_prefix, write_metadata_frame,
+    },
+    ioutil::{WriteExt, push_len_byte},
+    lz::{ContextHash, LZ_MF_BUCKET_ITEM_SIZE, LZCfg, LZDecoder, LZEncoder},
+    reorder::Reordering,
+};
+
+// room reserved in front of an encoded chunk for its length prefix and header
+const CHUNK_PREFIX_SIZE: usize = 10 + MAX_CHUNK_HEADER_LEN;
+
+type MetadataCallback = Box<dyn FnMut(u32, &[u8]) + Send>;
+
+// max bytes buffered at once when probing for a frame header
+const MAX_HEADER_PROBE_LEN: usize = 4096;
+
+/// Flush mode passed to [`OrzStream::compress`] and [`OrzStream::decompress`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Flush {
+    /// More input may follow, output is produced whenever available.
+    None,
+    /// Encodes all input received so far and emits it without ending the
+    /// stream. The window is kept, so compression continues across the flush.
+    Sync,
+    /// No more input follows, the stream is finished once all output is
+    /// drained.
+    Finish,
+}
+
+/// Progress reported by [`OrzStream::compress`] and [`OrzStream::decompress`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Status {
+    /// More input or more output space is needed to make progress.
+    Ok,
+    /// The stream is complete and all output has been produced.
+    StreamEnd,
+}
+
+/// A push-style ORZ stream working on caller-provided buffers.
+///
+/// Like zlib's `z_stream`, a stream never blocks: each call consumes as much
+/// input and produces as much output as possible, and returns how many bytes
+/// were consumed/produced so the caller can resume at any position.
+pub struct OrzStream {
+    inner: StreamInner,
+    total_in: usize,
+    total_out: usize,
+}
+
+enum StreamInner {
+    Encode(Box<StreamEncoder>),
+    Decode(Box<StreamDecoder>),
+}
+
+impl OrzStream {
+    /// Creates a stream compressing data with the given matching options,
+    /// fails if they are not valid (see [`LZCfg::validate`]).
+    pub fn encoder(cfg: &LZCfg) -> Result<Self> {
+        let encoder = StreamEncoder::new(cfg)?;
+        Ok(Self::new(StreamInner::Encode(Box::new(encoder))))
+    }
+
+    /// Creates a stream decompressing ORZ data.
+    ///
+    /// Frames encoded from a reordered tar archive are buffered whole to
+    /// restore the original order, which takes up to twice
+    /// [`MAX_REORDERED_LEN`](crate::MAX_REORDERED_LEN) bytes of memory.
+    pub fn decoder() -> Self {
+        Self::new(StreamInner::Decode(Box::new(StreamDecoder::new())))
+    }
+
+    fn new(inner: StreamInner) -> Self {
+        Self {
+            inner,
+            total_in: 0,
+            total_out: 0,
+        }
+    }
+
+    /// Resets the stream for a new input, keeping its configuration and
+    /// reusing its buffers, which is much cheaper than creating a new stream.
+    pub fn reset(&mut self) {
+        match &mut self.inner {
+            StreamInner::Encode(enc) => enc.reset(),
+            StreamInner::Decode(dec) => dec.reset(),
+        }
+        self.total_in = 0;
+        self.total_out = 0;
+    }
+
+    /// Total number of bytes consumed so far.
+    pub fn total_in(&self) -> usize {
+        self.total_in
+    }
+
+    /// Total number of bytes produced so far.
+    pub fn total_out(&self) -> usize {
+        self.total_out
+    }
+
+    /// Compresses `input` into `output`, returns `(consumed, produced,
+    /// status)`.
+    pub fn compress(
+        &mut self,
+        input: &[u8],
+        output: &mut [u8],
+        flush: Flush,
+    ) -> Result<(usize, usize, Status)> {
+        let (consumed, produced, status) = match &mut self.inner {
+            StreamInner::Encode(enc) => enc.compress(input, output, flush)?,
+            StreamInner::Decode(_) => return Err(ErrorKind::InvalidInput.into()),
+        };
+        self.total_in += consumed;
+        self.total_out += produced;
+        Ok((consumed, produced, status))
+    }
+
+    /// Decompresses `input` into `output`, returns `(consumed, produced,
+    /// status)`.
+    pub fn decompress(
+        &mut self,
+        input: &[u8],
+        output: &mut [u8],
+        flush: Flush,
+    ) -> Result<(usize, usize, Status)> {
+        let (consumed, produced, status) = match &mut self.inner {
+            StreamInner::Decode(dec) => dec.decompress(input, output, flush)?,
+            StreamInner::Encode(_) => return Err(ErrorKind::InvalidInput.into()),
+        };
+        self.total_in += consumed;
+        self.total_out += produced;
+        Ok((consumed, produced, status))
+    }
+
+    /// Adds a skippable metadata frame with a user tag to an encoding stream.
+    ///
+    /// Metadata added before compression starts is written in front of the
+    /// compressed data, otherwise it is written after it when finishing. The
+    /// compressed data is not affected and decoders skip the frame unless a
+    /// callback is set by [`OrzStream::set_metadata_callback`].
+    pub fn add_metadata(&mut self, tag: u32, payload: &[u8]) -> Result<()> {
+        match &mut self.inner {
+            StreamInner::Encode(enc) if !enc.finished => {
+                enc.metadata.push((tag, payload.to_vec()));
+            }
+            _ => return Err(ErrorKind::InvalidInput.into()),
+        }
+        Ok(())
+    }
+
+    /// Stores attributes of the original file in the header of an encoding
+    /// stream, must be called before compression starts.
+    ///
+    /// If `info.size` is set, the stream fails to finish when the input size
+    /// does not match.
+    pub fn set_file_info(&mut self, info: FileInfo) -> Result<()> {
+        match &mut self.inner {
+            StreamInner::Encode(enc) if !enc.started => enc.file_info = Some(info),
+            _ => return Err(ErrorKind::InvalidInput.into()),
+        }
+        Ok(())
+    }
+
+    /// Stores a reordering of the input in the header of an encoding stream,
+    /// the input must already be reordered.
+    pub(crate) fn set_reordering(&mut self, reordering: Reordering) -> Result<()> {
+        match &mut self.inner {
+            StreamInner::Encode(enc) if !enc.started => enc.reordering = Some(reordering),
+            _ => return Err(ErrorKind::InvalidInput.into()),
+        }
+        Ok(())
+    }
+
+    /// Applies a filter to the input of an encoding stream, must be called
+    /// before compression starts.
+    ///
+    /// Branch filters hold back up to 4 bytes until more input follows, which
+    /// are not emitted by a sync flush.
+    pub fn set_filter(&mut self, filter: Filter) -> Result<()> {
+        match &mut self.inner {
+            StreamInner::Encode(enc) if !enc.started => {
+                filter.check()?;
+                enc.filter = Some(FilterCoder::new(filter));
+            }
+            _ => return Err(ErrorKind::InvalidInput.into()),
+        }
+        Ok(())
+    }
+
+    /// Attributes of the original file, as set when encoding or read from the
+    /// header of the current data frame when decoding.
+    pub fn file_info(&self) -> Option<&FileInfo> {
+        match &self.inner {
+            StreamInner::Decode(dec) => dec.file_info.as_ref(),
+            StreamInner::Encode(enc) => enc.file_info.as_ref(),
+        }
+    }
+
+    /// Sets a callback receiving the tag and payload of every metadata frame
+    /// met by a decoding stream.
+    pub fn set_metadata_callback(
+        &mut self,
+        callback: impl FnMut(u32, &[u8]) + Send + 'static,
+    ) -> Result<()> {
+        match &mut self.inner {
+            StreamInner::Decode(dec) => dec.metadata_callback = Some(Box::new(callback)),
+            StreamInner::Encode(_) => return Err(ErrorKind::InvalidInput.into()),
+        }
+        Ok(())
+    }
+
+    /// Makes a decoding stream fail on trailing data after the last frame,
+    /// which is silently dropped by default.
+    pub fn set_reject_trailing_garbage(&mut self, reject: bool) -> Result<()> {
+        match &mut self.inner {
+            StreamInner::Decode(dec) => dec.reject_trailing_garbage = reject,
+            StreamInner::Encode(_) => return Err(ErrorKind::InvalidInput.into()),
+        }
+        Ok(())
+    }
+
+    /// Number of window blocks completely processed so far.
+    pub(crate) fn num_blocks(&self) -> usize {
+        match &self.inner {
+            StreamInner::Encode(enc) => enc.num_blocks,
+            StreamInner::Decode(dec) => dec.num_blocks,
+        }
+    }
+
+    /// Makes every call return after processing a chunk, so progress can be
+    /// reported per chunk. Sync flushes are never interrupted.
+    pub(crate) fn set_return_per_chunk(&mut self, return_per_chunk: bool) {
+        match &mut self.inner {
+            StreamInner::Encode(enc) => enc.return_per_chunk = return_per_chunk,
+            StreamInner::Decode(dec) => dec.return_per_chunk = return_per_chunk,
+        }
+    }
+
+    /// Number of bytes of the chunks encoded or decoded so far, counted after
+    /// filtering.
+    pub(crate) fn num_filtered_bytes(&self) -> usize {
+        match &self.inner {
+            StreamInner::Encode(enc) => enc.num_encoded_bytes,
+            StreamInner::Decode(dec) => dec.num_decoded_bytes,
+        }
+    }
+
+    /// Number of chunks encoded or decoded so far.
+    pub fn num_chunks(&self) -> usize {
+        match &self.inner {
+            StreamInner::Encode(enc) => enc.num_chunks,
+            StreamInner::Decode(dec) => dec.num_chunks,
+        }
+    }
+}
+
+// how the chunks of a run of segments are encoded
+#[derive(Clone, Copy, PartialEq)]
+enum ChunkEncoding {
+    Lz,
+    Stored,
+    Filled(u8),
+}
+
+struct StreamEncoder {
+    cfg: LZCfg,
+    lzenc: LZEncoder,
+    sbvec_buf: Vec<u8>,
+    tbvec: Vec<u8>,
+    hbuf: Vec<u8>,
+    hbuf_pos: usize,
+    sbvec_end: usize,
+    spos: usize,
+    // encoding chosen for the data ahead, up to where the choice changes
+    chunk_encoding: ChunkEncoding,
+    chunk_encoding_end: usize,
+    classifier: Classifier,
+    // context of the current block, chosen at its first encoded chunk
+    context: Option<ContextHash>,
+    pending_pos: usize,
+    pending_end: usize,
+    metadata: Vec<(u32, Vec<u8>)>,
+    file_info: Option<FileInfo>,
+    reordering: Option<Reordering>,
+    filter: Option<FilterCoder>,
+    // input waiting to be filtered, and filtered data waiting to be encoded
+    filter_buf: Vec<u8>,
+    filtered: Vec<u8>,
+    filtered_pos: usize,
+    total_in: u64,
+    num_encoded_bytes: usize,
+    num_blocks: usize,
+    num_chunks: usize,
+    return_per_chunk: bool,
+    started: bool,
+    finished: bool,
+}
+
+impl StreamEncoder {
+    fn new(cfg: &LZCfg) -> Result<Self> {
+        cfg.validate()?;
+        Ok(Self::with_buffers(
+            cfg,
+            vec![0u8; LZ_BLOCK_SIZE + SBVEC_SENTINEL_LEN * 2],
+            vec![0u8; CHUNK_PREFIX_SIZE + SBVEC_PREMATCH_LEN * 3],
+        ))
+    }
+
+    fn with_buffers(cfg: &LZCfg, sbvec_buf: Vec<u8>, tbvec: Vec<u8>) -> Self {
+        Self {
+            cfg: *cfg,
+            lzenc: LZEncoder::new(cfg.bucket_size),
+            sbvec_buf,
+            tbvec,
+            hbuf: vec![],
+            hbuf_pos: 0,
+            sbvec_end: SBVEC_PREMATCH_LEN,
+            spos: SBVEC_PREMATCH_LEN,
+            chunk_encoding: ChunkEncoding::Stored,
+            chunk_encoding_end: 0,
+            classifier: Classifier::default(),
+            context: None,
+            pending_pos: 0,
+            pending_end: 0,
+            metadata: vec![],
+            file_info: None,
+            reordering: None,
+            filter: None,
+            filter_buf: vec![],
+            filtered: vec![],
+            filtered_pos: 0,
+            total_in: 0,
+            num_encoded_bytes: 0,
+            num_blocks: 0,
+            num_chunks: 0,
+            return_per_chunk: false,
+            started: false,
+            finished: false,
+        }
+    }
+
+    fn reset(&mut self) {
+        // the prematch area must be zeroed like on the decoder side
+        let mut sbvec_buf = std::mem::take(&mut self.sbvec_buf);
+        sbvec_buf[..SBVEC_SENTINEL_LEN + SBVEC_PREMATCH_LEN].fill(0);
+        *self = Self::with_buffers(&self.cfg, sbvec_buf, std::mem::take(&mut self.tbvec));
+    }
+
+    fn compress(
+        &mut self,
+        input: &[u8],
+        output: &mut [u8],
+        flush: Flush,
+    ) -> Result<(usize, usize, Status)> {
+        let (mut ipos, mut opos) = (0, 0);
+        if !self.started {
+            self.write_metadata_frames();
+            let header = FrameHeader {
+                version: FORMAT_VERSION,
+                file_info: self.file_info.clone(),
+                lz_cfg: Some(self.cfg),
+                reordering: self.reordering.clone(),
+                filter: self.filter.as_ref().map(|coder| coder.filter()),
+                chunk_checksum: true,
+                chunk_mode: true,
+                bucket_size: Some(self.cfg.bucket_size)
+                    .filter(|&bucket_size| bucket_size != LZ_MF_BUCKET_ITEM_SIZE),
+            };
+            header.write(&mut self.hbuf).unwrap();
+            self.started = true;
+        }
+        loop {
+            // drain pending output
+            if self.hbuf_pos < self.hbuf.len() {
+                let n = (self.hbuf.len() - self.hbuf_pos).min(output.len() - opos);
+                output[opos..][..n].copy_from_slice(&self.hbuf[self.hbuf_pos..][..n]);
+                self.hbuf_pos += n;
+                opos += n;
+                if self.hbuf_pos < self.hbuf.len() {
+                    return Ok((ipos, opos, Status::Ok));
+                }
+                self.hbuf.clear();
+                self.hbuf_pos = 0;
+            }
+            if self.pending_pos < self.pending_end {
+                let n = (self.pending_end - self.pending_pos).min(output.len() - opos);
+                output[opos..][..n].copy_from_slice(&self.tbvec[self.pending_pos..][..n]);
+                self.pending_pos += n;
+                opos += n;
+                if self.pending_pos < self.pending_end
+                    || self.return_per_chunk && flush != Flush::Sync
+                {
+                    return Ok((ipos, opos, Status::Ok));
+                }
+            }
+            if self.finished {
+                return Ok((ipos, opos, Status::StreamEnd));
+            }
+
+            // encode next chunk of a full block, or of the partial block when flushing
+            let block_full = self.sbvec_end == LZ_BLOCK_SIZE;
+            let flushing = flush != Flush::None && ipos == input.len();
+            let finishing = flush == Flush::Finish && ipos == input.len();
+            if self.spos < self.sbvec_end && (block_full || flushing) {
+                self.encode_chunk();
+                continue;
+            }
+
+            // move the encoded block into the prematch area
+            if block_full {
+                let sbvec = &mut self.sbvec_buf[SBVEC_SENTINEL_LEN..][..LZ_BLOCK_SIZE];
+                sbvec.copy_within(sbvec.len() - SBVEC_PREMATCH_LEN..sbvec.len(), 0);
+                self.lzenc.forward(sbvec.len() - SBVEC_PREMATCH_LEN); // reset orz_lz encoder
+                self.sbvec_end = SBVEC_PREMATCH_LEN;
+                self.spos = SBVEC_PREMATCH_LEN;
+                self.chunk_encoding_end = 0;
+                self.classifier.clear();
+                self.context = None;
+                self.num_blocks += 1;
+                return Ok((ipos, opos, Status::Ok)); // return at block boundary for progress logging
+            }
+
+            // fill input into the current block through the filter
+            if let Some(filter) = &mut self.filter {
+                if self.filtered_pos == self.filtered.len() {
+                    let n = LZ_CHUNK_SIZE.saturating_sub(self.filter_buf.len());
+                    let n = n.min(input.len() - ipos);
+                    self.filter_buf.extend_from_slice(&input[ipos..][..n]);
+                    self.total_in += n as u64;
+                    ipos += n;
+
+                    let flush = if ipos == input.len() {
+                        flush
+                    } else {
+                        Flush::None
+                    };
+                    self.filtered.clear();
+                    self.filtered_pos = 0;
+                    let n = filter.encode(&self.filter_buf, flush, &mut self.filtered);
+                    self.filter_buf.drain(..n);
+                }
+
+                let n = self.filtered.len() - self.filtered_pos;
+                let n = (LZ_BLOCK_SIZE - self.sbvec_end).min(n);
+                self.sbvec_buf[SBVEC_SENTINEL_LEN + self.sbvec_end..][..n]
+                    .copy_from_slice(&self.filtered[self.filtered_pos..][..n]);
+                self.filtered_pos += n;
+                self.sbvec_end += n;
+                if n > 0 {
+                    continue;
+                }
+            } else if ipos < input.len() {
+                let n = (LZ_BLOCK_SIZE - self.sbvec_end).min(input.len() - ipos);
+                self.sbvec_buf[SBVEC_SENTINEL_LEN + self.sbvec_end..][..n]
+                    .copy_from_slice(&input[ipos..][..n]);
+                self.sbvec_end += n;
+                self.total_in += n as u64;
+                ipos += n;
+                continue;
+            }
+
+            // write an empty chunk to mark eof, followed by metadata added during encoding
+            if finishing {
+                if let Some(size) = self.file_info.as_ref().and_then(|info| info.size)
+                    && size != self.total_in
+                {
+                    let msg = "input size does not match file info";
+                    return Err(Error::new(ErrorKind::InvalidInput, msg));
+                }
+                if self.sbvec_end > SBVEC_PREMATCH_LEN {
+                    self.num_blocks += 1;
+                }
+                self.hbuf.write_len(0).unwrap();
+                self.write_metadata_frames();
+                self.finished = true;
+                continue;
+            }
+            return Ok((ipos, opos, Status::Ok));
+        }
+    }
+
+    fn write_metadata_frames(&mut self) {
+        for (tag, payload) in std::mem::take(&mut self.metadata) {
+            write_metadata_frame(&mut self.hbuf, tag, &payload).unwrap();
+        }
+    }
+
+    /// Classifies the data ahead segment by segment, until the encoding chosen
+    /// for it changes.
+    fn classify(&mut self) {
+        let sbvec = &self.sbvec_buf[SBVEC_SENTINEL_LEN..][..self.sbvec_end];
+        let segment_end = |pos: usize| (pos + SEGMENT_LEN).min(sbvec.len());
+        let fill_len = |pos: usize, b: u8, max_len: usize| {
+            sbvec[pos..]
+                .iter()
+                .take(max_len)
+                .take_while(|&&c| c == b)
+                .count()
+        };
+        let classifier = &mut self.classifier;
+        let mut segment_encoding =
+            |pos: usize| match classifier.classify(sbvec, pos, segment_end(pos)) {
+                // shorter runs cost less as long matches than splitting the chunks around them
+                DataClass::Filled(b) if fill_len(pos, b, LZ_CHUNK_SIZE) == LZ_CHUNK_SIZE => {
+                    ChunkEncoding::Filled(b)
+                }
+                DataClass::Compressed => ChunkEncoding::Stored,
+                _ => ChunkEncoding::Lz,
+            };
+        let is_lz = |encoding: ChunkEncoding| encoding == ChunkEncoding::Lz;
+
+        let mut chunk_encoding = segment_encoding(self.spos);
+        if let ChunkEncoding::Filled(b) = chunk_encoding {
+            self.chunk_encoding = chunk_encoding;
+            self.chunk_encoding_end = self.spos + fill_len(self.spos, b, usize::MAX);
+            return;
+        }
+
+        // a lone segment is not worth a chunk of its own, so it joins a neighboring
+        // run if that run is LZ-encoded
+        let mut end = segment_end(self.spos);
+        if end < sbvec.len() {
+            let next_encoding = segment_encoding(end);
+            if next_encoding != chunk_encoding && is_lz(next_encoding) {
+                chunk_encoding = next_encoding;
+            }
+        }
+        while end < sbvec.len() {
+            let next = segment_end(end);
+            let encoding = segment_encoding(end);
+            if encoding != chunk_encoding
+                && (!is_lz(chunk_encoding)
+                    || matches!(encoding, ChunkEncoding::Filled(_))
+                    || next < sbvec.len() && segment_encoding(next) == encoding)
+            {
+                break;
+            }
+            end = next;
+        }
+        self.chunk_encoding = chunk_encoding;
+        self.chunk_encoding_end = end;
+    }
+
+    fn encode_chunk(&mut self) {
+        if self.spos >= self.chunk_encoding_end {
+            self.classify();
+        }
+        let sbvec = &self.sbvec_buf[SBVEC_SENTINEL_LEN..][..self.chunk_encoding_end];
+        let tbvec = &mut self.tbvec[CHUNK_PREFIX_SIZE..];
+        let (mode, s, t) = match self.chunk_encoding {
+            ChunkEncoding::Lz => {
+                let context = *self
+                    .context
+                    .get_or_insert_with(|| ContextHash::choose(&sbvec[self.spos..]));
+                let (s, t) = self
+                    .lzenc
+                    .encode(&self.cfg, context, sbvec, tbvec, self.spos);
+                (ChunkMode::Lz(context), s, t)
+            }
+            ChunkEncoding::Stored => {
+                // stored data is skipped by the contexts on both sides
+                let s = sbvec.len().min(self.spos + LZ_CHUNK_SIZE);
+                tbvec[..s - self.spos].copy_from_slice(&sbvec[self.spos..s]);
+                (ChunkMode::Stored, s, s - self.spos)
+            }
+            ChunkEncoding::Filled(b) => {
+                // skipped by the contexts like stored data
+                let s = sbvec.len().min(self.spos + LZ_CHUNK_SIZE);
+                (ChunkMode::Filled(b), s, 0)
+            }
+        };
+        let header = ChunkHeader {
+            mode: Some(mode),
+            decoded_len: s - self.spos,
+            checksum: Some(crc32(&sbvec[self.spos..s])),
+        };
+        self.num_encoded_bytes += s - self.spos;
+        self.spos = s;
+        self.num_chunks += 1;
+
+        // prepend chunk length and header
+        let mut header_buf = Vec::with_capacity(MAX_CHUNK_HEADER_LEN);
+        header.write(&mut header_buf).unwrap();
+        let mut prefix = Vec::with_capacity(CHUNK_PREFIX_SIZE);
+        prefix.write_len(header_buf.len() + t).unwrap();
+        prefix.extend_from_slice(&header_buf);
+        self.pending_pos = CHUNK_PREFIX_SIZE - prefix.len();
+        self.pending_end = CHUNK_PREFIX_SIZE + t;
+        self.tbvec[self.pending_pos..CHUNK_PREFIX_SIZE].copy_from_slice(&prefix);
+    }
+}
+
+enum DecodeState {
+    FrameHeader,
+    ChunkLen { len: usize, factor: usize },
+    Chunk { len: usize, filled: usize },
+    Output { pos: usize, end: usize },
+    Unfilter { pos: usize, frame_end: bool },
+    Restore { pos: usize },
+    Metadata { tag: u32, len: usize, filled: usize },
+    TrailingGarbage,
+}
+
+struct StreamDecoder {
+    lzdec: LZDecoder,
+    sbvec_buf: Vec<u8>,
+    tbvec: Vec<u8>,
+    hbuf: Vec<u8>,
+    spos: usize,
+    state: DecodeState,
+    num_frames: usize,
+    num_data_frames: usize,
+    num_blocks: usize,
+    file_info: Option<FileInfo>,
+    reordering: Option<Reordering>,
+    reorder_buf: Vec<u8>,
+    filter: Option<FilterCoder>,
+    // decoded data waiting to be unfiltered, and unfiltered data waiting for output
+    filter_buf: Vec<u8>,
+    unfiltered: Vec<u8>,
+    // output length of the current frame
+    frame_len: u64,
+    chunk_checksum: bool,
+    chunk_mode: bool,
+    num_chunks: usize,
+    num_decoded_bytes: usize,
+    return_per_chunk: bool,
+    reject_trailing_garbage: bool,
+    // whether the stream has no frame header, see FrameHeader::legacy
+    legacy: bool,
+    metadata_callback: Option<MetadataCallback>,
+}
+
+impl StreamDecoder {
+    fn new() -> Self {
+        Self::with_buffers(
+            vec![0u8; LZ_BLOCK_SIZE * 2 + SBVEC_SENTINEL_LEN * 2],
+            vec![0u8; SBVEC_PREMATCH_LEN * 3],
+        )
+    }
+
+    fn with_buffers(sbvec_buf: Vec<u8>, tbvec: Vec<u8>) -> Self {
+        Self {
+            lzdec: LZDecoder::new(LZ_MF_BUCKET_ITEM_SIZE, FORMAT_VERSION),
+            sbvec_buf,
+            tbvec,
+            hbuf: vec![],
+            spos: SBVEC_PREMATCH_LEN,
+            state: DecodeState::FrameHeader,
+            num_frames: 0,
+            num_data_frames: 0,
+            num_blocks: 0,
+            file_info: None,
+            reordering: None,
+            reorder_buf: vec![],
+            filter: None,
+            filter_buf: vec![],
+            unfiltered: vec![],
+            frame_len: 0,
+            chunk_checksum: false,
+            chunk_mode: false,
+            num_chunks: 0,
+            num_decoded_bytes: 0,
+            return_per_chunk: false,
+            reject_trailing_garbage: false,
+            legacy: false,
+            metadata_callback: None,
+        }
+    }
+
+    fn reset(&mut self) {
+        let mut dec = Self::with_buffers(
+            std::mem::take(&mut self.sbvec_buf),
+            std::mem::take(&mut self.tbvec),
+        );
+        dec.sbvec_buf[..SBVEC_SENTINEL_LEN + SBVEC_PREMATCH_LEN].fill(0);
+        dec.reject_trailing_garbage = self.reject_trailing_garbage;
+        dec.metadata_callback = self.metadata_callback.take();
+        *self = dec;
+    }
+
+    /// Finishes a data frame after all its data is output, checking its size
+    /// and starting to restore the original order if it was reordered.
+    fn end_frame(&mut self) -> Result<DecodeState> {
+        if let Some(size) = self.file_info.as_ref().and_then(|info| info.size)
+            && size != self.frame_len
+        {
+            let msg = "decoded size does not match file info";
+            return Err(Error::new(ErrorKind::InvalidData, msg));
+        }
+        let Some(reordering) = &self.reordering else {
+            return Ok(DecodeState::FrameHeader);
+        };
+        if reordering.total_len() != self.reorder_buf.len() {
+            let msg = "decoded size does not match reordering";
+            return Err(Error::new(ErrorKind::InvalidData, msg));
+        }
+        self.reorder_buf = reordering.restore(&self.reorder_buf);
+        Ok(DecodeState::Restore { pos: 0 })
+    }
+
+    /// Resets the decoding context for a new frame.
+    fn start_data_frame(&mut self, header: FrameHeader) {
+        self.file_info = header.file_info;
+        self.reordering = header.reordering;
+        self.reorder_buf.clear();
+        self.filter = header.filter.map(FilterCoder::new);
+        self.filter_buf.clear();
+        self.unfiltered.clear();
+        self.chunk_checksum = header.chunk_checksum;
+        self.chunk_mode = header.chunk_mode;
+        self.legacy = false;
+        self.frame_len = 0;
+        let bucket_size = header.bucket_size.unwrap_or(LZ_MF_BUCKET_ITEM_SIZE);
+        if self.num_data_frames > 0
+            || self.lzdec.bucket_size() != bucket_size
+            || self.lzdec.version() != header.version
+        {
+            self.lzdec = LZDecoder::new(bucket_size, header.version);
+            self.sbvec_buf[..SBVEC_SENTINEL_LEN + SBVEC_PREMATCH_LEN].fill(0);
+            self.spos = SBVEC_PREMATCH_LEN;
+        }
+        self.num_data_frames += 1;
+    }
+
+    fn decompress(
+        &mut self,
+        input: &[u8],
+        output: &mut [u8],
+        flush: Flush,
+    ) -> Result<(usize, usize, Status)> {
+        let (mut ipos, mut opos) = (0, 0);
+        loop {
+            match self.state {
+                DecodeState::FrameHeader => {
+                    if ipos == input.len() {
+                        break;
+                    }
+                    let hbuf_len = self.hbuf.len();
+                    let n = (input.len() - ipos).min(MAX_HEADER_PROBE_LEN);
+                    self.hbuf.extend_from_slice(&input[ipos..][..n]);
+
+                    // anything other than a frame following the first frame is trailing garbage
+                    if !is_frame_magic_prefix(&self.hbuf) {
+                        if self.num_frames == 0 {
+                            // probed bytes of a partial magic are the start of the first chunk
+                            let probed = self.hbuf[..hbuf_len].to_vec();
+                            self.hbuf.clear();
+                            self.num_frames += 1;
+                            self.start_data_frame(FrameHeader::legacy());
+                            self.legacy = true;
+                            self.state = DecodeState::ChunkLen { len: 0, factor: 1 };
+                            self.decompress(&probed, &mut [], Flush::None)?;
+                            continue;
+                        }
+                        if self.reject_trailing_garbage {
+                            return Err(Error::new(ErrorKind::InvalidData, "trailing garbage"));
+                        }
+                        self.state = DecodeState::TrailingGarbage;
+                        continue;
+                    }
+                    let Some((frame, header_len)) = Frame::parse(&self.hbuf)? else {
+                        ipos += n;
+                        continue;
+                    };
+                    ipos += header_len - hbuf_len;
+                    self.hbuf.clear();
+                    self.num_frames += 1;
+                    self.state = match frame {
+                        Frame::Data(header) => {
+                            self.start_data_frame(*header);
+                            DecodeState::ChunkLen { len: 0, factor: 1 }
+                        }
+                        Frame::Metadata { tag, len } => DecodeState::Metadata {
+                            tag,
+                            len,
+                            filled: 0,
+                        },
+                    };
+                }
+                DecodeState::Metadata { tag, len, filled } => {
+                    let n = (len - filled).min(input.len() - ipos);
+                    if self.metadata_callback.is_some() {
+                        self.hbuf.extend_from_slice(&input[ipos..][..n]);
+                    }
+                    ipos += n;
+                    if filled + n < len {
+                        self.state = DecodeState::Metadata {
+                            tag,
+                            len,
+                            filled: filled + n,
+                        };
+                        break;
+                    }
+                    if let Some(callback) = &mut self.metadata_callback {
+                        callback(tag, &self.hbuf);
+                    }
+                    self.hbuf.clear();
+                    self.state = DecodeState::FrameHeader;
+                }
+                DecodeState::ChunkLen {
+                    mut len,
+                    mut factor,
+                } => {
+                    if ipos == input.len() {
+                        break;
+                    }
+                    let v = input[ipos];
+                    ipos += 1;
+                    if push_len_byte(&mut len, &mut factor, v)? {
+                        self.state = DecodeState::ChunkLen { len, factor };
+                        continue;
+                    }
+                    if len >= self.tbvec.len() {
+                        return Err(ErrorKind::InvalidData.into());
+                    }
+                    self.state = match len {
+                        0 => {
+                            if self.spos > SBVEC_PREMATCH_LEN {
+                                self.num_blocks += 1;
+                            }
+                            match &mut self.filter {
+                                Some(filter) => {
+                                    filter.decode(&self.filter_buf, true, &mut self.unfiltered)?;
+                                    self.filter_buf.clear();
+                                    DecodeState::Unfilter {
+                                        pos: 0,
+                                        frame_end: true,
+                                    }
+                                }
+                                None => self.end_frame()?,
+                            }
+                        }
+                        len => DecodeState::Chunk { len, filled: 0 },
+                    };
+                }
+                DecodeState::Chunk { len, filled } => {
+                    if ipos == input.len() {
+                        break;
+                    }
+                    let n = (len - filled).min(input.len() - ipos);
+                    self.tbvec[filled..][..n].copy_from_slice(&input[ipos..][..n]);
+                    ipos += n;
+                    if filled + n < len {
+                        self.state = DecodeState::Chunk {
+                            len,
+                            filled: filled + n,
+                        };
+                        continue;
+                    }
+
+                    let mut chunk = &self.tbvec[..len];
+                    let header = match self.legacy {
+                        // legacy chunks decode up to the end of the block
+                        true => ChunkHeader {
+                            mode: None,
+                            decoded_len: LZ_BLOCK_SIZE - self.spos,
+                            checksum: None,
+                        },
+                        false => {
+                            ChunkHeader::read(&mut chunk, self.chunk_mode, self.chunk_checksum)
+                                .map_err(|_| chunk_error(self.num_chunks, "truncated chunk"))?
+                        }
+                    };
+                    if header.decoded_len > LZ_BLOCK_SIZE - self.spos {
+                        return Err(chunk_error(self.num_chunks, "invalid decoded length"));
+                    }
+                    let sbvec = &mut self.sbvec_buf[SBVEC_SENTINEL_LEN..][..LZ_BLOCK_SIZE];
+                    let spos_end = match header.mode {
+                        Some(ChunkMode::Stored) if chunk.len() == header.decoded_len => {
+                            sbvec[self.spos..][..chunk.len()].copy_from_slice(chunk);
+                            self.spos + chunk.len()
+                        }
+                        Some(ChunkMode::Stored) => {
+                            return Err(chunk_error(self.num_chunks, "decoded length mismatch"));
+                        }
+                        Some(ChunkMode::Filled(b)) if chunk.is_empty() => {
+                            sbvec[self.spos..][..header.decoded_len].fill(b);
+                            self.spos + header.decoded_len
+                        }
+                        Some(ChunkMode::Filled(_)) => {
+                            return Err(chunk_error(self.num_chunks, "invalid filled chunk"));
+                        }
+                        mode => {
+                            let context = match mode {
+                                Some(ChunkMode::Lz(context)) => context,
+                                _ => ContextHash::Text,
+                            };
+                            self.lzdec
+                                .decode(context, chunk, sbvec, self.spos)
+                                .map_err(|e| chunk_error(self.num_chunks, e))?
+                        }
+                    };
+                    if !self.legacy && spos_end - self.spos != header.decoded_len {
+                        return Err(chunk_error(self.num_chunks, "decoded length mismatch"));
+                    }
+                    let decoded = &sbvec[self.spos..spos_end];
+                    if header
+                        .checksum
+                        .is_some_and(|checksum| checksum != crc32(decoded))
+                    {
+                        return Err(chunk_error(self.num_chunks, "checksum mismatch"));
+                    }
+                    self.num_chunks += 1;
+                    self.num_decoded_bytes += spos_end - self.spos;
+                    self.state = DecodeState::Output {
+                        pos: self.spos,
+                        end: spos_end,
+                    };
+                    if self.filter.is_none() {
+                        self.frame_len += (spos_end - self.spos) as u64;
+                    }
+                    self.spos = spos_end;
+                }
+                DecodeState::Output { pos, end } => {
+                    let sbvec = &mut self.sbvec_buf[SBVEC_SENTINEL_LEN..][..LZ_BLOCK_SIZE];
+                    let next_state = if let Some(filter) = &mut self.filter {
+                        // the window keeps filtered data, so a copy is unfiltered
+                        self.filter_buf.extend_from_slice(&sbvec[pos..end]);
+                        let n = filter.decode(&self.filter_buf, false, &mut self.unfiltered)?;
+                        self.filter_buf.drain(..n);
+                        DecodeState::Unfilter {
+                            pos: 0,
+                            frame_end: false,
+                        }
+                    } else {
+                        let reorder_buf = self.reordering.as_ref();
+                        let reorder_buf =
+                            reorder_buf.map(|r| (&mut self.reorder_buf, r.total_len()));
+                        let n = emit(&sbvec[pos..end], output, &mut opos, reorder_buf)?;
+                        if pos + n < end {
+                            self.state = DecodeState::Output { pos: pos + n, end };
+                            break;
+                        }
+                        DecodeState::ChunkLen { len: 0, factor: 1 }
+                    };
+
+                    self.state = next_state;
+                    if self.spos >= LZ_BLOCK_SIZE {
+                        sbvec.copy_within(sbvec.len() - SBVEC_PREMATCH_LEN..sbvec.len(), 0);
+                        self.lzdec.forward(sbvec.len() - SBVEC_PREMATCH_LEN);
+                        self.spos = SBVEC_PREMATCH_LEN;
+                        self.num_blocks += 1;
+                        break; // return at block boundary for progress logging
+                    }
+                    if self.return_per_chunk {
+                        break;
+                    }
+                }
+                DecodeState::Unfilter { pos, frame_end } => {
+                    let reorder_buf = self.reordering.as_ref();
+                    let reorder_buf = reorder_buf.map(|r| (&mut self.reorder_buf, r.total_len()));
+                    let data = &self.unfiltered[pos..];
+                    let n = emit(data, output, &mut opos, reorder_buf)?;
+                    if pos + n < self.unfiltered.len() {
+                        self.state = DecodeState::Unfilter {
+                            pos: pos + n,
+                            frame_end,
+                        };
+                        break;
+                    }
+                    self.frame_len += self.unfiltered.len() as u64;
+                    self.unfiltered.clear();
+                    self.state = match frame_end {
+                        true => self.end_frame()?,
+                        false => DecodeState::ChunkLen { len: 0, factor: 1 },
+                    };
+                }
+                DecodeState::Restore { pos } => {
+                    let n = (self.reorder_buf.len() - pos).min(output.len() - opos);
+                    output[opos..][..n].copy_from_slice(&self.reorder_buf[pos..][..n]);
+                    opos += n;
+                    if pos + n < self.reorder_buf.len() {
+                        self.state = DecodeState::Restore { pos: pos + n };
+                        break;
+                    }
+                    self.reorder_buf = vec![];
+                    self.state = DecodeState::FrameHeader;
+                }
+                DecodeState::TrailingGarbage => {
+                    self.hbuf.clear();
+                    ipos = input.len();
+                    break;
+                }
+            }
+        }
+
+        if flush == Flush::Finish && ipos == input.len() {
+            match self.state {
+                DecodeState::FrameHeader if self.num_frames > 0 && self.hbuf.is_empty() => {
+                    return Ok((ipos, opos, Status::StreamEnd));
+                }
+                DecodeState::FrameHeader
+                    if self.num_frames > 0 && self.hbuf.len() < FRAME_MAGIC.len() =>
+                {
+                    // an incomplete magic is trailing garbage, not a truncated frame
+                    if self.reject_trailing_garbage {
+                        return Err(Error::new(ErrorKind::InvalidData, "trailing garbage"));
+                    }
+                    return Ok((ipos, opos, Status::StreamEnd));
+                }
+                DecodeState::TrailingGarbage => return Ok((ipos, opos, Status::StreamEnd)),
+                DecodeState::Output { .. }
+                | DecodeState::Unfilter { .. }
+                | DecodeState::Restore { .. } => {}
+                _ => return Err(ErrorKind::UnexpectedEof.into()),
+            }
+        }
+        Ok((ipos, opos, Status::Ok))
+    }
+}
+
+/// Copies decoded data to output, or buffers it all if the frame is reordered
+/// (up to the total length of the reordering), returns the number of bytes
+/// taken.
+fn emit(
+    data: &[u8],
+    output: &mut [u8],
+    opos: &mut usize,
+    reorder_buf: Option<(&mut Vec<u8>, usize)>,
+) -> Result<usize> {
+    if let Some((reorder_buf, total_len)) = reorder_buf {
+        // reordered data is buffered until the whole frame is decoded
+        if reorder_buf.len() + data.len() > total_len {
+            let msg = "decoded size does not match reordering";
+            return Err(Error::new(ErrorKind::InvalidData, msg));
+        }
+        reorder_buf.extend_from_slice(data);
+        return Ok(data.len());
+    }
+    let n = data.len().min(output.len() - *opos);
+    output[*opos..][..n].copy_from_slice(&data[..n]);
+    *opos += n;
+    Ok(n)
+}
+
+fn chunk_error(index: usize, e: impl Display) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("chunk {}: {}", index, e))
+}
+
+/// An incremental encoder compressing written data into an inner writer.
+///
+/// [`Write::flush`] emits everything written so far (see [`Flush::Sync`])
+/// without ending the stream, [`OrzWriter::finish`] must be called to end it.
+pub struct OrzWriter<W: Write> {
+    inner: W,
+    stream: OrzStream,
+    obuf: Vec<u8>,
+}
+
+impl<W: Write> OrzWriter<W> {
+    /// Fails if the matching options are not valid.
+    pub fn new(inner: W, cfg: &LZCfg) -> Result<Self> {
+        Ok(Self {
+            inner,
+            stream: OrzStream::encoder(cfg)?,
+            obuf: vec![0u8; LZ_CHUNK_SIZE],
+        })
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    /// The underlying stream, for inspecting its counters.
+    pub fn stream(&self) -> &OrzStream {
+        &self.stream
+    }
+
+    /// Ends the stream and returns the inner writer.
+    pub fn finish(mut self) -> Result<W> {
+        self.process(&[], Flush::Finish)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+
+    fn process(&mut self, input: &[u8], flush: Flush) -> Result<()> {
+        let mut ipos = 0;
+        loop {
+            let (consumed, produced, status) =
+                self.stream
+                    .compress(&input[ipos..], &mut self.obuf, flush)?;
+            self.inner.write_all(&self.obuf[..produced])?;
+            ipos += consumed;
+
+            let stalled = ipos == input.len() && produced < self.obuf.len();
+            if status == Status::StreamEnd || stalled && flush != Flush::Finish {
+                return Ok(());
+            }
+        }
+    }
+}
+
+impl<W: Write> Write for OrzWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.process(buf, Flush::None)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.process(&[], Flush::Sync)?;
+        self.inner.flush()
+    }
+}
+
+/// An incremental decoder decompressing data read from an inner reader.
+///
+/// Like [`OrzStream::decoder`], it buffers reordered frames whole.
+pub struct OrzReader<R: Read> {
+    inner: R,
+    stream: OrzStream,
+    ibuf: Vec<u8>,
+    ipos: usize,
+    iend: usize,
+    inner_eof: bool,
+    finished: bool,
+}
+
+impl<R: Read> OrzReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self::with_stream(inner, OrzStream::decoder())
+    }
+
+    /// Creates a reader with a configured decoding stream.
+    pub fn with_stream(inner: R, stream: OrzStream) -> Self {
+        Self {
+            inner,
+            stream,
+            ibuf: vec![0u8; LZ_CHUNK_SIZE],
+            ipos: 0,
+            iend: 0,
+            inner_eof: false,
+            finished: false,
+        }
+    }
+
+    /// The underlying stream, for inspecting its counters.
+    pub fn stream(&self) -> &OrzStream {
+        &self.stream
+    }
+}
+
+impl<R: Read> Read for OrzReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        while !buf.is_empty() && !self.finished {
+            if self.ipos == self.iend && !self.inner_eof {
+                self.iend = self.inner.read(&mut self.ibuf)?;
+                self.ipos = 0;
+                self.inner_eof = self.iend == 0;
+            }
+            let flush = if self.inner_eof {
+                Flush::Finish
+            } else {
+                Flush::None
+            };
+            let (consumed, produced, status) =
+                self.stream
+                    .decompress(&self.ibuf[self.ipos..self.iend], buf, flush)?;
+            self.ipos += consumed;
+            self.finished = status == Status::StreamEnd;
+            if produced > 0 {
+                return Ok(produced);
+            }
+        }
+        Ok(0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        StreamInfo,
+        testutil::{random_bytes, roundtrip},
+    };
+
+    #[test]
+    fn test_stream_with_small_buffers() {
+        let input = (0..200000u32)
+            .flat_map(|i| format!("{} {} ", i % 997, i / 13).into_bytes())
+            .collect::<Vec<_>>();
+
+        // compress with tiny input/output pieces
+        let mut stream = OrzStream::encoder(&LZCfg::new(5, 3, 2)).unwrap();
+        let mut encoded = vec![];
+        let mut ipos = 0;
+        loop {
+            let mut obuf = [0u8; 777];
+            let iend = (ipos + 1000).min(input.len());
+            let flush = if iend == input.len() {
+                Flush::Finish
+            } else {
+                Flush::None
+            };
+            let (c, p, status) = stream
+                .compress(&input[ipos..iend], &mut obuf, flush)
+                .unwrap();
+            ipos += c;
+            encoded.extend_from_slice(&obuf[..p]);
+            if status == Status::StreamEnd {
+                break;
+            }
+        }
+        assert_eq!(stream.total_in(), input.len());
+        assert_eq!(stream.total_out(), encoded.len());
+
+        // decompress byte by byte
+        let mut stream = OrzStream::decoder();
+        let mut decoded = vec![];
+        let mut ipos = 0;
+        loop {
+            let mut obuf = [0u8; 333];
+            let iend = (ipos + 1).min(encoded.len());
+            let flush = if iend == encoded.len() {
+                Flush::Finish
+            } else {
+                Flush::None
+            };
+            let (c, p, status) = stream
+                .decompress(&encoded[ipos..iend], &mut obuf, flush)
+                .unwrap();
+            ipos += c;
+            decoded.extend_from_slice(&obuf[..p]);
+            if status == Status::StreamEnd {
+                break;
+            }
+        }
+        assert_eq!(ipos, encoded.len());
+        assert!(decoded == input);
+    }
+
+    #[test]
+    fn test_concatenated_frames() {
+        let encode = |input: &[u8]| roundtrip(&LZCfg::new(5, 3, 2), input);
+        let decode = |encoded: &[u8], reject_trailing_garbage| {
+            let mut stream = OrzStream::decoder();
+            stream
+                .set_reject_trailing_garbage(reject_trailing_garbage)
+                .unwrap();
+            let mut obuf = vec![0u8; 1000];
+            let (c, p, status) = stream.decompress(encoded, &mut obuf, Flush::Finish)?;
+            assert_eq!((c, status), (encoded.len(), Status::StreamEnd));
+            Result::Ok(obuf[..p].to_vec())
+        };
+
+        let mut encoded = [encode(b"hello, "), encode(b""), encode(b"world!")].concat();
+        assert_eq!(decode(&encoded, true).unwrap(), b"hello, world!");
+
+        encoded.extend_from_slice(b"garbage");
+        assert_eq!(decode(&encoded, false).unwrap(), b"hello, world!");
+        assert!(decode(&encoded, true).is_err());
+        assert!(decode(b"garbage", false).is_err());
+    }
+
+    #[test]
+    fn test_filter() {
+        // code calling a few functions from everywhere
+        let input = (0..500000u32)
+            .flat_map(|i| {
+                let target = [0x1000u32, 0x2345, 0x8000][i as usize % 3];
+                let rel = target.wrapping_sub(i * 7 + 5);
+                [[0xe8].as_slice(), &rel.to_le_bytes(), &[0x90, i as u8]].concat()
+            })
+            .collect::<Vec<_>>();
+        let encode = |filter: Option<Filter>| {
+            let mut stream = OrzStream::encoder(&LZCfg::new(5, 3, 2)).unwrap();
+            if let Some(filter) = filter {
+                stream.set_filter(filter).unwrap();
+            }
+            let mut encoded = vec![];
+            let mut ipos = 0;
+            loop {
+                let mut obuf = [0u8; 777];
+                let iend = (ipos + 1001).min(input.len());
+                let flush = match iend == input.len() {
+                    true => Flush::Finish,
+                    false => Flush::None,
+                };
+                let (c, p, status) = stream
+                    .compress(&input[ipos..iend], &mut obuf, flush)
+                    .unwrap();
+                ipos += c;
+                encoded.extend_from_slice(&obuf[..p]);
+                if status == Status::StreamEnd {
+                    return encoded;
+                }
+            }
+        };
+        let encoded = encode(Some(Filter::X86));
+        assert!(encoded.len() < encode(None).len());
+
+        let mut stream = OrzStream::decoder();
+        let mut decoded = vec![];
+        let mut ipos = 0;
+        loop {
+            let mut obuf = [0u8; 333];
+            let iend = (ipos + 3).min(encoded.len());
+            let flush = match iend == encoded.len() {
+                true => Flush::Finish,
+                false => Flush::None,
+            };
+            let (c, p, status) = stream
+                .decompress(&encoded[ipos..iend], &mut obuf, flush)
+                .unwrap();
+            ipos += c;
+            decoded.extend_from_slice(&obuf[..p]);
+            if status == Status::StreamEnd {
+                break;
+            }
+        }
+        assert!(decoded == input);
+    }
+
+    #[test]
+    fn test_text_filter_sync() {
+        let mut stream = OrzStream::encoder(&LZCfg::new(5, 3, 2)).unwrap();
+        stream.set_filter(Filter::Text).unwrap();
+        let mut obuf = vec![0u8; 10000];
+        let text = b"Hello World, hello world. ".repeat(20);
+        let (_, p, _) = stream.compress(&text, &mut obuf, Flush::Sync).unwrap();
+
+        // all text written before a sync flush is decodable
+        let mut stream = OrzStream::decoder();
+        let mut decoded = vec![0u8; 10000];
+        let (c, n, _) = stream
+            .decompress(&obuf[..p], &mut decoded, Flush::None)
+            .unwrap();
+        assert_eq!((c, &decoded[..n]), (p, &text[..]));
+    }
+
+    #[test]
+    fn test_stored_chunks() {
+        let random = random_bytes(300000);
+        let text = b"The quick brown fox jumps over the lazy dog. ".repeat(5000);
+        let input = [&text[..], &random, &text].concat();
+
+        let encoded = roundtrip(&LZCfg::new(5, 3, 2), &input);
+        let info = StreamInfo::read(&encoded[..]).unwrap();
+        assert_eq!(info.frames[0].blocks[0].num_stored_chunks, 1);
+        assert!(encoded.len() < random.len() * 101 / 100);
+    }
+
+    #[test]
+    fn test_context_hash() {
+        // fixed-width records of slowly changing fields
+        let input = (0..200000u32)
+            .flat_map(|i| {
+                let fields = [i.to_le_bytes(), (i / 7 % 50 * 3 + 1000).to_le_bytes()];
+                fields.concat()
+            })
+            .collect::<Vec<_>>();
+
+        let encoded = roundtrip(&LZCfg::new(5, 3, 2), &input);
+        let info = StreamInfo::read(&encoded[..]).unwrap();
+        assert_eq!(
+            info.frames[0].blocks[0].context,
+            Some(ContextHash::Column(8))
+        );
+    }
+
+    #[test]
+    fn test_bucket_size() {
+        let input = (0..100000u32)
+            .flat_map(|i| format!("{} ", i * 7919 % 10007).into_bytes())
+            .collect::<Vec<_>>();
+
+        // frames with different bucket sizes decode in one stream
+        let mut encoded = vec![];
+        let mut frame_lens = vec![];
+        for bucket_size in [1022, 65534, LZ_MF_BUCKET_ITEM_SIZE] {
+            let cfg = LZCfg::new(5, 3, 2).with_bucket_size(bucket_size);
+            let frame = roundtrip(&cfg, &input);
+            let info = StreamInfo::read(&frame[..]).unwrap();
+            assert_eq!(info.frames[0].lz_cfg, Some(cfg));
+            frame_lens.push(frame.len());
+            encoded.extend(frame);
+        }
+
+        // numbers repeat every 10007 numbers, further back than small buckets reach
+        assert!(frame_lens[1] < frame_lens[0] / 2);
+
+        let mut decoded = vec![];
+        OrzReader::new(&encoded[..])
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert!(decoded == input.repeat(3));
+    }
+
+    #[test]
+    fn test_invalid_lz_cfg() {
+        // encoders refuse invalid options instead of panicking
+        let invalid_cfg = LZCfg::new(5, 3, 2).with_lazy_match_lookahead(3);
+        let e = OrzStream::encoder(&invalid_cfg).err().unwrap();
+        assert_eq!(e.kind(), ErrorKind::InvalidInput);
+        let e = OrzWriter::new(vec![], &invalid_cfg).err().unwrap();
+        assert_eq!(e.kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_long_matches() {
+        let record = random_bytes(3000);
+        let input = [vec![0u8; 1 << 20], record.repeat(300), vec![0u8; 100000]].concat();
+
+        let encoded = roundtrip(&LZCfg::new(5, 3, 2), &input);
+        assert!(encoded.len() < record.len() + 1000);
+    }
+
+    #[test]
+    fn test_filled_chunks() {
+        let text = b"The quick brown fox jumps over the lazy dog. ".repeat(5000);
+        let input = [&text[..], &vec![0u8; 3 << 20], &[0xff; 100000], &text].concat();
+
+        let encoded = roundtrip(&LZCfg::new(5, 3, 2), &input);
+
+        // counted from the chunk modes written, the short 0xff run is coded with the
+        // text after it
+        let info = StreamInfo::read(&encoded[..]).unwrap();
+        assert_eq!(info.frames[0].blocks[0].num_filled_chunks, 3);
+        assert_eq!(info.frames[0].num_chunks, 5);
+    }
+
+    #[test]
+    fn test_version2_stream() {
+        // encoded before the repeated offset symbols were added
+        let encoded = [
+            0x89, 0x4f, 0x52, 0x5a, 0x02, 0x26, 0x05, 0x03, 0x02, 0x36, 0x00, 0x36, 0x1d, 0x4a,
+            0xcd, 0x17, 0xe4, 0x40, 0x65, 0x39, 0x1c, 0xce, 0xdd, 0xdf, 0x55, 0x55, 0x55, 0x55,
+            0x4d, 0xf2, 0xdd, 0x68, 0x72, 0xca, 0x32, 0xab, 0x72, 0x75, 0x4b, 0x6b, 0xff, 0x2f,
+            0xad, 0x2a, 0xae, 0xb5, 0x5d, 0x28, 0xdf, 0xa0, 0xbe, 0x29, 0x39, 0x61, 0xc2, 0x69,
+            0x31, 0x4c, 0x17, 0x8f, 0x80, 0x00, 0x00, 0x00, 0x00,
+        ];
+        let info = StreamInfo::read(&encoded[..]).unwrap();
+        assert_eq!(info.frames[0].version, 2);
+
+        let mut decoded = vec![];
+        OrzReader::new(&encoded[..])
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(
+            decoded,
+            b"version 2 stream, version 2 stream, version 2 stream.\n"
+        );
+    }
+
+    #[test]
+    fn test_legacy_stream() {
+        // encoded by orz 1.6.1, without frame header
+        let encoded = [
+            0x2c, 0xe4, 0x40, 0x2e, 0x18, 0x9b, 0xce, 0x5d, 0xdf, 0x55, 0x55, 0x55, 0x55, 0x4d,
+            0xf2, 0xc8, 0x52, 0x8a, 0x88, 0x97, 0x27, 0x54, 0x95, 0x27, 0xdc, 0x94, 0x95, 0x25,
+            0x22, 0x5a, 0x7d, 0xd4, 0x83, 0x7e, 0x82, 0x2f, 0x11, 0x88, 0x06, 0x33, 0x56, 0xa6,
+            0xdc, 0x93, 0xc6, 0x00,
+        ];
+        let expected = b"orz 1.6.1 stream, orz 1.6.1 stream, orz 1.6.1 stream.\n";
+
+        let mut decoded = vec![];
+        OrzReader::new(&encoded[..])
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, expected);
+
+        // fed byte by byte
+        let mut stream = OrzStream::decoder();
+        let mut decoded = vec![];
+        let mut obuf = vec![0u8; 1024];
+        for i in 0..encoded.len() {
+            let (c, p, _) = stream
+                .decompress(&encoded[i..][..1], &mut obuf, Flush::None)
+                .unwrap();
+            assert_eq!(c, 1);
+            decoded.extend_from_slice(&obuf[..p]);
+        }
+        let (_, p, status) = stream.decompress(&[], &mut obuf, Flush::Finish).unwrap();
+        decoded.extend_from_slice(&obuf[..p]);
+        assert_eq!((&decoded[..], status), (&expected[..], Status::StreamEnd));
+    }
+
+    #[test]
+    fn test_malformed_chunk_len() {
+        // a chunk length with too many continuation bytes, with and without a frame
+        // header
+        let encoded = roundtrip(&LZCfg::new(5, 3, 2), b"hello");
+        let (_, header_len) = Frame::parse(&encoded).unwrap().unwrap();
+        for header in [&[][..], &encoded[..header_len]] {
+            let input = [header, &[0xff; 32]].concat();
+            let mut obuf = vec![0u8; 1024];
+            let e = OrzStream::decoder()
+                .decompress(&input, &mut obuf, Flush::Finish)
+                .unwrap_err();
+            assert_eq!(e.kind(), ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn test_reset() {
+        let compress = |stream: &mut OrzStream, input: &[u8]| {
+            let mut obuf = vec![0u8; 1000];
+            let (_, p, _) = stream.compress(input, &mut obuf, Flush::Finish).unwrap();
+            obuf[..p].to_vec()
+        };
+        let mut stream = OrzStream::encoder(&LZCfg::new(5, 3, 2)).unwrap();
+        stream.set_file_info(FileInfo::default()).unwrap();
+        compress(&mut stream, b"first input, first input");
+        stream.reset();
+        let encoded = compress(&mut stream, b"second input");
+        assert_eq!(stream.total_in(), 12);
+        assert_eq!(
+            encoded,
+            compress(
+                &mut OrzStream::encoder(&LZCfg::new(5, 3, 2)).unwrap(),
+                b"second input"
+            )
+        );
+
+        let mut stream = OrzStream::decoder();
+        let mut obuf = vec![0u8; 1000];
+        stream
+            .decompress(b"garbage", &mut obuf, Flush::Finish)
+            .unwrap_err();
+        stream.reset();
+        let (_, p, status) = stream
+            .decompress(&encoded, &mut obuf, Flush::Finish)
+            .unwrap();
+        assert_eq!(
+            (&obuf[..p], status),
+            (&b"second input"[..], Status::StreamEnd)
+        );
+    }
+
+    #[test]
+    fn test_metadata_frames() {
+        let mut stream = OrzStream::encoder(&LZCfg::new(5, 3, 2)).unwrap();
+        stream.add_metadata(1, b"build-id").unwrap();
+        let mut obuf = vec![0u8; 1000];
+        let (_, p1, _) = stream.compress(b"payload", &mut obuf, Flush::None).unwrap();
+        stream.add_metadata(2, b"").unwrap();
+        let (_, p2, status) = stream
+            .compress(&[], &mut obuf[p1..], Flush::Finish)
+            .unwrap();
+        assert_eq!(status, Status::StreamEnd);
+        let encoded = &obuf[..p1 + p2];
+
+        let decode = |with_callback| {
+            let metadata = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+            let mut stream = OrzStream::decoder();
+            if with_callback {
+                let metadata = metadata.clone();
+                stream
+                    .set_metadata_callback(move |tag, payload| {
+                        metadata.lock().unwrap().push((tag, payload.to_vec()));
+                    })
+                    .unwrap();
+            }
+            let mut obuf = vec![0u8; 1000];
+            let (_, p, status) = stream
+                .decompress(encoded, &mut obuf, Flush::Finish)
+                .unwrap();
+            assert_eq!((&obuf[..p], status), (&b"payload"[..], Status::StreamEnd));
+            std::mem::take(&mut *metadata.lock().unwrap())
+        };
+        assert_eq!(decode(false), vec![]);
+        assert_eq!(decode(true), vec![(1, b"build-id".to_vec()), (2, vec![])]);
+    }
+
+    #[test]
+    fn test_file_info() {
+        let info = FileInfo {
+            name: Some("hello.txt".to_owned()),
+            size: Some(6),
+            mtime: Some(1700000000),
+            mode: Some(0o644),
+        };
+        let encode = |input: &[u8]| {
+            let mut stream = OrzStream::encoder(&LZCfg::new(5, 3, 2)).unwrap();
+            stream.set_file_info(info.clone()).unwrap();
+            let mut obuf = vec![0u8; 1000];
+            let (_, p, _) = stream.compress(input, &mut obuf, Flush::Finish)?;
+            Result::Ok(obuf[..p].to_vec())
+        };
+        assert!(encode(b"hello").is_err());
+        let encoded = encode(b"hello!").unwrap();
+
+        let mut stream = OrzStream::decoder();
+        let mut obuf = vec![0u8; 1000];
+        let (_, p, status) = stream
+            .decompress(&encoded, &mut obuf, Flush::Finish)
+            .unwrap();
+        assert_eq!((&obuf[..p], status), (&b"hello!"[..], Status::StreamEnd));
+        assert_eq!(stream.file_info(), Some(&info));
+    }
+
+    #[test]
+    fn test_writer_flush() {
+        let input = (0..100000u32)
+            .flat_map(|i| format!("{} {} ", i % 997, i / 13).into_bytes())
+            .collect::<Vec<_>>();
+
+        // every flushed part must be decodable without the following data
+        let mut writer = OrzWriter::new(vec![], &LZCfg::new(5, 3, 2)).unwrap();
+        let mut decoder = OrzStream::decoder();
+        let mut decoded = vec![];
+        let mut encoded_len = 0;
+        for piece in input.chunks(input.len() / 7) {
+            writer.write_all(piece).unwrap();
+            writer.flush().unwrap();
+
+            let encoded = &writer.get_ref()[encoded_len..];
+            let mut obuf = vec![0u8; input.len()];
+            let (c, p, status) = decoder.decompress(encoded, &mut obuf, Flush::None).unwrap();
+            assert_eq!((c, p, status), (encoded.len(), piece.len(), Status::Ok));
+            decoded.extend_from_slice(&obuf[..p]);
+            encoded_len += c;
+        }
+        let encoded = writer.finish().unwrap();
+        let mut obuf = [0u8; 1];
+        let (_, p, status) = decoder
+            .decompress(&encoded[encoded_len..], &mut obuf, Flush::Finish)
+            .unwrap();
+        assert_eq!((p, status), (0, Status::StreamEnd));
+        assert!(decoded == input);
+    }
+}
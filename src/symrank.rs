@@ -8,21 +8,23 @@ use std::hint::unlikely;
 
 use unchecked_index::UncheckedIndex;
 
-use crate::{SYMRANK_NUM_SYMBOLS, unchecked};
+use crate::{SYMRANK_MAX_NUM_SYMBOLS, unchecked};
 
 #[derive(Clone)]
 pub struct SymRankCoder {
-    value_array: UncheckedIndex<[u16; SYMRANK_NUM_SYMBOLS]>,
-    index_array: UncheckedIndex<[u16; SYMRANK_NUM_SYMBOLS]>,
+    value_array: UncheckedIndex<[u16; SYMRANK_MAX_NUM_SYMBOLS]>,
+    index_array: UncheckedIndex<[u16; SYMRANK_MAX_NUM_SYMBOLS]>,
+    num_symbols: u16,
     encoded_cnt: u32,
     encoded_idx_sum: u32,
 }
 
 impl SymRankCoder {
-    pub fn new() -> Self {
+    pub fn new(num_symbols: usize) -> Self {
         SymRankCoder {
-            value_array: unchecked!([0; SYMRANK_NUM_SYMBOLS]),
-            index_array: unchecked!([0; SYMRANK_NUM_SYMBOLS]),
+            value_array: unchecked!([0; SYMRANK_MAX_NUM_SYMBOLS]),
+            index_array: unchecked!([0; SYMRANK_MAX_NUM_SYMBOLS]),
+            num_symbols: num_symbols as u16,
             encoded_cnt: 0,
             encoded_idx_sum: 1000000, // for fast initialization
         }
@@ -41,14 +43,14 @@ impl SymRankCoder {
         self.update(v, i);
 
         if unlikely(i == iunlikely) {
-            return SYMRANK_NUM_SYMBOLS as u16 - 1;
+            return self.num_symbols - 1;
         }
         i - (i > iunlikely) as u16
     }
 
     pub fn decode(&mut self, i: u16, vunlikely: u16) -> u16 {
         let iunlikely = self.index_array[vunlikely as usize];
-        let i = if unlikely(i == SYMRANK_NUM_SYMBOLS as u16 - 1) {
+        let i = if unlikely(i == self.num_symbols - 1) {
             iunlikely
         } else {
             i + !(i < iunlikely) as u16
@@ -60,7 +62,7 @@ impl SymRankCoder {
 
     fn update(&mut self, v: u16, i: u16) {
         // adjust encoded_cnt and encoded_idx_sum
-        if self.encoded_cnt > SYMRANK_NUM_SYMBOLS as u32 {
+        if self.encoded_cnt > self.num_symbols as u32 {
             self.encoded_cnt = self.encoded_cnt * 9 / 10;
             self.encoded_idx_sum = self.encoded_idx_sum * 9 / 10;
         }
@@ -0,0 +1,38 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Helpers shared by the tests of several modules.
+
+use std::io::{Read, Write};
+
+use crate::{LZCfg, OrzReader, OrzWriter};
+
+/// Incompressible bytes from a xorshift generator with a fixed seed.
+pub fn random_bytes(len: usize) -> Vec<u8> {
+    let mut rng = 0x2545f4914f6cdd1du64;
+    let bytes = (0..len).map(|_| {
+        rng ^= rng << 13;
+        rng ^= rng >> 7;
+        rng ^= rng << 17;
+        rng as u8
+    });
+    bytes.collect()
+}
+
+/// Encodes data into a stream and checks that it decodes back, returns the
+/// encoded stream.
+pub fn roundtrip(cfg: &LZCfg, data: &[u8]) -> Vec<u8> {
+    let mut writer = OrzWriter::new(vec![], cfg).unwrap();
+    writer.write_all(data).unwrap();
+    let encoded = writer.finish().unwrap();
+
+    let mut decoded = vec![];
+    OrzReader::new(&encoded[..])
+        .read_to_end(&mut decoded)
+        .unwrap();
+    assert!(decoded == data);
+    encoded
+}
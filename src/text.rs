@@ -0,0 +1,410 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A reversible transform of UTF-8 text, replacing capitalized words with a
+//! flag and the lowercase word, and frequent words with 2-byte codes from a
+//! dictionary.
+//!
+//! Transformed data is a sequence of blocks, each of which is:
+//!  kind | decoded_len | [encoded_len | new words] | data
+//! where new words are a count followed by length-prefixed words, which are
+//! added to the dictionary shared by the following blocks, so codes stay the
+//! same across blocks.
+
+use std::{
+    collections::HashMap,
+    io::{Error, ErrorKind, Result},
+};
+
+use crate::ioutil::{ReadExt, WriteExt};
+
+/// Max input bytes of a block.
+pub const TEXT_BLOCK_LEN: usize = 1 << 20;
+
+const BLOCK_RAW: u8 = 0;
+const BLOCK_TEXT: u8 = 1;
+
+// reserved bytes, which are escaped if they occur in the text
+const ESC: u8 = 0x01;
+const CAP: u8 = 0x02; // capitalizes the next byte
+const UPPER: u8 = 0x03; // uppercases the following letters
+const CODE_LEAD_START: u8 = 0x10; // 0x10..0x20 followed by a byte form a word code
+const CODE_LEAD_END: u8 = 0x20;
+
+const MAX_WORDS: usize = (CODE_LEAD_END - CODE_LEAD_START) as usize * 256;
+const MIN_WORD_LEN: usize = 3;
+const MAX_WORD_LEN: usize = 64;
+
+/// Words replaced by codes, the code of a word is its index.
+#[derive(Default)]
+pub struct Dictionary {
+    words: Vec<Vec<u8>>,
+    codes: HashMap<Vec<u8>, usize>,
+}
+
+impl Dictionary {
+    fn push(&mut self, word: Vec<u8>) {
+        self.codes.insert(word.clone(), self.words.len());
+        self.words.push(word);
+    }
+
+    fn truncate(&mut self, len: usize) {
+        for word in self.words.drain(len..) {
+            self.codes.remove(&word);
+        }
+    }
+}
+
+/// Transforms the complete blocks of input, or all input if `flush`,
+/// returns the number of bytes consumed.
+pub fn encode(dict: &mut Dictionary, input: &[u8], flush: bool, output: &mut Vec<u8>) -> usize {
+    let mut consumed = 0;
+    while input.len() - consumed >= TEXT_BLOCK_LEN || flush && consumed < input.len() {
+        let block = &input[consumed..][..(input.len() - consumed).min(TEXT_BLOCK_LEN)];
+        encode_block(dict, block, output);
+        consumed += block.len();
+    }
+    consumed
+}
+
+/// Reverses `encode` for the complete blocks of input, returns the number of
+/// bytes consumed.
+pub fn decode(dict: &mut Dictionary, input: &[u8], output: &mut Vec<u8>) -> Result<usize> {
+    let mut consumed = 0;
+    while let Some((kind, decoded_len, header_len, payload_len)) = parse_block(&input[consumed..])?
+    {
+        let payload = &input[consumed + header_len..][..payload_len];
+        match kind {
+            BLOCK_RAW => output.extend_from_slice(payload),
+            _ => decode_text(dict, payload, decoded_len, output)?,
+        }
+        consumed += header_len + payload_len;
+    }
+    Ok(consumed)
+}
+
+/// Checks if a block looks like UTF-8 text, a character may be split at
+/// both ends.
+pub fn looks_like_text(block: &[u8]) -> bool {
+    let start = block
+        .iter()
+        .take(3)
+        .take_while(|&&b| b & 0xc0 == 0x80)
+        .count();
+    if let Err(e) = std::str::from_utf8(&block[start..])
+        && e.error_len().is_some()
+    {
+        return false;
+    }
+    let control = block
+        .iter()
+        .filter(|&&b| (b < 0x20 || b == 0x7f) && !matches!(b, b'\t' | b'\n' | b'\r' | 0x0c))
+        .count();
+    control * 64 <= block.len()
+}
+
+/// Checks if a block looks like text, either as is or transformed, where
+/// flags and word codes are not counted as control characters.
+pub fn looks_like_encoded_text(block: &[u8]) -> bool {
+    let mut control = 0;
+    let mut i = 0;
+    while i < block.len() {
+        match block[i] {
+            CODE_LEAD_START..CODE_LEAD_END => i += 1,
+            ESC | CAP | UPPER | b'\t' | b'\n' | b'\r' | 0x0c => {}
+            b if b < 0x20 || b == 0x7f => control += 1,
+            _ => {}
+        }
+        i += 1;
+    }
+    control * 64 <= block.len()
+}
+
+fn encode_block(dict: &mut Dictionary, block: &[u8], output: &mut Vec<u8>) {
+    if looks_like_text(block) {
+        let num_words = dict.words.len();
+        let new_words = choose_new_words(dict, block);
+        let mut payload = vec![];
+        payload.write_len(new_words.len()).unwrap();
+        for word in new_words {
+            payload.push(word.len() as u8);
+            payload.extend_from_slice(&word);
+            dict.push(word);
+        }
+        let mut lower = vec![];
+        for_each_token(block, |token| match token {
+            Token::Word(word) => {
+                let case = Case::of(word);
+                match case {
+                    Case::Lower => {}
+                    Case::Cap => payload.push(CAP),
+                    Case::Upper => payload.push(UPPER),
+                    Case::Mixed => return payload.extend_from_slice(word),
+                }
+                lower.clear();
+                lower.extend(word.iter().map(u8::to_ascii_lowercase));
+                match dict.codes.get(&lower[..]) {
+                    Some(&code) => {
+                        payload.push(CODE_LEAD_START + (code >> 8) as u8);
+                        payload.push(code as u8);
+                    }
+                    None => payload.extend_from_slice(&lower),
+                }
+            }
+            Token::Byte(b) => {
+                if is_reserved(b) {
+                    payload.push(ESC);
+                }
+                payload.push(b);
+            }
+        });
+
+        if payload.len() < block.len() {
+            output.push(BLOCK_TEXT);
+            output.write_len(block.len()).unwrap();
+            output.write_len(payload.len()).unwrap();
+            output.extend_from_slice(&payload);
+            return;
+        }
+        dict.truncate(num_words); // words of a raw block are not added
+    }
+    output.push(BLOCK_RAW);
+    output.write_len(block.len()).unwrap();
+    output.extend_from_slice(block);
+}
+
+/// Parses the header of a block, returns `(kind, decoded_len, header_len,
+/// payload_len)` or none if the block is incomplete.
+fn parse_block(input: &[u8]) -> Result<Option<(u8, usize, usize, usize)>> {
+    let mut r = input;
+    let parse_len = |r: &mut &[u8]| match r.read_len() {
+        Ok(len) => Ok(Some(len)),
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(None),
+        Err(e) => Err(e),
+    };
+    let Some((&kind, rest)) = r.split_first() else {
+        return Ok(None);
+    };
+    r = rest;
+    let Some(decoded_len) = parse_len(&mut r)? else {
+        return Ok(None);
+    };
+    if decoded_len > TEXT_BLOCK_LEN {
+        return Err(invalid_data("text block too large"));
+    }
+    let payload_len = match kind {
+        BLOCK_RAW => decoded_len,
+        BLOCK_TEXT => match parse_len(&mut r)? {
+            Some(len) => len,
+            None => return Ok(None),
+        },
+        _ => return Err(invalid_data("invalid text block")),
+    };
+    let header_len = input.len() - r.len();
+    match r.len() >= payload_len {
+        true => Ok(Some((kind, decoded_len, header_len, payload_len))),
+        false => Ok(None),
+    }
+}
+
+fn decode_text(
+    dict: &mut Dictionary,
+    payload: &[u8],
+    decoded_len: usize,
+    output: &mut Vec<u8>,
+) -> Result<()> {
+    let mut r = payload;
+    let num_new_words = r.read_len()?;
+    if num_new_words > MAX_WORDS - dict.words.len() {
+        return Err(invalid_data("too many words in text dictionary"));
+    }
+    for _ in 0..num_new_words {
+        let (&len, rest) = r.split_first().ok_or_else(truncated)?;
+        let word = rest.get(..len as usize).ok_or_else(truncated)?;
+        dict.words.push(word.to_vec()); // codes are only used for encoding
+        r = &rest[len as usize..];
+    }
+    let words = &dict.words;
+
+    let start = output.len();
+    let mut case = Case::Lower;
+    let mut i = 0;
+    while i < r.len() {
+        match r[i] {
+            ESC => {
+                push_decoded(output, &mut case, *r.get(i + 1).ok_or_else(truncated)?);
+                i += 2;
+            }
+            CAP | UPPER => {
+                case = if r[i] == CAP { Case::Cap } else { Case::Upper };
+                i += 1;
+            }
+            lead @ CODE_LEAD_START..CODE_LEAD_END => {
+                let low = *r.get(i + 1).ok_or_else(truncated)?;
+                let code = ((lead - CODE_LEAD_START) as usize) << 8 | low as usize;
+                let word = words
+                    .get(code)
+                    .ok_or_else(|| invalid_data("invalid word code"))?;
+                word.iter()
+                    .for_each(|&b| push_decoded(output, &mut case, b));
+                i += 2;
+            }
+            b => {
+                push_decoded(output, &mut case, b);
+                i += 1;
+            }
+        }
+        if output.len() - start > decoded_len {
+            break;
+        }
+    }
+    if output.len() - start != decoded_len {
+        return Err(invalid_data("text block size mismatch"));
+    }
+    Ok(())
+}
+
+/// Outputs a decoded byte, applying the pending case flag.
+fn push_decoded(output: &mut Vec<u8>, case: &mut Case, b: u8) {
+    match case {
+        Case::Cap => {
+            output.push(b.to_ascii_uppercase());
+            *case = Case::Lower;
+        }
+        Case::Upper if b.is_ascii_alphabetic() => output.push(b.to_ascii_uppercase()),
+        _ => {
+            output.push(b);
+            *case = Case::Lower;
+        }
+    }
+}
+
+/// Chooses the words of a block to add to the dictionary, which save most
+/// bytes when replaced by codes.
+fn choose_new_words(dict: &Dictionary, block: &[u8]) -> Vec<Vec<u8>> {
+    let mut counts = HashMap::<Vec<u8>, usize>::new();
+    let mut lower = vec![];
+    for_each_token(block, |token| {
+        if let Token::Word(word) = token
+            && (MIN_WORD_LEN..=MAX_WORD_LEN).contains(&word.len())
+            && Case::of(word) != Case::Mixed
+        {
+            lower.clear();
+            lower.extend(word.iter().map(u8::to_ascii_lowercase));
+            if dict.codes.contains_key(&lower[..]) {
+                return;
+            }
+            match counts.get_mut(&lower[..]) {
+                Some(count) => *count += 1,
+                None => drop(counts.insert(lower.clone(), 1)),
+            }
+        }
+    });
+
+    // a word is stored once in the dictionary and saves len - 2 bytes per use
+    let mut scored = counts
+        .into_iter()
+        .map(|(word, count)| {
+            (
+                (count * (word.len() - 2)) as isize - word.len() as isize - 1,
+                word,
+            )
+        })
+        .filter(|&(score, _)| score > 0)
+        .collect::<Vec<_>>();
+    scored.sort_unstable_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.cmp(&b.1)));
+    scored.truncate(MAX_WORDS - dict.words.len());
+    scored.into_iter().map(|(_, word)| word).collect()
+}
+
+enum Token<'a> {
+    Word(&'a [u8]),
+    Byte(u8),
+}
+
+/// Splits text into words of ASCII letters and other bytes.
+fn for_each_token<'a>(text: &'a [u8], mut f: impl FnMut(Token<'a>)) {
+    let mut i = 0;
+    while i < text.len() {
+        let len = text[i..]
+            .iter()
+            .take_while(|b| b.is_ascii_alphabetic())
+            .count();
+        match len {
+            0 => f(Token::Byte(text[i])),
+            _ => f(Token::Word(&text[i..][..len])),
+        }
+        i += len.max(1);
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Case {
+    Lower,
+    /// First letter uppercase, the rest lowercase.
+    Cap,
+    /// All letters uppercase, at least two of them.
+    Upper,
+    Mixed,
+}
+
+impl Case {
+    fn of(word: &[u8]) -> Self {
+        let rest_lower = word[1..].iter().all(u8::is_ascii_lowercase);
+        match word[0].is_ascii_uppercase() {
+            false if rest_lower => Case::Lower,
+            true if rest_lower => Case::Cap,
+            true if word.iter().all(u8::is_ascii_uppercase) => Case::Upper,
+            _ => Case::Mixed,
+        }
+    }
+}
+
+fn is_reserved(b: u8) -> bool {
+    matches!(b, ESC | CAP | UPPER | CODE_LEAD_START..CODE_LEAD_END)
+}
+
+fn invalid_data(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+fn truncated() -> Error {
+    invalid_data("truncated text block")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_text_transform() {
+        let text = "The quick brown fox jumps over the lazy dog. THE END, iPhone I A ".repeat(100)
+            + "\x01\x1b[0m caf\u{e9} na\u{ef}ve";
+        let mut encoded = vec![];
+        let mut dict = Dictionary::default();
+        assert_eq!(
+            encode(&mut dict, text.as_bytes(), true, &mut encoded),
+            text.len()
+        );
+        assert!(encoded.len() < text.len());
+
+        // blocks are decoded only when complete
+        let mut decoded = vec![];
+        let mut dict = Dictionary::default();
+        assert_eq!(
+            decode(&mut dict, &encoded[..encoded.len() - 1], &mut decoded).unwrap(),
+            0
+        );
+        assert_eq!(
+            decode(&mut dict, &encoded, &mut decoded).unwrap(),
+            encoded.len()
+        );
+        assert_eq!(decoded, text.as_bytes());
+
+        assert!(!looks_like_text(&[0xff, 0x00, 0x12]));
+        assert!(looks_like_text(&"\u{e9}t\u{e9}".as_bytes()[1..]));
+    }
+}